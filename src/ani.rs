@@ -0,0 +1,525 @@
+use crate::icondir::IconDir;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+//===========================================================================//
+
+// The size of the ANIHEADER struct, in bytes.
+const ANIH_LEN: u32 = 36;
+
+// ANIHEADER flag: frames are stored as ICO/CUR files (rather than as raw
+// bitmaps, which we don't support).
+const AF_ICON: u32 = 0x1;
+// ANIHEADER flag: the file contains a "seq " chunk.
+const AF_SEQUENCE: u32 = 0x2;
+
+//===========================================================================//
+
+/// One step of an animation: which frame to show, and for how long.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AniStep {
+    /// The index of the frame to display
+    pub frame: usize,
+    /// How long to display the frame, in jiffies (1/60 of a second)
+    pub rate: u32,
+}
+
+//===========================================================================//
+
+/// An animated cursor (or icon); the contents of a Windows .ani file.
+///
+/// Each frame is stored as a complete ICO/CUR file (an `IconDir`), and the
+/// animation is a sequence of steps, each of which shows one frame for some
+/// length of time.  Frames can be shown by more than one step.
+#[derive(Clone, Debug)]
+pub struct AniCursor {
+    frames: Vec<IconDir>,
+    steps: Vec<AniStep>,
+    default_rate: u32,
+    title: Option<String>,
+    artist: Option<String>,
+}
+
+impl AniCursor {
+    /// Creates a new, empty animation, whose steps will by default display
+    /// for the given number of jiffies (1/60 of a second) each.
+    pub fn new(default_rate: u32) -> AniCursor {
+        AniCursor {
+            frames: Vec::new(),
+            steps: Vec::new(),
+            default_rate,
+            title: None,
+            artist: None,
+        }
+    }
+
+    /// Returns the frames of the animation.
+    pub fn frames(&self) -> &[IconDir] {
+        &self.frames
+    }
+
+    /// Adds a frame to the animation, along with a step (at the default rate)
+    /// that displays it after all existing steps.
+    pub fn add_frame(&mut self, frame: IconDir) {
+        self.steps.push(AniStep {
+            frame: self.frames.len(),
+            rate: self.default_rate,
+        });
+        self.frames.push(frame);
+    }
+
+    /// Returns the steps of the animation, in the order they are displayed.
+    pub fn steps(&self) -> &[AniStep] {
+        &self.steps
+    }
+
+    /// Replaces the steps of the animation (e.g. to reorder or repeat
+    /// frames, or to give them different display rates).  The steps are
+    /// checked against the frames when the animation is written.
+    pub fn set_steps(&mut self, steps: Vec<AniStep>) {
+        self.steps = steps;
+    }
+
+    /// Returns the default display rate, in jiffies (1/60 of a second).
+    pub fn default_rate(&self) -> u32 {
+        self.default_rate
+    }
+
+    /// Sets the default display rate, in jiffies (1/60 of a second).  This
+    /// doesn't change the rates of any existing steps.
+    pub fn set_default_rate(&mut self, rate: u32) {
+        self.default_rate = rate;
+    }
+
+    /// Returns the title of the animation (the `INAM` field), if any.
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Sets or clears the title of the animation (the `INAM` field).
+    pub fn set_title(&mut self, title: Option<String>) {
+        self.title = title;
+    }
+
+    /// Returns the artist of the animation (the `IART` field), if any.
+    pub fn artist(&self) -> Option<&str> {
+        self.artist.as_deref()
+    }
+
+    /// Sets or clears the artist of the animation (the `IART` field).
+    pub fn set_artist(&mut self, artist: Option<String>) {
+        self.artist = artist;
+    }
+
+    /// Reads an ANI file into memory.
+    pub fn read<R: Read + Seek>(mut reader: R) -> io::Result<AniCursor> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut riff_id = [0u8; 4];
+        reader.read_exact(&mut riff_id)?;
+        if &riff_id != b"RIFF" {
+            invalid_data!("Not a RIFF file (no RIFF signature)");
+        }
+        let riff_len = reader.read_u32::<LittleEndian>()? as u64;
+        if riff_len < 4 {
+            invalid_data!(
+                "RIFF size ({}) is too small for the form type",
+                riff_len
+            );
+        }
+        if riff_len + 8 > file_len {
+            invalid_data!(
+                "RIFF size ({}) exceeds file length ({})",
+                riff_len,
+                file_len
+            );
+        }
+        let mut form_type = [0u8; 4];
+        reader.read_exact(&mut form_type)?;
+        if &form_type != b"ACON" {
+            invalid_data!("Not an ANI file (RIFF form type is not ACON)");
+        }
+        let mut header: Option<(u32, u32, u32, u32)> = None;
+        let mut rates: Option<Vec<u32>> = None;
+        let mut sequence: Option<Vec<u32>> = None;
+        let mut frames = Vec::<IconDir>::new();
+        let mut title = None;
+        let mut artist = None;
+        let mut reader = reader.take(riff_len - 4);
+        while let Some((id, data)) = read_chunk(&mut reader)? {
+            match &id {
+                b"anih" => {
+                    let mut data = data.as_slice();
+                    let header_len = data.read_u32::<LittleEndian>()?;
+                    if header_len != ANIH_LEN {
+                        invalid_data!(
+                            "Invalid anih size (was {}, but must be {})",
+                            header_len,
+                            ANIH_LEN
+                        );
+                    }
+                    let num_frames = data.read_u32::<LittleEndian>()?;
+                    let num_steps = data.read_u32::<LittleEndian>()?;
+                    let _width = data.read_u32::<LittleEndian>()?;
+                    let _height = data.read_u32::<LittleEndian>()?;
+                    let _bit_count = data.read_u32::<LittleEndian>()?;
+                    let _planes = data.read_u32::<LittleEndian>()?;
+                    let rate = data.read_u32::<LittleEndian>()?;
+                    let flags = data.read_u32::<LittleEndian>()?;
+                    if flags & AF_ICON == 0 {
+                        invalid_data!(
+                            "Unsupported ANI file (frames are raw bitmaps)"
+                        );
+                    }
+                    header = Some((num_frames, num_steps, rate, flags));
+                }
+                b"rate" => rates = Some(read_u32_array(&data)?),
+                b"seq " => sequence = Some(read_u32_array(&data)?),
+                b"LIST" if data.len() >= 4 => {
+                    let mut list = &data[4..];
+                    match &data[..4] {
+                        b"fram" => {
+                            while let Some((id, data)) = read_chunk(&mut list)?
+                            {
+                                if &id == b"icon" {
+                                    frames.push(IconDir::read(Cursor::new(
+                                        data,
+                                    ))?);
+                                }
+                            }
+                        }
+                        b"INFO" => {
+                            while let Some((id, data)) = read_chunk(&mut list)?
+                            {
+                                match &id {
+                                    b"INAM" => title = Some(read_zstr(&data)),
+                                    b"IART" => artist = Some(read_zstr(&data)),
+                                    _ => {}
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {} // Ignore unknown chunks.
+            }
+        }
+        let (num_frames, num_steps, default_rate, flags) = match header {
+            Some(header) => header,
+            None => invalid_data!("ANI file has no anih chunk"),
+        };
+        if frames.len() != num_frames as usize {
+            invalid_data!(
+                "Wrong number of frames in ANI file (anih says {}, but \
+                 found {})",
+                num_frames,
+                frames.len()
+            );
+        }
+        let num_steps = num_steps as usize;
+        let sequence = match sequence {
+            Some(sequence) if flags & AF_SEQUENCE != 0 => sequence,
+            _ => (0..num_frames).collect(),
+        };
+        if sequence.len() != num_steps {
+            invalid_data!(
+                "Wrong number of steps in ANI sequence (anih says {}, but \
+                 found {})",
+                num_steps,
+                sequence.len()
+            );
+        }
+        let rates = rates.unwrap_or_else(|| vec![default_rate; num_steps]);
+        if rates.len() != num_steps {
+            invalid_data!(
+                "Wrong number of ANI rates (anih says {} steps, but found \
+                 {} rates)",
+                num_steps,
+                rates.len()
+            );
+        }
+        let mut steps = Vec::with_capacity(num_steps);
+        for (&frame, &rate) in sequence.iter().zip(rates.iter()) {
+            if frame >= num_frames {
+                invalid_data!(
+                    "Invalid frame index in ANI sequence (was {}, but \
+                     there are only {} frames)",
+                    frame,
+                    num_frames
+                );
+            }
+            steps.push(AniStep { frame: frame as usize, rate });
+        }
+        Ok(AniCursor { frames, steps, default_rate, title, artist })
+    }
+
+    /// Writes an ANI file out to disk.  Returns an error if any step refers
+    /// to a nonexistent frame, or if the title or artist contains characters
+    /// outside of Latin-1.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for step in self.steps.iter() {
+            if step.frame >= self.frames.len() {
+                invalid_input!(
+                    "Invalid frame index in AniCursor step (was {}, but \
+                     there are only {} frames)",
+                    step.frame,
+                    self.frames.len()
+                );
+            }
+        }
+        let mut body = Vec::<u8>::new();
+        body.extend_from_slice(b"ACON");
+
+        // Write the metadata, if any:
+        let mut info = Vec::<u8>::new();
+        if let Some(ref title) = self.title {
+            write_chunk(&mut info, b"INAM", &write_zstr(title)?)?;
+        }
+        if let Some(ref artist) = self.artist {
+            write_chunk(&mut info, b"IART", &write_zstr(artist)?)?;
+        }
+        if !info.is_empty() {
+            write_chunk(
+                &mut body,
+                b"LIST",
+                &[b"INFO".as_slice(), &info].concat(),
+            )?;
+        }
+
+        // Only write the rate and sequence chunks if they are needed:
+        let needs_rates =
+            self.steps.iter().any(|step| step.rate != self.default_rate);
+        let needs_sequence = self.steps.len() != self.frames.len()
+            || self
+                .steps
+                .iter()
+                .enumerate()
+                .any(|(index, step)| step.frame != index);
+        let mut flags = AF_ICON;
+        if needs_sequence {
+            flags |= AF_SEQUENCE;
+        }
+        let mut anih = Vec::<u8>::with_capacity(ANIH_LEN as usize);
+        anih.write_u32::<LittleEndian>(ANIH_LEN)?;
+        anih.write_u32::<LittleEndian>(self.frames.len() as u32)?;
+        anih.write_u32::<LittleEndian>(self.steps.len() as u32)?;
+        anih.write_u32::<LittleEndian>(0)?; // width
+        anih.write_u32::<LittleEndian>(0)?; // height
+        anih.write_u32::<LittleEndian>(0)?; // bit count
+        anih.write_u32::<LittleEndian>(0)?; // planes
+        anih.write_u32::<LittleEndian>(self.default_rate)?;
+        anih.write_u32::<LittleEndian>(flags)?;
+        write_chunk(&mut body, b"anih", &anih)?;
+        if needs_rates {
+            let mut rates = Vec::<u8>::new();
+            for step in self.steps.iter() {
+                rates.write_u32::<LittleEndian>(step.rate)?;
+            }
+            write_chunk(&mut body, b"rate", &rates)?;
+        }
+        if needs_sequence {
+            let mut sequence = Vec::<u8>::new();
+            for step in self.steps.iter() {
+                sequence.write_u32::<LittleEndian>(step.frame as u32)?;
+            }
+            write_chunk(&mut body, b"seq ", &sequence)?;
+        }
+
+        // Write the frames:
+        let mut frames = b"fram".to_vec();
+        for frame in self.frames.iter() {
+            let mut data = Vec::<u8>::new();
+            frame.write(&mut data)?;
+            write_chunk(&mut frames, b"icon", &data)?;
+        }
+        write_chunk(&mut body, b"LIST", &frames)?;
+
+        write_chunk(&mut writer, b"RIFF", &body)
+    }
+}
+
+//===========================================================================//
+
+/// Reads the next RIFF chunk, returning its ID and data, or `None` at the
+/// end of the input.
+fn read_chunk<R: Read>(
+    reader: &mut R,
+) -> io::Result<Option<([u8; 4], Vec<u8>)>> {
+    let mut id = [0u8; 4];
+    match reader.read_exact(&mut id) {
+        Ok(()) => {}
+        Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok(None);
+        }
+        Err(error) => return Err(error),
+    }
+    let len = reader.read_u32::<LittleEndian>()? as u64;
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+    if (data.len() as u64) < len {
+        invalid_data!(
+            "RIFF chunk {:?} is truncated (expected {} bytes, but found {})",
+            String::from_utf8_lossy(&id),
+            len,
+            data.len()
+        );
+    }
+    // Chunks are padded to an even length.
+    if !len.is_multiple_of(2) {
+        let _ = reader.read_u8();
+    }
+    Ok(Some((id, data)))
+}
+
+/// Writes a RIFF chunk, padded to an even length.
+fn write_chunk<W: Write>(
+    writer: &mut W,
+    id: &[u8; 4],
+    data: &[u8],
+) -> io::Result<()> {
+    if data.len() > u32::MAX as usize {
+        invalid_input!("RIFF chunk is too large ({} bytes)", data.len());
+    }
+    writer.write_all(id)?;
+    writer.write_u32::<LittleEndian>(data.len() as u32)?;
+    writer.write_all(data)?;
+    if !data.len().is_multiple_of(2) {
+        writer.write_u8(0)?;
+    }
+    Ok(())
+}
+
+fn read_u32_array(data: &[u8]) -> io::Result<Vec<u32>> {
+    let mut reader = data;
+    let mut values = Vec::with_capacity(data.len() / 4);
+    for _ in 0..(data.len() / 4) {
+        values.push(reader.read_u32::<LittleEndian>()?);
+    }
+    Ok(values)
+}
+
+/// Decodes a NUL-terminated Latin-1 string.
+fn read_zstr(data: &[u8]) -> String {
+    data.iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as char)
+        .collect()
+}
+
+/// Encodes a string as NUL-terminated Latin-1.
+fn write_zstr(string: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(string.len() + 1);
+    for chr in string.chars() {
+        if chr as u32 > 0xff || chr == '\0' {
+            invalid_input!(
+                "Invalid character in ANI metadata string ({:?})",
+                chr
+            );
+        }
+        bytes.push(chr as u8);
+    }
+    bytes.push(0);
+    Ok(bytes)
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{AniCursor, AniStep};
+    use crate::icondir::{IconDir, IconDirEntry};
+    use crate::image::IconImage;
+    use crate::restype::ResourceType;
+    use std::io::Cursor;
+
+    fn cursor_frame(color: u8) -> IconDir {
+        let mut image = IconImage::from_rgba_data(2, 2, vec![color; 16]);
+        image.set_cursor_hotspot(Some((1, 0)));
+        let mut icon_dir = IconDir::new(ResourceType::Cursor);
+        icon_dir.add_entry(IconDirEntry::encode(&image).unwrap());
+        icon_dir
+    }
+
+    #[test]
+    fn read_minimal_ani() {
+        let mut frame = Vec::new();
+        cursor_frame(0xff).write(&mut frame).unwrap();
+        let mut input = Vec::new();
+        input.extend_from_slice(b"RIFF");
+        input.extend_from_slice(
+            &(4 + 44 + 20 + frame.len() as u32).to_le_bytes(),
+        );
+        input.extend_from_slice(b"ACONanih\x24\x00\x00\x00\x24\x00\x00\x00");
+        input.extend_from_slice(b"\x01\x00\x00\x00\x01\x00\x00\x00");
+        input.extend_from_slice(&[0; 16]);
+        input.extend_from_slice(b"\x0a\x00\x00\x00\x01\x00\x00\x00");
+        input.extend_from_slice(b"LIST");
+        input.extend_from_slice(&(12 + frame.len() as u32).to_le_bytes());
+        input.extend_from_slice(b"framicon");
+        input.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        input.extend_from_slice(&frame);
+        let ani = AniCursor::read(Cursor::new(input)).unwrap();
+        assert_eq!(ani.frames().len(), 1);
+        assert_eq!(ani.default_rate(), 10);
+        assert_eq!(ani.steps(), &[AniStep { frame: 0, rate: 10 }]);
+        let entry = &ani.frames()[0].entries()[0];
+        assert_eq!(entry.cursor_hotspot(), Some((1, 0)));
+        assert_eq!(ani.title(), None);
+    }
+
+    #[test]
+    fn ani_round_trip() {
+        let mut ani = AniCursor::new(6);
+        ani.add_frame(cursor_frame(0x00));
+        ani.add_frame(cursor_frame(0x80));
+        ani.add_frame(cursor_frame(0xff));
+        assert_eq!(ani.steps().len(), 3);
+        ani.set_steps(vec![
+            AniStep { frame: 0, rate: 6 },
+            AniStep { frame: 2, rate: 12 },
+            AniStep { frame: 1, rate: 6 },
+            AniStep { frame: 2, rate: 6 },
+        ]);
+        ani.set_title(Some("Busy".to_string()));
+        ani.set_artist(Some("Zoë".to_string()));
+        let mut data = Vec::new();
+        ani.write(&mut data).unwrap();
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize,
+            data.len() - 8
+        );
+        let ani = AniCursor::read(Cursor::new(data)).unwrap();
+        assert_eq!(ani.frames().len(), 3);
+        assert_eq!(ani.steps()[1], AniStep { frame: 2, rate: 12 });
+        assert_eq!(ani.steps().len(), 4);
+        assert_eq!(ani.title(), Some("Busy"));
+        assert_eq!(ani.artist(), Some("Zoë"));
+        let image = ani.frames()[1].entries()[0].decode().unwrap();
+        assert_eq!(image.rgba_data(), &[0x80; 16]);
+    }
+
+    #[test]
+    fn read_ani_with_truncated_riff_size() {
+        for riff_len in 0..4u32 {
+            let mut input = Vec::new();
+            input.extend_from_slice(b"RIFF");
+            input.extend_from_slice(&riff_len.to_le_bytes());
+            input.extend_from_slice(b"ACON");
+            assert!(AniCursor::read(Cursor::new(input)).is_err());
+        }
+    }
+
+    #[test]
+    fn write_ani_with_invalid_step() {
+        let mut ani = AniCursor::new(6);
+        ani.add_frame(cursor_frame(0x00));
+        ani.set_steps(vec![AniStep { frame: 1, rate: 6 }]);
+        assert!(ani.write(Vec::new()).is_err());
+        ani.set_steps(vec![]);
+        ani.set_title(Some("\u{263a}".to_string()));
+        assert!(ani.write(Vec::new()).is_err());
+    }
+}
+
+//===========================================================================//
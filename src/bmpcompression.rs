@@ -0,0 +1,324 @@
+use crate::bmpdepth::BmpDepth;
+use byteorder::ReadBytesExt;
+use std::io::{self, Read};
+
+//===========================================================================//
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BmpCompression {
+    Rgb,
+    Rle8,
+    Rle4,
+    Bitfields,
+    AlphaBitfields,
+}
+
+impl BmpCompression {
+    pub(crate) fn from_number(number: u32) -> Option<BmpCompression> {
+        match number {
+            0 => Some(BmpCompression::Rgb),
+            1 => Some(BmpCompression::Rle8),
+            2 => Some(BmpCompression::Rle4),
+            3 => Some(BmpCompression::Bitfields),
+            6 => Some(BmpCompression::AlphaBitfields),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn number(&self) -> u32 {
+        match *self {
+            BmpCompression::Rgb => 0,
+            BmpCompression::Rle8 => 1,
+            BmpCompression::Rle4 => 2,
+            BmpCompression::Bitfields => 3,
+            BmpCompression::AlphaBitfields => 6,
+        }
+    }
+}
+
+//===========================================================================//
+
+/// Decodes run-length-encoded (BI_RLE8 or BI_RLE4) pixel data, and returns
+/// the color table index of each pixel, row by row in the order that the rows
+/// are stored (just like uncompressed BMP data).  Pixels that the encoded data
+/// skips over (via delta or end-of-line codes) are left as index zero.
+/// Decoding stops at the end-of-bitmap code, or at the end of the data.
+pub(crate) fn decode_rle<R: Read>(
+    mut reader: R,
+    compression: BmpCompression,
+    width: u32,
+    height: u32,
+) -> io::Result<Vec<u8>> {
+    let is_rle4 = match compression {
+        BmpCompression::Rle8 => false,
+        BmpCompression::Rle4 => true,
+        _ => panic!("decode_rle called with {:?} compression", compression),
+    };
+    let (width, height) = (width as usize, height as usize);
+    let mut indices = vec![0u8; width * height];
+    let mut put = |x: usize, y: usize, index: u8| {
+        // Runs that extend past the edge of the image are clipped.
+        if x < width && y < height {
+            indices[y * width + x] = index;
+        }
+    };
+    let (mut x, mut y) = (0usize, 0usize);
+    loop {
+        let first = match reader.read_u8() {
+            Ok(byte) => byte,
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(error) => return Err(error),
+        };
+        let second = reader.read_u8()?;
+        if first > 0 {
+            // Encoded mode: repeat the color index (or, for RLE4, the pair of
+            // alternating color indices) `first` times.
+            for i in 0..(first as usize) {
+                let index = if !is_rle4 {
+                    second
+                } else if i % 2 == 0 {
+                    second >> 4
+                } else {
+                    second & 0xf
+                };
+                put(x, y, index);
+                x += 1;
+            }
+            continue;
+        }
+        match second {
+            0 => {
+                // End of line:
+                x = 0;
+                y += 1;
+            }
+            1 => break, // End of bitmap
+            2 => {
+                // Delta:
+                x += reader.read_u8()? as usize;
+                y += reader.read_u8()? as usize;
+            }
+            count => {
+                // Absolute mode: `count` literal color indices, padded to a
+                // 16-bit boundary.
+                let count = count as usize;
+                let num_bytes =
+                    if is_rle4 { count.div_ceil(2) } else { count };
+                let mut literal = vec![0u8; num_bytes + num_bytes % 2];
+                reader.read_exact(&mut literal)?;
+                for i in 0..count {
+                    let index = if !is_rle4 {
+                        literal[i]
+                    } else if i % 2 == 0 {
+                        literal[i / 2] >> 4
+                    } else {
+                        literal[i / 2] & 0xf
+                    };
+                    put(x, y, index);
+                    x += 1;
+                }
+            }
+        }
+    }
+    Ok(indices)
+}
+
+//===========================================================================//
+
+/// The location of a single color channel within a 16- or 32-bit pixel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct ChannelMask {
+    shift: u32,
+    max: u32,
+}
+
+impl ChannelMask {
+    fn new(name: &str, mask: u32) -> io::Result<Option<ChannelMask>> {
+        if mask == 0 {
+            return Ok(None);
+        }
+        let shift = mask.trailing_zeros();
+        let max = mask >> shift;
+        if max & max.wrapping_add(1) != 0 {
+            invalid_data!(
+                "Invalid BMP {} mask (was {:#010x}, but must be a \
+                 contiguous run of bits)",
+                name,
+                mask
+            );
+        }
+        Ok(Some(ChannelMask { shift, max }))
+    }
+
+    fn insert(&self, value: u8) -> u32 {
+        let max = self.max as u64;
+        ((((value as u64) * max + 127) / 255) as u32) << self.shift
+    }
+
+    fn extract(&self, pixel: u32) -> u8 {
+        let value = ((pixel >> self.shift) & self.max) as u64;
+        let max = self.max as u64;
+        ((value * 255 + max / 2) / max) as u8
+    }
+}
+
+//===========================================================================//
+
+/// The red, green, blue, and (optionally) alpha channel masks used to decode
+/// 16- and 32-bpp BMP pixels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct BmpColorMasks {
+    red: Option<ChannelMask>,
+    green: Option<ChannelMask>,
+    blue: Option<ChannelMask>,
+    alpha: Option<ChannelMask>,
+}
+
+impl BmpColorMasks {
+    /// Creates masks from the raw bitfield values in a BMP header.  Returns
+    /// an error if any of the masks are malformed.
+    pub(crate) fn new(
+        red: u32,
+        green: u32,
+        blue: u32,
+        alpha: u32,
+    ) -> io::Result<BmpColorMasks> {
+        Ok(BmpColorMasks {
+            red: ChannelMask::new("red", red)?,
+            green: ChannelMask::new("green", green)?,
+            blue: ChannelMask::new("blue", blue)?,
+            alpha: ChannelMask::new("alpha", alpha)?,
+        })
+    }
+
+    /// Returns the masks implied by uncompressed (BI_RGB) pixel data at the
+    /// given depth: 5-5-5 for 16 bpp, and BGRA for 32 bpp.
+    pub(crate) fn for_depth(depth: BmpDepth) -> BmpColorMasks {
+        let result = if depth == BmpDepth::Sixteen {
+            BmpColorMasks::new(0x7c00, 0x03e0, 0x001f, 0)
+        } else {
+            BmpColorMasks::new(0x00ff0000, 0x0000ff00, 0x000000ff, 0xff000000)
+        };
+        result.unwrap()
+    }
+
+    /// Returns the 5-6-5 masks commonly used for 16-bpp images with
+    /// BI_BITFIELDS compression.
+    pub(crate) fn rgb565() -> BmpColorMasks {
+        BmpColorMasks::new(0xf800, 0x07e0, 0x001f, 0).unwrap()
+    }
+
+    /// Returns the raw red, green, and blue mask values, as stored in a BMP
+    /// file for BI_BITFIELDS compression.
+    pub(crate) fn rgb_mask_values(&self) -> (u32, u32, u32) {
+        let value = |mask: Option<ChannelMask>| {
+            mask.map_or(0, |mask| mask.max << mask.shift)
+        };
+        (value(self.red), value(self.green), value(self.blue))
+    }
+
+    /// Returns true if these masks include an alpha channel.
+    pub(crate) fn has_alpha(&self) -> bool {
+        self.alpha.is_some()
+    }
+
+    /// Decodes a pixel into RGBA.  Channels with no mask are zero, except
+    /// for alpha, which is fully opaque.
+    pub(crate) fn decode(&self, pixel: u32) -> [u8; 4] {
+        let channel = |mask: Option<ChannelMask>, default: u8| {
+            mask.map_or(default, |mask| mask.extract(pixel))
+        };
+        [
+            channel(self.red, 0),
+            channel(self.green, 0),
+            channel(self.blue, 0),
+            channel(self.alpha, u8::MAX),
+        ]
+    }
+
+    /// Encodes an RGBA pixel, rounding each channel to the nearest value
+    /// that the mask can represent.  Channels with no mask are dropped.
+    pub(crate) fn encode(&self, rgba: &[u8]) -> u32 {
+        let channel = |mask: Option<ChannelMask>, value: u8| {
+            mask.map_or(0, |mask| mask.insert(value))
+        };
+        channel(self.red, rgba[0])
+            | channel(self.green, rgba[1])
+            | channel(self.blue, rgba[2])
+            | channel(self.alpha, rgba[3])
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_rle, BmpColorMasks, BmpCompression};
+    use crate::bmpdepth::BmpDepth;
+
+    #[test]
+    fn bmp_compression_round_trip() {
+        let compressions = &[
+            BmpCompression::Rgb,
+            BmpCompression::Rle8,
+            BmpCompression::Rle4,
+            BmpCompression::Bitfields,
+            BmpCompression::AlphaBitfields,
+        ];
+        for &compression in compressions.iter() {
+            assert_eq!(
+                BmpCompression::from_number(compression.number()),
+                Some(compression)
+            );
+        }
+    }
+
+    #[test]
+    fn decode_rle8_with_delta_and_end_of_line() {
+        let input: &[u8] = b"\
+            \x02\x07\x00\x02\x00\x01\
+            \x01\x09\x00\x00\
+            \x00\x03\x01\x02\x03\x00\
+            \x00\x01";
+        let indices = decode_rle(input, BmpCompression::Rle8, 3, 3).unwrap();
+        assert_eq!(indices, vec![7, 7, 0, 0, 0, 9, 1, 2, 3]);
+    }
+
+    #[test]
+    fn decode_rle4_clips_long_runs() {
+        let input: &[u8] = b"\x05\x12\x00\x00\x00\x03\x34\x50\x00\x01";
+        let indices = decode_rle(input, BmpCompression::Rle4, 4, 2).unwrap();
+        assert_eq!(indices, vec![1, 2, 1, 2, 3, 4, 5, 0]);
+    }
+
+    #[test]
+    fn decode_with_masks() {
+        let masks = BmpColorMasks::for_depth(BmpDepth::Sixteen);
+        assert!(!masks.has_alpha());
+        assert_eq!(masks.decode(0x7c1f), [255, 0, 255, 255]);
+        let masks = BmpColorMasks::new(0xf800, 0x07e0, 0x001f, 0).unwrap();
+        assert_eq!(masks.decode(0x0410), [0, 130, 132, 255]);
+        let masks = BmpColorMasks::for_depth(BmpDepth::ThirtyTwo);
+        assert!(masks.has_alpha());
+        assert_eq!(masks.decode(0x80123456), [0x12, 0x34, 0x56, 0x80]);
+    }
+
+    #[test]
+    fn encode_with_masks() {
+        let masks = BmpColorMasks::rgb565();
+        assert_eq!(masks.rgb_mask_values(), (0xf800, 0x07e0, 0x001f));
+        assert_eq!(masks.encode(&[0, 130, 132, 255]), 0x0410);
+        assert_eq!(masks.encode(&[0, 128, 133, 0]), 0x0410);
+        let masks = BmpColorMasks::for_depth(BmpDepth::ThirtyTwo);
+        assert_eq!(masks.encode(&[0x12, 0x34, 0x56, 0x80]), 0x80123456);
+    }
+
+    #[test]
+    fn reject_noncontiguous_mask() {
+        assert!(BmpColorMasks::new(0xf00f, 0x0ff0, 0, 0).is_err());
+    }
+}
+
+//===========================================================================//
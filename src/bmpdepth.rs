@@ -1,12 +1,19 @@
 //===========================================================================//
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub(crate) enum BmpDepth {
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+/// The color depth of a BMP-encoded image.
+pub enum BmpDepth {
+    /// 1 bit per pixel, with a 2-color table
     One,
+    /// 4 bits per pixel, with a 16-color table
     Four,
+    /// 8 bits per pixel, with a 256-color table
     Eight,
+    /// 16 bits per pixel (normally 5 bits each for red, green, and blue)
     Sixteen,
+    /// 24 bits per pixel (8 bits each for red, green, and blue)
     TwentyFour,
+    /// 32 bits per pixel (8 bits each for red, green, blue, and alpha)
     ThirtyTwo,
 }
 
@@ -0,0 +1,193 @@
+use crate::image::{MIN_HEIGHT, MIN_WIDTH};
+use byteorder::{LittleEndian, ReadBytesExt};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+//===========================================================================//
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+/// The type of header used by a BMP-encoded ICO/CUR entry.
+pub enum BmpHeaderType {
+    /// OS/2-style `BITMAPCOREHEADER` (12 bytes), with 3-byte color table
+    /// entries
+    Core,
+    /// `BITMAPINFOHEADER` (40 bytes); the usual header for ICO/CUR files
+    Info,
+    /// `BITMAPV2INFOHEADER` (52 bytes), which adds RGB color masks
+    V2Info,
+    /// `BITMAPV3INFOHEADER` (56 bytes), which adds an alpha color mask
+    V3Info,
+    /// `BITMAPV4HEADER` (108 bytes), which adds color space information
+    V4,
+    /// `BITMAPV5HEADER` (124 bytes), which adds ICC color profile information
+    V5,
+}
+
+impl BmpHeaderType {
+    pub(crate) fn from_size(size: u32) -> Option<BmpHeaderType> {
+        match size {
+            12 => Some(BmpHeaderType::Core),
+            40 => Some(BmpHeaderType::Info),
+            52 => Some(BmpHeaderType::V2Info),
+            56 => Some(BmpHeaderType::V3Info),
+            108 => Some(BmpHeaderType::V4),
+            124 => Some(BmpHeaderType::V5),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn size(&self) -> u32 {
+        match *self {
+            BmpHeaderType::Core => 12,
+            BmpHeaderType::Info => 40,
+            BmpHeaderType::V2Info => 52,
+            BmpHeaderType::V3Info => 56,
+            BmpHeaderType::V4 => 108,
+            BmpHeaderType::V5 => 124,
+        }
+    }
+}
+
+//===========================================================================//
+
+/// The fields of a BMP header that matter for decoding an ICO/CUR entry.
+pub(crate) struct BmpHeader {
+    pub(crate) header_type: BmpHeaderType,
+    pub(crate) width: u32,
+    /// The height of the image (*not* doubled to include the alpha mask).
+    pub(crate) height: u32,
+    /// True if the rows are stored from top to bottom, rather than the usual
+    /// bottom to top.
+    pub(crate) top_down: bool,
+    pub(crate) bits_per_pixel: u16,
+    pub(crate) compression: u32,
+    pub(crate) image_size: u32,
+    /// The number of entries in the color table, or zero for the maximum
+    /// number of entries for the bit depth.
+    pub(crate) colors_used: u32,
+    /// The red, green, blue, and alpha masks stored within the header itself
+    /// (for V2 headers and later), or `None` for older headers.
+    pub(crate) masks: Option<(u32, u32, u32, u32)>,
+}
+
+impl BmpHeader {
+    pub(crate) fn read<R: Read>(reader: &mut R) -> io::Result<BmpHeader> {
+        let header_size = reader.read_u32::<LittleEndian>()?;
+        let header_type = match BmpHeaderType::from_size(header_size) {
+            Some(header_type) => header_type,
+            None => {
+                invalid_data!("Unsupported BMP header size ({})", header_size)
+            }
+        };
+        let (width, height) = if header_type == BmpHeaderType::Core {
+            let width = reader.read_u16::<LittleEndian>()?;
+            let height = reader.read_u16::<LittleEndian>()?;
+            (width as i32, height as i32)
+        } else {
+            let width = reader.read_i32::<LittleEndian>()?;
+            let height = reader.read_i32::<LittleEndian>()?;
+            (width, height)
+        };
+        if width < (MIN_WIDTH as i32) {
+            invalid_data!(
+                "Invalid BMP width (was {}, but must be at least {})",
+                width,
+                MIN_WIDTH
+            );
+        }
+        // A negative height indicates a top-down image, whose rows are stored
+        // starting from the top rather than from the bottom.
+        let top_down = height < 0;
+        let height = height.unsigned_abs();
+        if height % 2 != 0 {
+            // The height is stored doubled, counting the rows of both the
+            // color data and the alpha mask, so it should be divisible by 2.
+            invalid_data!(
+                "Invalid height field in BMP header \
+                 (was {}, but must be divisible by 2)",
+                height
+            );
+        }
+        let height = height / 2;
+        if height < MIN_HEIGHT {
+            invalid_data!(
+                "Invalid BMP height (was {}, but must be at least {})",
+                height,
+                MIN_HEIGHT
+            );
+        }
+        let _planes = reader.read_u16::<LittleEndian>()?;
+        let bits_per_pixel = reader.read_u16::<LittleEndian>()?;
+        let mut header = BmpHeader {
+            header_type,
+            width: width as u32,
+            height,
+            top_down,
+            bits_per_pixel,
+            compression: 0,
+            image_size: 0,
+            colors_used: 0,
+            masks: None,
+        };
+        if header_type == BmpHeaderType::Core {
+            return Ok(header);
+        }
+        header.compression = reader.read_u32::<LittleEndian>()?;
+        header.image_size = reader.read_u32::<LittleEndian>()?;
+        let _horz_ppm = reader.read_i32::<LittleEndian>()?;
+        let _vert_ppm = reader.read_i32::<LittleEndian>()?;
+        header.colors_used = reader.read_u32::<LittleEndian>()?;
+        let _colors_important = reader.read_u32::<LittleEndian>()?;
+        if header_type == BmpHeaderType::Info {
+            return Ok(header);
+        }
+        let red = reader.read_u32::<LittleEndian>()?;
+        let green = reader.read_u32::<LittleEndian>()?;
+        let blue = reader.read_u32::<LittleEndian>()?;
+        let alpha = if header_type == BmpHeaderType::V2Info {
+            0
+        } else {
+            reader.read_u32::<LittleEndian>()?
+        };
+        header.masks = Some((red, green, blue, alpha));
+        // Skip over any color space and color profile fields, which we don't
+        // use:
+        let bytes_read =
+            if header_type == BmpHeaderType::V2Info { 52 } else { 56 };
+        let remaining = (header_type.size() - bytes_read) as u64;
+        let skipped = io::copy(&mut reader.take(remaining), &mut io::sink())?;
+        if skipped != remaining {
+            invalid_data!("BMP header is truncated");
+        }
+        Ok(header)
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::BmpHeaderType;
+
+    #[test]
+    fn bmp_header_type_round_trip() {
+        let header_types = &[
+            BmpHeaderType::Core,
+            BmpHeaderType::Info,
+            BmpHeaderType::V2Info,
+            BmpHeaderType::V3Info,
+            BmpHeaderType::V4,
+            BmpHeaderType::V5,
+        ];
+        for &header_type in header_types.iter() {
+            assert_eq!(
+                BmpHeaderType::from_size(header_type.size()),
+                Some(header_type)
+            );
+        }
+    }
+}
+
+//===========================================================================//
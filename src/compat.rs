@@ -0,0 +1,239 @@
+use crate::bmpdepth::BmpDepth;
+use crate::bmpheader::BmpHeader;
+use crate::icondir::{IconDir, IconDirEntry};
+use crate::image::IconImage;
+use crate::options::{BmpEncodingOptions, EncodingOptions, FormatPolicy};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+
+//===========================================================================//
+
+/// A set of rules describing which ICO/CUR files a particular group of
+/// consumers can display properly.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CompatibilityPreset {
+    /// Windows XP and earlier, which can't decode PNG entries at all.  Each
+    /// size should be provided as 32-bpp, 8-bpp, and 4-bpp BMPs, so that
+    /// older displays can choose the best depth they support.
+    WindowsXp,
+    /// Windows Vista and later.  Any 256x256 entries must be PNG (to keep
+    /// files small), while smaller entries must be 32-bpp BMPs (so that
+    /// the file still works on older versions of Windows, which will ignore
+    /// the 256x256 entries).
+    WindowsVista,
+    /// Modern web browsers, which can decode PNG entries of any size.  Each
+    /// entry is encoded as whichever of BMP or PNG is smaller.
+    Modern,
+}
+
+impl CompatibilityPreset {
+    /// Returns true if this preset allows an image of the given size to be
+    /// encoded as a PNG.
+    pub fn allows_png(&self, width: u32, height: u32) -> bool {
+        match *self {
+            CompatibilityPreset::WindowsXp => false,
+            CompatibilityPreset::WindowsVista => {
+                self.requires_png(width, height)
+            }
+            CompatibilityPreset::Modern => true,
+        }
+    }
+
+    /// Returns true if this preset requires an image of the given size to be
+    /// encoded as a PNG.
+    pub fn requires_png(&self, width: u32, height: u32) -> bool {
+        match *self {
+            CompatibilityPreset::WindowsVista => width >= 256 && height >= 256,
+            CompatibilityPreset::WindowsXp | CompatibilityPreset::Modern => {
+                false
+            }
+        }
+    }
+
+    /// Returns the BMP color depths that this preset expects to be present
+    /// for each image size (other than sizes that must be PNG), from highest
+    /// to lowest.
+    pub fn bmp_depths(&self) -> &'static [BmpDepth] {
+        match *self {
+            CompatibilityPreset::WindowsXp => {
+                &[BmpDepth::ThirtyTwo, BmpDepth::Eight, BmpDepth::Four]
+            }
+            CompatibilityPreset::WindowsVista
+            | CompatibilityPreset::Modern => &[BmpDepth::ThirtyTwo],
+        }
+    }
+
+    /// Encodes an image into the entries that this preset calls for at the
+    /// image's size (one per color depth, or a single PNG entry where one is
+    /// required).  Reducing the image to lower color depths may alter it.
+    /// Returns an error if any of the encodings fail.
+    pub fn encode(&self, image: &IconImage) -> io::Result<Vec<IconDirEntry>> {
+        let (width, height) = (image.width(), image.height());
+        if self.requires_png(width, height) {
+            return Ok(vec![IconDirEntry::encode_as_png(image)?]);
+        }
+        let format_policy = if self.allows_png(width, height) {
+            FormatPolicy::Smallest { min_png_size: 0 }
+        } else {
+            FormatPolicy::AlwaysBmp
+        };
+        let mut entries = Vec::new();
+        for &depth in self.bmp_depths().iter() {
+            let mut bmp_options = BmpEncodingOptions::new();
+            bmp_options.set_depth(Some(depth));
+            bmp_options.set_allow_quantization(true);
+            let mut options = EncodingOptions::new();
+            options.set_format_policy(format_policy);
+            options.set_bmp_options(bmp_options);
+            entries.push(IconDirEntry::encode_with_options(image, &options)?);
+        }
+        Ok(entries)
+    }
+
+    /// Checks an existing collection of icons/cursors against this preset,
+    /// and returns a list of the problems found (which will be empty if the
+    /// collection is fully compatible).
+    pub fn validate(&self, icon_dir: &IconDir) -> Vec<CompatibilityViolation> {
+        let mut violations = Vec::new();
+        if icon_dir.entries().is_empty() {
+            violations.push(CompatibilityViolation::NoEntries);
+            return violations;
+        }
+        // For each image size, the BMP depths present (with PNG entries
+        // counting as 32 bpp):
+        let mut depths = BTreeSet::<(u32, u32, u16)>::new();
+        for (index, entry) in icon_dir.entries().iter().enumerate() {
+            let (width, height) = (entry.width(), entry.height());
+            if entry.is_png() {
+                if !self.allows_png(width, height) {
+                    violations.push(CompatibilityViolation::PngNotAllowed {
+                        index,
+                        width,
+                        height,
+                    });
+                }
+                depths.insert((width, height, 32));
+                continue;
+            }
+            if self.requires_png(width, height) {
+                violations.push(CompatibilityViolation::PngRequired {
+                    index,
+                    width,
+                    height,
+                });
+            }
+            match BmpHeader::read(&mut entry.data()) {
+                Ok(header) => {
+                    depths.insert((width, height, header.bits_per_pixel));
+                }
+                Err(_) => {
+                    violations.push(CompatibilityViolation::MalformedEntry {
+                        index,
+                    });
+                }
+            }
+        }
+        let sizes: BTreeSet<(u32, u32)> = icon_dir
+            .entries()
+            .iter()
+            .map(|entry| (entry.width(), entry.height()))
+            .collect();
+        for (width, height) in sizes {
+            if self.requires_png(width, height) {
+                continue;
+            }
+            for &depth in self.bmp_depths().iter() {
+                if !depths.contains(&(width, height, depth.bits_per_pixel())) {
+                    violations.push(CompatibilityViolation::MissingDepth {
+                        width,
+                        height,
+                        depth,
+                    });
+                }
+            }
+        }
+        violations
+    }
+}
+
+//===========================================================================//
+
+/// A way in which an ICO/CUR file fails to satisfy a `CompatibilityPreset`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CompatibilityViolation {
+    /// The collection contains no entries at all.
+    NoEntries,
+    /// The entry at `index` is encoded as a PNG, which the preset doesn't
+    /// allow at this size.
+    PngNotAllowed {
+        /// The index of the entry within the collection
+        index: usize,
+        /// The width of the entry's image
+        width: u32,
+        /// The height of the entry's image
+        height: u32,
+    },
+    /// The entry at `index` is encoded as a BMP, but the preset requires PNG
+    /// at this size.
+    PngRequired {
+        /// The index of the entry within the collection
+        index: usize,
+        /// The width of the entry's image
+        width: u32,
+        /// The height of the entry's image
+        height: u32,
+    },
+    /// There is no entry of the given size at the given color depth.
+    MissingDepth {
+        /// The width of the images missing this depth
+        width: u32,
+        /// The height of the images missing this depth
+        height: u32,
+        /// The missing color depth
+        depth: BmpDepth,
+    },
+    /// The entry at `index` has a BMP header that can't be parsed.
+    MalformedEntry {
+        /// The index of the entry within the collection
+        index: usize,
+    },
+}
+
+impl fmt::Display for CompatibilityViolation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompatibilityViolation::NoEntries => {
+                write!(formatter, "Collection has no entries")
+            }
+            CompatibilityViolation::PngNotAllowed { index, width, height } => {
+                write!(
+                    formatter,
+                    "Entry {} ({}x{}) is a PNG, but must be a BMP",
+                    index, width, height
+                )
+            }
+            CompatibilityViolation::PngRequired { index, width, height } => {
+                write!(
+                    formatter,
+                    "Entry {} ({}x{}) is a BMP, but must be a PNG",
+                    index, width, height
+                )
+            }
+            CompatibilityViolation::MissingDepth { width, height, depth } => {
+                write!(
+                    formatter,
+                    "No {}-bpp entry for size {}x{}",
+                    depth.bits_per_pixel(),
+                    width,
+                    height
+                )
+            }
+            CompatibilityViolation::MalformedEntry { index } => {
+                write!(formatter, "Entry {} has a malformed BMP header", index)
+            }
+        }
+    }
+}
+
+//===========================================================================//
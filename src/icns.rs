@@ -0,0 +1,614 @@
+use crate::icondir::IconDir;
+use crate::image::IconImage;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+//===========================================================================//
+
+// The signature that all PNG files start with.
+const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G'];
+
+//===========================================================================//
+
+/// The types of image element within an ICNS file that this library can
+/// decode and encode.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum IcnsType {
+    /// 16x16 RGB, run-length encoded (`is32`), with an `s8mk` alpha mask
+    RleRgb16,
+    /// 32x32 RGB, run-length encoded (`il32`), with an `l8mk` alpha mask
+    RleRgb32,
+    /// 48x48 RGB, run-length encoded (`ih32`), with an `h8mk` alpha mask
+    RleRgb48,
+    /// 128x128 RGB, run-length encoded (`it32`), with a `t8mk` alpha mask
+    RleRgb128,
+    /// 16x16 PNG (`icp4`)
+    Png16,
+    /// 32x32 PNG (`icp5`)
+    Png32,
+    /// 64x64 PNG (`icp6`)
+    Png64,
+    /// 128x128 PNG (`ic07`)
+    Png128,
+    /// 256x256 PNG (`ic08`)
+    Png256,
+    /// 512x512 PNG (`ic09`)
+    Png512,
+    /// 1024x1024 PNG, for 512x512 at 2x scale (`ic10`)
+    Png512At2x,
+    /// 32x32 PNG, for 16x16 at 2x scale (`ic11`)
+    Png16At2x,
+    /// 64x64 PNG, for 32x32 at 2x scale (`ic12`)
+    Png32At2x,
+    /// 256x256 PNG, for 128x128 at 2x scale (`ic13`)
+    Png128At2x,
+    /// 512x512 PNG, for 256x256 at 2x scale (`ic14`)
+    Png256At2x,
+}
+
+impl IcnsType {
+    /// All supported types, in the order they are written to a file.
+    const ALL: [IcnsType; 15] = [
+        IcnsType::RleRgb16,
+        IcnsType::RleRgb32,
+        IcnsType::RleRgb48,
+        IcnsType::RleRgb128,
+        IcnsType::Png16,
+        IcnsType::Png32,
+        IcnsType::Png64,
+        IcnsType::Png128,
+        IcnsType::Png256,
+        IcnsType::Png512,
+        IcnsType::Png512At2x,
+        IcnsType::Png16At2x,
+        IcnsType::Png32At2x,
+        IcnsType::Png128At2x,
+        IcnsType::Png256At2x,
+    ];
+
+    /// Returns the type with the given OSType code, if it is a supported
+    /// image type (mask types are not included).
+    pub fn from_ostype(ostype: [u8; 4]) -> Option<IcnsType> {
+        IcnsType::ALL
+            .iter()
+            .cloned()
+            .find(|icns_type| icns_type.ostype() == ostype)
+    }
+
+    /// Returns the four-character OSType code for this element type.
+    pub fn ostype(&self) -> [u8; 4] {
+        *match *self {
+            IcnsType::RleRgb16 => b"is32",
+            IcnsType::RleRgb32 => b"il32",
+            IcnsType::RleRgb48 => b"ih32",
+            IcnsType::RleRgb128 => b"it32",
+            IcnsType::Png16 => b"icp4",
+            IcnsType::Png32 => b"icp5",
+            IcnsType::Png64 => b"icp6",
+            IcnsType::Png128 => b"ic07",
+            IcnsType::Png256 => b"ic08",
+            IcnsType::Png512 => b"ic09",
+            IcnsType::Png512At2x => b"ic10",
+            IcnsType::Png16At2x => b"ic11",
+            IcnsType::Png32At2x => b"ic12",
+            IcnsType::Png128At2x => b"ic13",
+            IcnsType::Png256At2x => b"ic14",
+        }
+    }
+
+    /// Returns the OSType code of the separate alpha mask element that goes
+    /// with this type, or `None` if this type stores its own alpha.
+    pub fn mask_ostype(&self) -> Option<[u8; 4]> {
+        match *self {
+            IcnsType::RleRgb16 => Some(*b"s8mk"),
+            IcnsType::RleRgb32 => Some(*b"l8mk"),
+            IcnsType::RleRgb48 => Some(*b"h8mk"),
+            IcnsType::RleRgb128 => Some(*b"t8mk"),
+            _ => None,
+        }
+    }
+
+    /// Returns the width and height of images of this type, in pixels.
+    pub fn pixel_size(&self) -> u32 {
+        match *self {
+            IcnsType::RleRgb16 | IcnsType::Png16 => 16,
+            IcnsType::RleRgb32 | IcnsType::Png32 | IcnsType::Png16At2x => 32,
+            IcnsType::RleRgb48 => 48,
+            IcnsType::Png64 | IcnsType::Png32At2x => 64,
+            IcnsType::RleRgb128 | IcnsType::Png128 => 128,
+            IcnsType::Png256 | IcnsType::Png128At2x => 256,
+            IcnsType::Png512 | IcnsType::Png256At2x => 512,
+            IcnsType::Png512At2x => 1024,
+        }
+    }
+
+    /// Returns the types that `IcnsFamily::from_icon_dir` uses for an image
+    /// of the given size (which may be empty, if ICNS has no element type of
+    /// that size).
+    fn types_for_size(size: u32) -> &'static [IcnsType] {
+        match size {
+            16 => &[IcnsType::RleRgb16],
+            32 => &[IcnsType::RleRgb32, IcnsType::Png16At2x],
+            48 => &[IcnsType::RleRgb48],
+            64 => &[IcnsType::Png64, IcnsType::Png32At2x],
+            128 => &[IcnsType::Png128],
+            256 => &[IcnsType::Png256, IcnsType::Png128At2x],
+            512 => &[IcnsType::Png512, IcnsType::Png256At2x],
+            1024 => &[IcnsType::Png512At2x],
+            _ => &[],
+        }
+    }
+}
+
+//===========================================================================//
+
+/// A collection of images at different sizes; the contents of a macOS ICNS
+/// file.
+///
+/// Elements are stored by their four-character OSType code.  Elements of
+/// types this library doesn't understand (such as a table of contents, or
+/// JPEG 2000 data) are preserved when reading and writing, but can't be
+/// decoded.
+#[derive(Clone, Debug, Default)]
+pub struct IcnsFamily {
+    elements: BTreeMap<[u8; 4], Vec<u8>>,
+}
+
+impl IcnsFamily {
+    /// Creates a new, empty icon family.
+    pub fn new() -> IcnsFamily {
+        IcnsFamily::default()
+    }
+
+    /// Returns the OSType codes of all elements in the family, in the order
+    /// they will be written.
+    pub fn ostypes(&self) -> Vec<[u8; 4]> {
+        self.elements.keys().cloned().collect()
+    }
+
+    /// Returns the raw data of the element with the given OSType code, if
+    /// present.
+    pub fn element_data(&self, ostype: [u8; 4]) -> Option<&[u8]> {
+        self.elements.get(&ostype).map(Vec::as_slice)
+    }
+
+    /// Returns the supported image types present in the family.
+    pub fn available_types(&self) -> Vec<IcnsType> {
+        IcnsType::ALL
+            .iter()
+            .cloned()
+            .filter(|icns_type| {
+                self.elements.contains_key(&icns_type.ostype())
+            })
+            .collect()
+    }
+
+    /// Decodes the image of the given type.  For run-length-encoded types,
+    /// the alpha channel comes from the matching mask element (or the image
+    /// is fully opaque if there is none).  Returns an error if the family has
+    /// no such element, or if its data is malformed or unsupported.
+    pub fn get_image(&self, icns_type: IcnsType) -> io::Result<IconImage> {
+        let data = match self.elements.get(&icns_type.ostype()) {
+            Some(data) => data,
+            None => invalid_input!(
+                "ICNS family has no {} element",
+                String::from_utf8_lossy(&icns_type.ostype())
+            ),
+        };
+        let size = icns_type.pixel_size();
+        let mask_ostype = match icns_type.mask_ostype() {
+            Some(mask_ostype) => mask_ostype,
+            None => {
+                if !data.starts_with(PNG_SIGNATURE) {
+                    invalid_data!(
+                        "Unsupported {} element data (not a PNG)",
+                        String::from_utf8_lossy(&icns_type.ostype())
+                    );
+                }
+                let image = IconImage::read_png(data.as_slice())?;
+                if image.width() != size || image.height() != size {
+                    invalid_data!(
+                        "Wrong image size in {} element (was {}x{}, but \
+                         should be {}x{})",
+                        String::from_utf8_lossy(&icns_type.ostype()),
+                        image.width(),
+                        image.height(),
+                        size,
+                        size
+                    );
+                }
+                return Ok(image);
+            }
+        };
+        let num_pixels = (size * size) as usize;
+        let mut data = data.as_slice();
+        if icns_type == IcnsType::RleRgb128 {
+            // The it32 element has four extra zero bytes at the start.
+            if data.len() < 4 {
+                invalid_data!("it32 element is truncated");
+            }
+            data = &data[4..];
+        }
+        let rgb = if data.len() == 4 * num_pixels {
+            // Some files store the pixels uncompressed, as xRGB.
+            let mut rgb = vec![0u8; 3 * num_pixels];
+            for (channel, planar) in
+                rgb.chunks_exact_mut(num_pixels).enumerate()
+            {
+                for (index, value) in planar.iter_mut().enumerate() {
+                    *value = data[4 * index + 1 + channel];
+                }
+            }
+            rgb
+        } else {
+            decode_rle(data, 3 * num_pixels)?
+        };
+        let mask = match self.elements.get(&mask_ostype) {
+            Some(mask) if mask.len() == num_pixels => Some(mask),
+            Some(mask) => invalid_data!(
+                "Wrong {} element size (was {}, but should be {})",
+                String::from_utf8_lossy(&mask_ostype),
+                mask.len(),
+                num_pixels
+            ),
+            None => None,
+        };
+        let mut rgba = Vec::with_capacity(4 * num_pixels);
+        for index in 0..num_pixels {
+            rgba.push(rgb[index]);
+            rgba.push(rgb[num_pixels + index]);
+            rgba.push(rgb[2 * num_pixels + index]);
+            rgba.push(mask.map_or(u8::MAX, |mask| mask[index]));
+        }
+        Ok(IconImage::from_rgba_data(size, size, rgba))
+    }
+
+    /// Encodes an image as an element of the given type (plus its alpha mask,
+    /// for run-length-encoded types), replacing any existing element of that
+    /// type.  Returns an error if the image is the wrong size for the type, or
+    /// if the encoding fails.
+    pub fn add_image(
+        &mut self,
+        image: &IconImage,
+        icns_type: IcnsType,
+    ) -> io::Result<()> {
+        let size = icns_type.pixel_size();
+        if image.width() != size || image.height() != size {
+            invalid_input!(
+                "Wrong image size for {} element (was {}x{}, but must be \
+                 {}x{})",
+                String::from_utf8_lossy(&icns_type.ostype()),
+                image.width(),
+                image.height(),
+                size,
+                size
+            );
+        }
+        let mask_ostype = match icns_type.mask_ostype() {
+            Some(mask_ostype) => mask_ostype,
+            None => {
+                let mut data = Vec::new();
+                image.write_png(&mut data)?;
+                self.elements.insert(icns_type.ostype(), data);
+                return Ok(());
+            }
+        };
+        let mut data = Vec::new();
+        if icns_type == IcnsType::RleRgb128 {
+            data.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        let rgba = image.rgba_data();
+        for channel in 0..3 {
+            let values: Vec<u8> =
+                rgba.chunks_exact(4).map(|pixel| pixel[channel]).collect();
+            encode_rle(&values, &mut data);
+        }
+        let mask: Vec<u8> =
+            rgba.chunks_exact(4).map(|pixel| pixel[3]).collect();
+        self.elements.insert(icns_type.ostype(), data);
+        self.elements.insert(mask_ostype, mask);
+        Ok(())
+    }
+
+    /// Converts an ICO/CUR collection into an icon family, encoding each image
+    /// whose size ICNS supports as the appropriate element type(s).  If there
+    /// are several entries of the same size, the one with the most bits per
+    /// pixel is used.  Entries of other sizes are skipped.  Returns an error
+    /// if any entry can't be decoded or encoded.
+    pub fn from_icon_dir(icon_dir: &IconDir) -> io::Result<IcnsFamily> {
+        let mut best = BTreeMap::<u32, (u16, usize)>::new();
+        for (index, entry) in icon_dir.entries().iter().enumerate() {
+            if entry.width() != entry.height()
+                || IcnsType::types_for_size(entry.width()).is_empty()
+            {
+                continue;
+            }
+            let depth = entry.color_depth();
+            match best.get(&entry.width()) {
+                Some(&(best_depth, _)) if best_depth >= depth => {}
+                _ => {
+                    best.insert(entry.width(), (depth, index));
+                }
+            }
+        }
+        let mut family = IcnsFamily::new();
+        for (&size, &(_, index)) in best.iter() {
+            let image = icon_dir.entries()[index].decode()?;
+            for &icns_type in IcnsType::types_for_size(size).iter() {
+                family.add_image(&image, icns_type)?;
+            }
+        }
+        Ok(family)
+    }
+
+    /// Reads an ICNS file into memory.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<IcnsFamily> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"icns" {
+            invalid_data!("Not an ICNS file (no icns signature)");
+        }
+        let file_len = reader.read_u32::<BigEndian>()?;
+        if file_len < 8 {
+            invalid_data!("Invalid ICNS file length ({})", file_len);
+        }
+        let mut remaining = file_len - 8;
+        let mut elements = BTreeMap::new();
+        while remaining > 0 {
+            let mut ostype = [0u8; 4];
+            reader.read_exact(&mut ostype)?;
+            let element_len = reader.read_u32::<BigEndian>()?;
+            if element_len < 8 || element_len > remaining {
+                invalid_data!(
+                    "Invalid length for ICNS element {} (was {}, but only {} \
+                     bytes remain)",
+                    String::from_utf8_lossy(&ostype),
+                    element_len,
+                    remaining
+                );
+            }
+            // Don't trust the length enough to allocate it all up front.
+            let data_len = (element_len - 8) as u64;
+            let mut data = Vec::new();
+            (&mut reader).take(data_len).read_to_end(&mut data)?;
+            if (data.len() as u64) < data_len {
+                invalid_data!(
+                    "ICNS element {} is truncated (was {} bytes, but should \
+                     be {})",
+                    String::from_utf8_lossy(&ostype),
+                    data.len(),
+                    data_len
+                );
+            }
+            elements.insert(ostype, data);
+            remaining -= element_len;
+        }
+        Ok(IcnsFamily { elements })
+    }
+
+    /// Writes an ICNS file out to disk.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let total_len = 8 + self
+            .elements
+            .values()
+            .map(|data| 8 + data.len() as u64)
+            .sum::<u64>();
+        if total_len > u32::MAX as u64 {
+            invalid_input!("ICNS file is too large ({} bytes)", total_len);
+        }
+        writer.write_all(b"icns")?;
+        writer.write_u32::<BigEndian>(total_len as u32)?;
+        for (ostype, data) in self.elements.iter() {
+            writer.write_all(ostype)?;
+            writer.write_u32::<BigEndian>(8 + data.len() as u32)?;
+            writer.write_all(data)?;
+        }
+        Ok(())
+    }
+}
+
+//===========================================================================//
+
+/// Decodes ICNS run-length-encoded data into exactly `len` bytes.  Each run
+/// starts with a header byte; values below 0x80 are followed by that many
+/// plus one literal bytes, while values of 0x80 and above are followed by a
+/// single byte to be repeated that many minus 0x80 plus three times.
+fn decode_rle(mut data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    while output.len() < len {
+        let header = match data.read_u8() {
+            Ok(header) => header,
+            Err(_) => invalid_data!(
+                "ICNS RLE data is truncated (decoded {} of {} bytes)",
+                output.len(),
+                len
+            ),
+        };
+        if header < 0x80 {
+            let count = header as usize + 1;
+            if data.len() < count {
+                invalid_data!("ICNS RLE literal run is truncated");
+            }
+            output.extend_from_slice(&data[..count]);
+            data = &data[count..];
+        } else {
+            let count = header as usize - 0x80 + 3;
+            let value = data.read_u8()?;
+            output.extend(std::iter::repeat_n(value, count));
+        }
+    }
+    if output.len() > len {
+        invalid_data!(
+            "ICNS RLE data is too long (decoded {} bytes, but expected {})",
+            output.len(),
+            len
+        );
+    }
+    Ok(output)
+}
+
+/// Appends the ICNS run-length encoding of `values` to `output`.
+fn encode_rle(values: &[u8], output: &mut Vec<u8>) {
+    let mut start = 0;
+    let mut literal_start = 0;
+    while start < values.len() {
+        let mut run = 1;
+        while start + run < values.len()
+            && run < 130
+            && values[start + run] == values[start]
+        {
+            run += 1;
+        }
+        if run < 3 {
+            start += run;
+            continue;
+        }
+        flush_literals(&values[literal_start..start], output);
+        output.push((0x80 + run - 3) as u8);
+        output.push(values[start]);
+        start += run;
+        literal_start = start;
+    }
+    flush_literals(&values[literal_start..], output);
+}
+
+/// Appends literal runs (of at most 128 bytes each) for the given values.
+fn flush_literals(values: &[u8], output: &mut Vec<u8>) {
+    for chunk in values.chunks(128) {
+        output.push((chunk.len() - 1) as u8);
+        output.extend_from_slice(chunk);
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_rle, encode_rle, IcnsFamily, IcnsType};
+    use crate::bmpdepth::BmpDepth;
+    use crate::icondir::{IconDir, IconDirEntry};
+    use crate::image::IconImage;
+    use crate::options::BmpEncodingOptions;
+    use crate::restype::ResourceType;
+    use std::io::Cursor;
+
+    #[test]
+    fn icns_type_round_trip() {
+        for &icns_type in IcnsType::ALL.iter() {
+            assert_eq!(
+                IcnsType::from_ostype(icns_type.ostype()),
+                Some(icns_type)
+            );
+        }
+        assert_eq!(IcnsType::from_ostype(*b"s8mk"), None);
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let mut values = vec![1, 2, 3, 3, 3, 3, 4, 5, 5];
+        values.extend_from_slice(&[7; 300]);
+        values.extend((0..200).map(|value| value as u8));
+        let mut encoded = Vec::new();
+        encode_rle(&values, &mut encoded);
+        assert_eq!(&encoded[..8], &[1, 1, 2, 0x81, 3, 2, 4, 5]);
+        assert_eq!(decode_rle(&encoded, values.len()).unwrap(), values);
+        assert!(decode_rle(&encoded, values.len() + 1).is_err());
+        assert!(decode_rle(&encoded, values.len() - 1).is_err());
+    }
+
+    #[test]
+    fn decode_rle_image_with_mask() {
+        let mut family = IcnsFamily::new();
+        let mut rgba = Vec::new();
+        for index in 0..(16 * 16) {
+            rgba.extend_from_slice(&[index as u8, 0x40, 0, (index / 2) as u8]);
+        }
+        let image = IconImage::from_rgba_data(16, 16, rgba);
+        family.add_image(&image, IcnsType::RleRgb16).unwrap();
+        assert_eq!(family.ostypes(), vec![*b"is32", *b"s8mk"]);
+        assert_eq!(family.available_types(), vec![IcnsType::RleRgb16]);
+        let mut data = Vec::new();
+        family.write(&mut data).unwrap();
+        let mut family = IcnsFamily::read(Cursor::new(data)).unwrap();
+        let decoded = family.get_image(IcnsType::RleRgb16).unwrap();
+        assert_eq!(decoded.rgba_data(), image.rgba_data());
+        assert!(family.get_image(IcnsType::Png16).is_err());
+        assert!(family.add_image(&decoded, IcnsType::RleRgb32).is_err());
+    }
+
+    #[test]
+    fn read_truncated_element() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"icns\xff\xff\xff\xffic08\xff\xff\xff\xf0");
+        data.extend_from_slice(&[0; 100]);
+        assert!(IcnsFamily::read(data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn read_uncompressed_it32() {
+        let mut family = IcnsFamily::new();
+        let mut data = vec![0u8; 4];
+        for _ in 0..(128 * 128) {
+            data.extend_from_slice(&[0, 1, 2, 3]);
+        }
+        family.elements.insert(*b"it32", data);
+        let image = family.get_image(IcnsType::RleRgb128).unwrap();
+        assert_eq!(
+            image.rgba_data(),
+            [1, 2, 3, 255].repeat(128 * 128).as_slice()
+        );
+    }
+
+    #[test]
+    fn convert_icon_dir_to_icns() {
+        let mut icon_dir = IconDir::new(ResourceType::Icon);
+        for &size in [16, 24, 32, 256].iter() {
+            let image = IconImage::from_rgba_data(
+                size,
+                size,
+                vec![0x80; (4 * size * size) as usize],
+            );
+            icon_dir.add_entry(IconDirEntry::encode(&image).unwrap());
+        }
+        let family = IcnsFamily::from_icon_dir(&icon_dir).unwrap();
+        assert_eq!(
+            family.available_types(),
+            vec![
+                IcnsType::RleRgb16,
+                IcnsType::RleRgb32,
+                IcnsType::Png256,
+                IcnsType::Png16At2x,
+                IcnsType::Png128At2x,
+            ]
+        );
+        let image = family.get_image(IcnsType::Png128At2x).unwrap();
+        assert_eq!(image.rgba_data(), &[0x80; 4 * 256 * 256][..]);
+    }
+
+    #[test]
+    fn convert_cursors_using_deepest_entry() {
+        let mut icon_dir = IconDir::new(ResourceType::Cursor);
+        for &(color, depth) in
+            [(0x00, BmpDepth::Four), (0xff, BmpDepth::ThirtyTwo)].iter()
+        {
+            let mut image = IconImage::from_rgba_data(
+                16,
+                16,
+                [0, 0, color, 0xff].repeat(16 * 16),
+            );
+            image.set_cursor_hotspot(Some((2, 3)));
+            let mut options = BmpEncodingOptions::new();
+            options.set_depth(Some(depth));
+            let entry =
+                IconDirEntry::encode_as_bmp_with_options(&image, &options)
+                    .unwrap();
+            icon_dir.add_entry(entry);
+        }
+        let family = IcnsFamily::from_icon_dir(&icon_dir).unwrap();
+        let image = family.get_image(IcnsType::RleRgb16).unwrap();
+        assert_eq!(image.rgba_data(), [0, 0, 0xff, 0xff].repeat(16 * 16));
+    }
+}
+
+//===========================================================================//
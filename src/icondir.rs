@@ -1,4 +1,11 @@
+use crate::bmpdepth::BmpDepth;
+use crate::bmpheader::{BmpHeader, BmpHeaderType};
 use crate::image::{IconImage, ImageStats};
+use crate::indexed::IndexedImage;
+use crate::options::{
+    BmpEncodingOptions, EncodingOptions, FormatPolicy, PngEncodingOptions,
+};
+use crate::resize::{self, ResizeFilter};
 use crate::restype::ResourceType;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 #[cfg(feature = "serde")]
@@ -21,6 +28,10 @@ pub struct IconDir {
 }
 
 impl IconDir {
+    /// The image sizes that `IconDir::from_image` generates: the sizes that
+    /// Windows commonly uses for icons at various display scales.
+    pub const DEFAULT_SIZES: [u32; 7] = [16, 24, 32, 48, 64, 128, 256];
+
     /// Creates a new, empty collection of icons/cursors.
     pub fn new(resource_type: ResourceType) -> IconDir {
         IconDir { restype: resource_type, entries: Vec::new() }
@@ -50,6 +61,54 @@ impl IconDir {
         self.entries.push(entry);
     }
 
+    /// Builds a complete collection of icons from a single (typically large)
+    /// image, by resizing it to each of the `DEFAULT_SIZES` and encoding
+    /// each result with `IconDirEntry::encode`.  Returns an error if any of
+    /// the encodings fail.
+    pub fn from_image(image: &IconImage) -> io::Result<IconDir> {
+        IconDir::from_image_with_sizes(
+            image,
+            &IconDir::DEFAULT_SIZES,
+            &EncodingOptions::new(),
+        )
+    }
+
+    /// Builds a complete collection of icons from a single image, by resizing
+    /// it to each of the given sizes (using a Lanczos3 filter) and encoding
+    /// each result with the given options.  Images that aren't square are
+    /// scaled to fit, and centered on a transparent background.  If the image
+    /// has a cursor hotspot, the collection will contain cursors (with scaled
+    /// hotspots) rather than icons.  Returns an error if any size is zero or
+    /// greater than 256, or if any of the encodings fail.
+    pub fn from_image_with_sizes(
+        image: &IconImage,
+        sizes: &[u32],
+        options: &EncodingOptions,
+    ) -> io::Result<IconDir> {
+        let restype = if image.cursor_hotspot().is_some() {
+            ResourceType::Cursor
+        } else {
+            ResourceType::Icon
+        };
+        let mut icon_dir = IconDir::new(restype);
+        for &size in sizes.iter() {
+            if !(1..=256).contains(&size) {
+                invalid_input!(
+                    "Invalid icon size (was {}, but must be from 1 to 256)",
+                    size
+                );
+            }
+            let resized = if image.width() == size && image.height() == size {
+                image.clone()
+            } else {
+                resize::resize_to_square(image, size, ResizeFilter::Lanczos3)
+            };
+            let entry = IconDirEntry::encode_with_options(&resized, options)?;
+            icon_dir.add_entry(entry);
+        }
+        Ok(icon_dir)
+    }
+
     /// Reads an ICO or CUR file into memory.
     pub fn read<R: Read + Seek>(mut reader: R) -> io::Result<IconDir> {
         // Get total file length so we can validate size fields.
@@ -180,6 +239,153 @@ impl IconDir {
         }
         Ok(())
     }
+
+    /// Builds a collection from the data of an RT_GROUP_ICON or
+    /// RT_GROUP_CURSOR resource (as found in PE and .res files), using
+    /// `member` to look up the data of each RT_ICON or RT_CURSOR resource
+    /// that the group refers to by ID.  Returns an error if the group data is
+    /// malformed or if any member resource is missing.
+    pub(crate) fn read_resource_group<'a, F>(
+        mut group: &[u8],
+        mut member: F,
+    ) -> io::Result<IconDir>
+    where
+        F: FnMut(u16) -> Option<&'a [u8]>,
+    {
+        let reserved = group.read_u16::<LittleEndian>()?;
+        if reserved != 0 {
+            invalid_data!(
+                "Invalid reserved field value in GRPICONDIR \
+                 (was {}, but must be 0)",
+                reserved
+            );
+        }
+        let restype = group.read_u16::<LittleEndian>()?;
+        let restype = match ResourceType::from_number(restype) {
+            Some(restype) => restype,
+            None => invalid_data!("Invalid resource type ({})", restype),
+        };
+        let num_entries = group.read_u16::<LittleEndian>()? as usize;
+        let mut entries = Vec::<IconDirEntry>::with_capacity(num_entries);
+        for _ in 0..num_entries {
+            // Icon groups use the same width/height bytes as ICO files,
+            // while cursor groups use 16-bit fields, with the height doubled
+            // (as in a BMP's header, to count the AND mask).
+            let (width, height, num_colors) = match restype {
+                ResourceType::Icon => {
+                    let width = group.read_u8()?;
+                    let height = group.read_u8()?;
+                    let num_colors = group.read_u8()?;
+                    let _reserved = group.read_u8()?;
+                    let width = if width == 0 { 256 } else { width as u32 };
+                    let height = if height == 0 { 256 } else { height as u32 };
+                    (width, height, num_colors)
+                }
+                ResourceType::Cursor => {
+                    let width = group.read_u16::<LittleEndian>()? as u32;
+                    let height = group.read_u16::<LittleEndian>()? as u32;
+                    (width, height / 2, 0)
+                }
+            };
+            let color_planes = group.read_u16::<LittleEndian>()?;
+            let bits_per_pixel = group.read_u16::<LittleEndian>()?;
+            let _data_size = group.read_u32::<LittleEndian>()?;
+            let id = group.read_u16::<LittleEndian>()?;
+            let mut data = match member(id) {
+                Some(data) => data,
+                None => invalid_data!(
+                    "Resource group refers to missing member (ID {})",
+                    id
+                ),
+            };
+            // Cursor resources store the hotspot at the start of the member
+            // data, rather than in the group entry.
+            let (color_planes, bits_per_pixel) = match restype {
+                ResourceType::Icon => (color_planes, bits_per_pixel),
+                ResourceType::Cursor => {
+                    let x = data.read_u16::<LittleEndian>()?;
+                    let y = data.read_u16::<LittleEndian>()?;
+                    (x, y)
+                }
+            };
+            let mut entry = IconDirEntry {
+                restype,
+                width,
+                height,
+                num_colors,
+                color_planes,
+                bits_per_pixel,
+                data: data.to_vec(),
+            };
+            // As in IconDir::read, defer any errors here until the user tries
+            // to decode the entry.
+            if let Ok((width, height)) = entry.decode_size() {
+                entry.width = width;
+                entry.height = height;
+            }
+            entries.push(entry);
+        }
+        Ok(IconDir { restype, entries })
+    }
+
+    /// Serializes this collection as the data of an RT_GROUP_ICON or
+    /// RT_GROUP_CURSOR resource, plus the data of each RT_ICON or RT_CURSOR
+    /// member resource, with the members numbered consecutively starting
+    /// from `first_id`.  Returns an error if the member IDs would be out of
+    /// range.
+    pub(crate) fn write_resource_group(
+        &self,
+        first_id: u16,
+    ) -> io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        if first_id == 0
+            || first_id as usize + self.entries.len() > u16::MAX as usize + 1
+        {
+            invalid_input!(
+                "Invalid first member ID for {} entries (was {})",
+                self.entries.len(),
+                first_id
+            );
+        }
+        let mut group = Vec::with_capacity(6 + 14 * self.entries.len());
+        group.write_u16::<LittleEndian>(0)?; // reserved
+        group.write_u16::<LittleEndian>(self.restype.number())?;
+        group.write_u16::<LittleEndian>(self.entries.len() as u16)?;
+        let mut members = Vec::with_capacity(self.entries.len());
+        for (index, entry) in self.entries.iter().enumerate() {
+            let mut data = Vec::with_capacity(4 + entry.data.len());
+            match self.restype {
+                ResourceType::Icon => {
+                    let width =
+                        if entry.width > 255 { 0 } else { entry.width as u8 };
+                    let height = if entry.height > 255 {
+                        0
+                    } else {
+                        entry.height as u8
+                    };
+                    group.write_u8(width)?;
+                    group.write_u8(height)?;
+                    group.write_u8(entry.num_colors)?;
+                    group.write_u8(0)?; // reserved
+                    group.write_u16::<LittleEndian>(entry.color_planes)?;
+                    group.write_u16::<LittleEndian>(entry.bits_per_pixel)?;
+                }
+                ResourceType::Cursor => {
+                    group.write_u16::<LittleEndian>(entry.width as u16)?;
+                    group
+                        .write_u16::<LittleEndian>(2 * entry.height as u16)?;
+                    group.write_u16::<LittleEndian>(1)?; // color planes
+                    group.write_u16::<LittleEndian>(entry.color_depth())?;
+                    data.write_u16::<LittleEndian>(entry.color_planes)?;
+                    data.write_u16::<LittleEndian>(entry.bits_per_pixel)?;
+                }
+            }
+            data.extend_from_slice(&entry.data);
+            group.write_u32::<LittleEndian>(data.len() as u32)?;
+            group.write_u16::<LittleEndian>(first_id + index as u16)?;
+            members.push(data);
+        }
+        Ok((group, members))
+    }
 }
 
 //===========================================================================//
@@ -242,11 +448,52 @@ impl IconDirEntry {
         self.data.starts_with(PNG_SIGNATURE)
     }
 
+    /// Returns the type of BMP header used to encode the image, or `None` if
+    /// the image is encoded as a PNG (or if the BMP header is unrecognized).
+    pub fn bmp_header_type(&self) -> Option<BmpHeaderType> {
+        if self.is_png() {
+            return None;
+        }
+        let header_size =
+            self.data.as_slice().read_u32::<LittleEndian>().ok()?;
+        BmpHeaderType::from_size(header_size)
+    }
+
+    /// Returns true if the image is encoded as a top-down BMP (that is, one
+    /// whose header has a negative height, with rows stored from top to
+    /// bottom rather than the usual bottom to top).  Returns false for PNG
+    /// images, and for BMP images with malformed headers.
+    pub fn is_top_down_bmp(&self) -> bool {
+        if self.is_png() {
+            return false;
+        }
+        match BmpHeader::read(&mut self.data.as_slice()) {
+            Ok(header) => header.top_down,
+            Err(_) => false,
+        }
+    }
+
     /// Returns the raw, encoded image data.
     pub fn data(&self) -> &[u8] {
         &self.data
     }
 
+    /// Returns the color depth of the image, treating PNGs as 32-bit and
+    /// reading it from the BMP header for cursors (whose bits-per-pixel field
+    /// holds the hotspot instead).
+    pub(crate) fn color_depth(&self) -> u16 {
+        if self.is_png() {
+            32
+        } else if self.restype == ResourceType::Icon {
+            self.bits_per_pixel
+        } else {
+            match BmpHeader::read(&mut self.data.as_slice()) {
+                Ok(header) => header.bits_per_pixel,
+                Err(_) => 0,
+            }
+        }
+    }
+
     /// Decodes just enough of the raw image data to determine its size.
     pub(crate) fn decode_size(&mut self) -> io::Result<(u32, u32)> {
         if self.is_png() {
@@ -283,17 +530,78 @@ impl IconDirEntry {
     /// automatically based on the image.  Returns an error if the encoding
     /// fails.
     pub fn encode(image: &IconImage) -> io::Result<IconDirEntry> {
+        IconDirEntry::encode_with_options(image, &EncodingOptions::new())
+    }
+
+    /// Encodes an image in a new entry, using the given options to choose
+    /// between BMP and PNG and to control each encoding.  Returns an error if
+    /// the encoding fails.
+    pub fn encode_with_options(
+        image: &IconImage,
+        options: &EncodingOptions,
+    ) -> io::Result<IconDirEntry> {
         let stats = image.compute_stats();
-        // Very rough heuristic: Use PNG only for images with complicated alpha
-        // or for large images, which are cases where PNG's better compression
-        // is a big savings.  Otherwise, prefer BMP for its better
-        // backwards-compatibility with older ICO consumers.
-        let use_png = stats.has_nonbinary_alpha
-            || image.width() * image.height() > 64 * 64;
-        if use_png {
-            IconDirEntry::encode_as_png_internal(image, &stats)
-        } else {
-            IconDirEntry::encode_as_bmp_internal(image, &stats)
+        let bmp_options = options.bmp_options();
+        let png_options = options.png_options();
+        match options.format_policy() {
+            FormatPolicy::Heuristic => {
+                // Very rough heuristic: Use PNG only for images with
+                // complicated alpha or for large images, which are cases
+                // where PNG's better compression is a big savings.
+                // Otherwise, prefer BMP for its better backwards-compatibility
+                // with older ICO consumers.
+                let use_png = stats.has_nonbinary_alpha
+                    || image.width() * image.height() > 64 * 64;
+                if use_png {
+                    IconDirEntry::encode_as_png_internal(
+                        image,
+                        &stats,
+                        png_options,
+                    )
+                } else {
+                    IconDirEntry::encode_as_bmp_internal(
+                        image,
+                        &stats,
+                        bmp_options,
+                    )
+                }
+            }
+            FormatPolicy::AlwaysBmp => IconDirEntry::encode_as_bmp_internal(
+                image,
+                &stats,
+                bmp_options,
+            ),
+            FormatPolicy::AlwaysPng => IconDirEntry::encode_as_png_internal(
+                image,
+                &stats,
+                png_options,
+            ),
+            policy @ FormatPolicy::Smallest { .. } => {
+                let bmp_result = IconDirEntry::encode_as_bmp_internal(
+                    image,
+                    &stats,
+                    bmp_options,
+                );
+                if !policy.allows_png(image.width(), image.height()) {
+                    return bmp_result;
+                }
+                let png_entry = IconDirEntry::encode_as_png_internal(
+                    image,
+                    &stats,
+                    png_options,
+                )?;
+                // If the BMP options rule out this image (e.g. because it
+                // can't be represented at the requested color depth), fall
+                // back to the PNG.
+                match bmp_result {
+                    Ok(bmp_entry)
+                        if bmp_entry.data.len() <= png_entry.data.len() =>
+                    {
+                        Ok(bmp_entry)
+                    }
+                    _ => Ok(png_entry),
+                }
+            }
         }
     }
 
@@ -301,15 +609,68 @@ impl IconDirEntry {
     /// determined automatically based on the image.  Returns an error if the
     /// encoding fails.
     pub fn encode_as_bmp(image: &IconImage) -> io::Result<IconDirEntry> {
-        IconDirEntry::encode_as_bmp_internal(image, &image.compute_stats())
+        IconDirEntry::encode_as_bmp_with_options(
+            image,
+            &BmpEncodingOptions::new(),
+        )
+    }
+
+    /// Encodes an image as a BMP in a new entry, using the given options
+    /// (e.g. to choose a specific color depth).  Returns an error if the
+    /// encoding fails, or if the image can't be represented at the requested
+    /// color depth without quantization and the options don't allow it.
+    pub fn encode_as_bmp_with_options(
+        image: &IconImage,
+        options: &BmpEncodingOptions,
+    ) -> io::Result<IconDirEntry> {
+        IconDirEntry::encode_as_bmp_internal(
+            image,
+            &image.compute_stats(),
+            options,
+        )
+    }
+
+    /// Encodes an image that has already been reduced to a palette as a BMP
+    /// in a new entry, using the smallest color depth that fits the palette.
+    /// Returns an error if the encoding fails.
+    pub fn encode_indexed_as_bmp(
+        image: &IndexedImage,
+    ) -> io::Result<IconDirEntry> {
+        let depth = if image.palette().len() <= 2 {
+            BmpDepth::One
+        } else if image.palette().len() <= 16 {
+            BmpDepth::Four
+        } else {
+            BmpDepth::Eight
+        };
+        // The color table and pixel data come straight from the palette and
+        // indices (so that their order is kept); the RGBA form of the image
+        // only supplies the mask.
+        let rgba_image = image.to_rgba_image();
+        let bmp = rgba_image.write_bmp_with_indices(
+            depth,
+            image.palette(),
+            image.indices(),
+            &BmpEncodingOptions::new(),
+        )?;
+        Ok(IconDirEntry::from_bmp(&rgba_image, bmp))
     }
 
     fn encode_as_bmp_internal(
         image: &IconImage,
         stats: &ImageStats,
+        options: &BmpEncodingOptions,
     ) -> io::Result<IconDirEntry> {
-        let (num_colors, bits_per_pixel, data) =
-            image.write_bmp_internal(stats)?;
+        let bmp = image.write_bmp_internal(stats, options)?;
+        Ok(IconDirEntry::from_bmp(image, bmp))
+    }
+
+    /// Creates an entry for an image from its encoded BMP data, along with
+    /// the size of the BMP's color table and its bits-per-pixel.
+    fn from_bmp(
+        image: &IconImage,
+        (num_colors, bits_per_pixel, data): (u8, u16, Vec<u8>),
+    ) -> IconDirEntry {
         let (color_planes, bits_per_pixel) =
             image.cursor_hotspot().unwrap_or((1, bits_per_pixel));
         let restype = if image.cursor_hotspot().is_some() {
@@ -317,7 +678,7 @@ impl IconDirEntry {
         } else {
             ResourceType::Icon
         };
-        let entry = IconDirEntry {
+        IconDirEntry {
             restype,
             width: image.width(),
             height: image.height(),
@@ -325,23 +686,42 @@ impl IconDirEntry {
             color_planes,
             bits_per_pixel,
             data,
-        };
-        Ok(entry)
+        }
     }
 
     /// Encodes an image as a PNG in a new entry.  The color depth is
     /// determined automatically based on the image.  Returns an error if the
     /// encoding fails.
     pub fn encode_as_png(image: &IconImage) -> io::Result<IconDirEntry> {
-        IconDirEntry::encode_as_png_internal(image, &image.compute_stats())
+        IconDirEntry::encode_as_png_with_options(
+            image,
+            &PngEncodingOptions::new(),
+        )
+    }
+
+    /// Encodes an image as a PNG in a new entry, using the given options
+    /// (e.g. to choose the compression level).  The color depth is determined
+    /// automatically based on the image.  Returns an error if the encoding
+    /// fails.
+    pub fn encode_as_png_with_options(
+        image: &IconImage,
+        options: &PngEncodingOptions,
+    ) -> io::Result<IconDirEntry> {
+        IconDirEntry::encode_as_png_internal(
+            image,
+            &image.compute_stats(),
+            options,
+        )
     }
 
     fn encode_as_png_internal(
         image: &IconImage,
         stats: &ImageStats,
+        options: &PngEncodingOptions,
     ) -> io::Result<IconDirEntry> {
         let mut data = Vec::new();
-        let bits_per_pixel = image.write_png_internal(stats, &mut data)?;
+        let bits_per_pixel =
+            image.write_png_internal(stats, options, &mut data)?;
         let (color_planes, bits_per_pixel) =
             image.cursor_hotspot().unwrap_or((0, bits_per_pixel));
         let restype = if image.cursor_hotspot().is_some() {
@@ -366,7 +746,9 @@ impl IconDirEntry {
 
 #[cfg(test)]
 mod tests {
-    use super::{IconDir, IconDirEntry, IconImage, ResourceType};
+    use super::{
+        BmpHeaderType, IconDir, IconDirEntry, IconImage, ResourceType,
+    };
     use std::io::Cursor;
 
     #[test]
@@ -430,6 +812,7 @@ mod tests {
         assert_eq!(entry.width(), 2);
         assert_eq!(entry.height(), 2);
         assert!(!entry.is_png());
+        assert!(!entry.is_top_down_bmp());
         let image = entry.decode().unwrap();
         assert_eq!(image.width(), 2);
         assert_eq!(image.height(), 2);
@@ -439,6 +822,72 @@ mod tests {
         assert_eq!(image.rgba_data(), rgba);
     }
 
+    #[test]
+    fn read_bmp_1bpp_top_down_icon() {
+        // This is the same image as in the above test, but with a negative
+        // height, so that the rows of both the color data and the alpha mask
+        // are stored from top to bottom.
+        let input: &[u8] = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x02\x02\x02\x00\x01\x00\x01\x00\
+            \x40\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x28\x00\x00\x00\x02\x00\x00\x00\xfc\xff\xff\xff\
+            \x01\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\
+            \
+            \x55\x00\x55\x00\xff\xff\xff\x00\
+            \
+            \x40\x00\x00\x00\
+            \xc0\x00\x00\x00\
+            \
+            \x00\x00\x00\x00\
+            \x40\x00\x00\x00";
+        let icondir = IconDir::read(Cursor::new(input)).unwrap();
+        let entry = &icondir.entries()[0];
+        assert_eq!(entry.width(), 2);
+        assert_eq!(entry.height(), 2);
+        assert!(entry.is_top_down_bmp());
+        let image = entry.decode().unwrap();
+        let rgba: &[u8] = b"\
+            \x55\x00\x55\xff\xff\xff\xff\xff\
+            \xff\xff\xff\xff\xff\xff\xff\x00";
+        assert_eq!(image.rgba_data(), rgba);
+    }
+
+    #[test]
+    fn read_bmp_1bpp_icon_with_core_header() {
+        // This image uses an OS/2 BITMAPCOREHEADER, with a color table of
+        // 3-byte RGBTRIPLE entries.
+        let input: &[u8] = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x02\x02\x02\x00\x01\x00\x01\x00\
+            \x22\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x0c\x00\x00\x00\x02\x00\x04\x00\x01\x00\x01\x00\
+            \
+            \x55\x00\x55\xff\xff\xff\
+            \
+            \xc0\x00\x00\x00\
+            \x40\x00\x00\x00\
+            \
+            \x40\x00\x00\x00\
+            \x00\x00\x00\x00";
+        let icondir = IconDir::read(Cursor::new(input)).unwrap();
+        let entry = &icondir.entries()[0];
+        assert_eq!(entry.width(), 2);
+        assert_eq!(entry.height(), 2);
+        assert_eq!(entry.bmp_header_type(), Some(BmpHeaderType::Core));
+        let image = entry.decode().unwrap();
+        let rgba: &[u8] = b"\
+            \x55\x00\x55\xff\xff\xff\xff\xff\
+            \xff\xff\xff\xff\xff\xff\xff\x00";
+        assert_eq!(image.rgba_data(), rgba);
+    }
+
     #[test]
     fn read_bmp_4bpp_icon() {
         let input: &[u8] = b"\
@@ -488,6 +937,198 @@ mod tests {
         assert_eq!(image.rgba_data(), rgba);
     }
 
+    #[test]
+    fn read_bmp_8bpp_icon_with_short_color_table() {
+        // This image's color table has only two entries (as given by the
+        // biClrUsed field), rather than the usual 256.
+        let mut input: Vec<u8> = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x02\x01\x00\x00\x01\x00\x08\x00\
+            \x38\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x28\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\
+            \x01\x00\x08\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x00\x00\
+            \x00\x00\x00\x00\
+            \
+            \x00\x00\xff\x00\xff\x00\x00\x00\
+            \
+            \x01\x00\x00\x00\
+            \
+            \x00\x00\x00\x00"
+            .to_vec();
+        let icondir = IconDir::read(Cursor::new(&input)).unwrap();
+        let image = icondir.entries()[0].decode().unwrap();
+        let rgba: &[u8] = b"\x00\x00\xff\xff\xff\x00\x00\xff";
+        assert_eq!(image.rgba_data(), rgba);
+        // If a pixel refers to a color past the end of the table, decoding
+        // should fail (rather than panic).
+        input[70] = 2;
+        let icondir = IconDir::read(Cursor::new(&input)).unwrap();
+        assert!(icondir.entries()[0].decode().is_err());
+    }
+
+    #[test]
+    fn read_bmp_32bpp_icon_with_unused_alpha() {
+        // This image's alpha channel is all zeros, so the AND mask should be
+        // used for transparency instead.
+        let input: &[u8] = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x02\x02\x00\x00\x01\x00\x20\x00\
+            \x40\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x28\x00\x00\x00\x02\x00\x00\x00\x04\x00\x00\x00\
+            \x01\x00\x20\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\
+            \
+            \x00\x00\xff\x00\x00\xff\x00\x00\
+            \xff\x00\x00\x00\x7f\x7f\x7f\x00\
+            \
+            \x40\x00\x00\x00\
+            \x80\x00\x00\x00";
+        let icondir = IconDir::read(Cursor::new(input)).unwrap();
+        let image = icondir.entries()[0].decode().unwrap();
+        let rgba: &[u8] = b"\
+            \x00\x00\xff\x00\x7f\x7f\x7f\xff\
+            \xff\x00\x00\xff\x00\xff\x00\x00";
+        assert_eq!(image.rgba_data(), rgba);
+    }
+
+    #[test]
+    fn read_bmp_32bpp_icon_with_v4_header() {
+        // This image uses a BITMAPV4HEADER, with BI_BITFIELDS compression and
+        // color masks (including an alpha mask) stored in the header.
+        let input: &[u8] = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x01\x02\x00\x00\x01\x00\x20\x00\
+            \x7c\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x6c\x00\x00\x00\x01\x00\x00\x00\x04\x00\x00\x00\
+            \x01\x00\x20\x00\x03\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\
+            \xff\x00\x00\x00\x00\xff\x00\x00\x00\x00\xff\x00\
+            \x00\x00\x00\xff\x42\x47\x52\x73\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \
+            \x11\x22\x33\x80\
+            \x44\x55\x66\xff\
+            \
+            \x00\x00\x00\x00\
+            \x00\x00\x00\x00";
+        let icondir = IconDir::read(Cursor::new(input)).unwrap();
+        let entry = &icondir.entries()[0];
+        assert_eq!(entry.width(), 1);
+        assert_eq!(entry.height(), 2);
+        assert_eq!(entry.bmp_header_type(), Some(BmpHeaderType::V4));
+        let image = entry.decode().unwrap();
+        let rgba: &[u8] = b"\x44\x55\x66\xff\x11\x22\x33\x80";
+        assert_eq!(image.rgba_data(), rgba);
+    }
+
+    #[test]
+    fn read_bmp_16bpp_icon_with_bitfields() {
+        // This image uses BI_BITFIELDS compression with RGB565 masks.
+        let input: &[u8] = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x02\x01\x00\x00\x01\x00\x10\x00\
+            \x3c\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x28\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\
+            \x01\x00\x10\x00\x03\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\
+            \
+            \x00\xf8\x00\x00\xe0\x07\x00\x00\x1f\x00\x00\x00\
+            \
+            \x00\xf8\xe0\x07\
+            \
+            \x40\x00\x00\x00";
+        let icondir = IconDir::read(Cursor::new(input)).unwrap();
+        let image = icondir.entries()[0].decode().unwrap();
+        let rgba: &[u8] = b"\xff\x00\x00\xff\x00\xff\x00\x00";
+        assert_eq!(image.rgba_data(), rgba);
+    }
+
+    #[test]
+    fn read_bmp_4bpp_icon_with_rle4() {
+        let input: &[u8] = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x04\x02\x10\x00\x01\x00\x04\x00\
+            \x7a\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x28\x00\x00\x00\x04\x00\x00\x00\x04\x00\x00\x00\
+            \x01\x00\x04\x00\x02\x00\x00\x00\x0a\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\
+            \
+            \x00\x00\x00\x00\x00\x00\xff\x00\
+            \x00\xff\x00\x00\xff\x00\x00\x00\
+            \xff\xff\xff\x00\x7f\x7f\x7f\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\
+            \
+            \x04\x12\x00\x00\
+            \x00\x03\x34\x50\
+            \x00\x01\
+            \
+            \x00\x00\x00\x00\
+            \x10\x00\x00\x00";
+        let icondir = IconDir::read(Cursor::new(input)).unwrap();
+        let entry = &icondir.entries()[0];
+        assert_eq!(entry.width(), 4);
+        assert_eq!(entry.height(), 2);
+        let image = entry.decode().unwrap();
+        let rgba: &[u8] = b"\
+            \x00\x00\xff\xff\xff\xff\xff\xff\
+            \x7f\x7f\x7f\xff\x00\x00\x00\x00\
+            \xff\x00\x00\xff\x00\xff\x00\xff\
+            \xff\x00\x00\xff\x00\xff\x00\xff";
+        assert_eq!(image.rgba_data(), rgba);
+    }
+
+    #[test]
+    fn read_bmp_icon_with_unsupported_compression() {
+        // This image claims to use BI_JPEG compression, which isn't valid for
+        // an icon.
+        let input: &[u8] = b"\
+            \x00\x00\x01\x00\x01\x00\
+            \
+            \x01\x01\x00\x00\x01\x00\x20\x00\
+            \x30\x00\x00\x00\x16\x00\x00\x00\
+            \
+            \x28\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\
+            \x01\x00\x20\x00\x04\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x00\x00\x00\x00\
+            \
+            \x00\x00\x00\xff\
+            \
+            \x00\x00\x00\x00";
+        let icondir = IconDir::read(Cursor::new(input)).unwrap();
+        match icondir.entries()[0].decode() {
+            Ok(_) => panic!("decode should have failed"),
+            Err(error) => {
+                assert_eq!(
+                    error.to_string(),
+                    "Unsupported BMP compression (4)"
+                )
+            }
+        }
+    }
+
     #[test]
     fn read_png_grayscale_icon() {
         let input: &[u8] = b"\
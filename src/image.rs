@@ -1,4 +1,12 @@
+use crate::bmpcompression::{decode_rle, BmpColorMasks, BmpCompression};
 use crate::bmpdepth::BmpDepth;
+use crate::bmpheader::{BmpHeader, BmpHeaderType};
+use crate::indexed::IndexedImage;
+use crate::options::{
+    BmpEncodingOptions, PngCompression, PngEncodingOptions, PngFilter,
+};
+use crate::quantize::{Dithering, Quantizer};
+use crate::resize::{self, ResizeFilter};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::collections::{BTreeSet, HashMap};
 use std::io::{self, Read, Write};
@@ -9,8 +17,8 @@ use std::io::{self, Read, Write};
 const BMP_HEADER_LEN: u32 = 40;
 
 // Size limits for images in an ICO file:
-const MIN_WIDTH: u32 = 1;
-const MIN_HEIGHT: u32 = 1;
+pub(crate) const MIN_WIDTH: u32 = 1;
+pub(crate) const MIN_HEIGHT: u32 = 1;
 const MAX_PIXELS: u64 = 8192 * 8192;
 //===========================================================================//
 
@@ -87,7 +95,12 @@ impl IconImage {
     pub(crate) fn read_png_info<R: Read>(
         reader: R,
     ) -> io::Result<png::Reader<R>> {
-        let decoder = png::Decoder::new(reader);
+        let mut decoder = png::Decoder::new(reader);
+        // Expand palette images, low bit depths, and any tRNS chunk, and strip
+        // 16-bit samples down to 8 bits, so that we always get 8-bit gray,
+        // gray+alpha, RGB, or RGBA samples.
+        decoder
+            .set_transformations(png::Transformations::normalize_to_color8());
         let png_reader = match decoder.read_info() {
             Ok(png_reader) => png_reader,
             Err(error) => invalid_data!("Malformed PNG data: {}", error),
@@ -111,10 +124,6 @@ impl IconImage {
                 MIN_HEIGHT
             );
         }
-        if info.bit_depth != png::BitDepth::Eight {
-            // TODO: Support other bit depths.
-            invalid_data!("Unsupported PNG bit depth: {:?}", info.bit_depth);
-        }
         Ok(())
     }
 
@@ -127,7 +136,13 @@ impl IconImage {
             Ok(_) => {}
             Err(error) => invalid_data!("Malformed PNG data: {}", error),
         }
-        let rgba_data = match png_reader.info().color_type {
+        let (color_type, bit_depth) = png_reader.output_color_type();
+        if bit_depth != png::BitDepth::Eight {
+            // This shouldn't happen, since we ask the decoder to normalize
+            // all samples to 8 bits.
+            invalid_data!("Unsupported PNG bit depth: {:?}", bit_depth);
+        }
+        let rgba_data = match color_type {
             png::ColorType::Rgba => buffer,
             png::ColorType::Rgb => {
                 let num_pixels = buffer.len() / 3;
@@ -162,11 +177,9 @@ impl IconImage {
                 rgba
             }
             png::ColorType::Indexed => {
-                // TODO: Implement ColorType::Indexed conversion
-                invalid_data!(
-                    "Unsupported PNG color type: {:?}",
-                    png_reader.info().color_type
-                );
+                // This shouldn't happen, since we ask the decoder to expand
+                // palette data into RGB or RGBA samples.
+                invalid_data!("Unsupported PNG color type: {:?}", color_type);
             }
         };
         Ok(IconImage::from_rgba_data(
@@ -178,8 +191,18 @@ impl IconImage {
 
     /// Encodes the image as a PNG file.
     pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.write_png_with_options(writer, &PngEncodingOptions::new())
+    }
+
+    /// Encodes the image as a PNG file, using the given options (e.g. to
+    /// choose the compression level).
+    pub fn write_png_with_options<W: Write>(
+        &self,
+        writer: W,
+        options: &PngEncodingOptions,
+    ) -> io::Result<()> {
         let _bits_per_pixel =
-            self.write_png_internal(&self.compute_stats(), writer)?;
+            self.write_png_internal(&self.compute_stats(), options, writer)?;
         Ok(())
     }
 
@@ -187,9 +210,49 @@ impl IconImage {
     pub(crate) fn write_png_internal<W: Write>(
         &self,
         stats: &ImageStats,
+        options: &PngEncodingOptions,
+        mut writer: W,
+    ) -> io::Result<u16> {
+        let compression = options.compression();
+        if options.filter() != PngFilter::Smallest {
+            return self.write_png_with_filter(
+                stats,
+                compression,
+                options.filter(),
+                writer,
+            );
+        }
+        let mut best: Option<(u16, Vec<u8>)> = None;
+        for filter in PngFilter::ALL {
+            let mut data = Vec::new();
+            let bits_per_pixel = self.write_png_with_filter(
+                stats,
+                compression,
+                filter,
+                &mut data,
+            )?;
+            if best
+                .as_ref()
+                .is_none_or(|(_, best_data)| data.len() < best_data.len())
+            {
+                best = Some((bits_per_pixel, data));
+            }
+        }
+        let (bits_per_pixel, data) = best.unwrap();
+        writer.write_all(&data)?;
+        Ok(bits_per_pixel)
+    }
+
+    /// Encodes the image as a PNG file with a single filtering strategy, and
+    /// returns the bits-per-pixel.
+    fn write_png_with_filter<W: Write>(
+        &self,
+        stats: &ImageStats,
+        compression: PngCompression,
+        filter: PngFilter,
         writer: W,
     ) -> io::Result<u16> {
-        match self.write_png_internal_enc(stats, writer) {
+        match self.write_png_internal_enc(stats, compression, filter, writer) {
             Ok(bits_per_pixel) => Ok(bits_per_pixel),
             Err(png::EncodingError::IoError(error)) => Err(error),
             Err(png::EncodingError::Format(error)) => {
@@ -205,15 +268,110 @@ impl IconImage {
     }
 
     /// Encodes the image as a PNG file and returns the bits-per-pixel (or the
-    /// `png::EncodingError`).
+    /// `png::EncodingError`).  The most compact lossless color type is chosen
+    /// automatically: grayscale if every pixel is gray and opaque, indexed
+    /// (with a tRNS chunk for alpha) if there are at most 256 distinct RGBA
+    /// values, grayscale+alpha if every pixel is gray, and otherwise RGB or
+    /// RGBA.
     fn write_png_internal_enc<W: Write>(
         &self,
         stats: &ImageStats,
+        compression: PngCompression,
+        filter: PngFilter,
         writer: W,
     ) -> Result<u16, png::EncodingError> {
         let mut encoder = png::Encoder::new(writer, self.width, self.height);
-        // TODO: Detect if we can use grayscale.
+        let (filter, adaptive_filter) = filter.to_png();
+        encoder.set_compression(compression.to_png());
+        encoder.set_filter(filter);
+        encoder.set_adaptive_filter(adaptive_filter);
+        let width = self.width as usize;
+        let is_gray = match stats.colors {
+            Some(ref colors) => {
+                colors.iter().all(|&(r, g, b)| r == g && g == b)
+            }
+            None => false,
+        };
+        let palette = if stats.colors.is_some() {
+            self.compute_rgba_palette()
+        } else {
+            None
+        };
+        let palette_depth = palette
+            .as_ref()
+            .map(|palette| png_depth_for_colors(palette.len()));
+
+        // Grayscale, for opaque gray images (unless a palette would let us
+        // use fewer bits per pixel):
+        if is_gray && !stats.has_alpha {
+            let colors = stats.colors.as_ref().unwrap();
+            let depth = png_gray_depth(colors);
+            if palette_depth.is_none_or(|palette_depth| depth <= palette_depth)
+            {
+                let step = 255 / ((1u16 << depth) - 1) as u8;
+                let samples: Vec<u8> = self
+                    .rgba_data
+                    .chunks_exact(4)
+                    .map(|pixel| pixel[0] / step)
+                    .collect();
+                encoder.set_color(png::ColorType::Grayscale);
+                encoder.set_depth(png::BitDepth::from_u8(depth).unwrap());
+                let mut writer = encoder.write_header()?;
+                writer.write_image_data(&pack_png_samples(
+                    &samples, width, depth,
+                ))?;
+                return Ok(depth as u16);
+            }
+        }
+
+        // Indexed color, for images with at most 256 distinct RGBA values:
+        if let (Some(palette), Some(depth)) = (palette, palette_depth) {
+            let index_map: HashMap<&[u8], u8> = palette
+                .iter()
+                .enumerate()
+                .map(|(index, color)| (&color[..], index as u8))
+                .collect();
+            let samples: Vec<u8> = self
+                .rgba_data
+                .chunks_exact(4)
+                .map(|pixel| index_map[pixel])
+                .collect();
+            let rgb_palette: Vec<u8> =
+                palette.iter().flat_map(|color| color[..3].to_vec()).collect();
+            // Partially transparent entries come first in the palette, so
+            // the tRNS chunk can omit the (fully opaque) remainder.
+            let trns: Vec<u8> = palette
+                .iter()
+                .map(|color| color[3])
+                .take_while(|&alpha| alpha != u8::MAX)
+                .collect();
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::from_u8(depth).unwrap());
+            encoder.set_palette(rgb_palette);
+            if !trns.is_empty() {
+                encoder.set_trns(trns);
+            }
+            let mut writer = encoder.write_header()?;
+            writer
+                .write_image_data(&pack_png_samples(&samples, width, depth))?;
+            return Ok(depth as u16);
+        }
+
         encoder.set_depth(png::BitDepth::Eight);
+        if is_gray {
+            // At this point, the image must have alpha (otherwise it would
+            // have been encoded as plain grayscale above).
+            debug_assert!(stats.has_alpha);
+            encoder.set_color(png::ColorType::GrayscaleAlpha);
+            let mut writer = encoder.write_header()?;
+            let gray_alpha_data: Vec<u8> = self
+                .rgba_data
+                .chunks_exact(4)
+                .flat_map(|pixel| [pixel[0], pixel[3]])
+                .collect();
+            writer.write_image_data(&gray_alpha_data)?;
+            return Ok(16);
+        }
         if stats.has_alpha {
             encoder.set_color(png::ColorType::Rgba);
         } else {
@@ -239,59 +397,36 @@ impl IconImage {
         }
     }
 
+    /// Returns the distinct RGBA values in the image, with partially
+    /// transparent values first, or `None` if there are more than 256.
+    fn compute_rgba_palette(&self) -> Option<Vec<[u8; 4]>> {
+        let mut colors = BTreeSet::<[u8; 4]>::new();
+        for pixel in self.rgba_data.chunks_exact(4) {
+            colors.insert([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            if colors.len() > 256 {
+                return None;
+            }
+        }
+        let mut palette: Vec<[u8; 4]> = colors.into_iter().collect();
+        palette.sort_by_key(|color| color[3] == u8::MAX);
+        Some(palette)
+    }
+
     pub(crate) fn read_bmp_size<R: Read>(
         reader: &mut R,
     ) -> io::Result<(u32, u32)> {
-        let data_size = reader.read_u32::<LittleEndian>()?;
-        if data_size != BMP_HEADER_LEN {
-            invalid_data!(
-                "Invalid BMP header size (was {}, must be {})",
-                data_size,
-                BMP_HEADER_LEN
-            );
-        }
-        let width = reader.read_i32::<LittleEndian>()?;
-        if width < (MIN_WIDTH as i32) {
-            invalid_data!(
-                "Invalid BMP width (was {}, but must be at least {})",
-                width,
-                MIN_WIDTH
-            );
-        }
-        let width = width as u32;
-        let height = reader.read_i32::<LittleEndian>()?;
-        if height % 2 != 0 {
-            // The height is stored doubled, counting the rows of both the
-            // color data and the alpha mask, so it should be divisible by 2.
-            invalid_data!(
-                "Invalid height field in BMP header \
-                 (was {}, but must be divisible by 2)",
-                height
-            );
-        }
-        let height = height / 2;
-        if height < (MIN_HEIGHT as i32) {
-            invalid_data!(
-                "Invalid BMP height (was {}, but must be at least {})",
-                height,
-                MIN_HEIGHT
-            );
-        }
-        let height = height as u32;
-        Ok((width, height))
+        let header = BmpHeader::read(reader)?;
+        Ok((header.width, header.height))
     }
 
     pub(crate) fn read_bmp<R: Read>(mut reader: R) -> io::Result<IconImage> {
-        // Read the BITMAPINFOHEADER struct:
-        let (width, height) = IconImage::read_bmp_size(&mut reader)?;
-        let _planes = reader.read_u16::<LittleEndian>()?;
-        let bits_per_pixel = reader.read_u16::<LittleEndian>()?;
-        let _compression = reader.read_u32::<LittleEndian>()?;
-        let _image_size = reader.read_u32::<LittleEndian>()?;
-        let _horz_ppm = reader.read_i32::<LittleEndian>()?;
-        let _vert_ppm = reader.read_i32::<LittleEndian>()?;
-        let _colors_used = reader.read_u32::<LittleEndian>()?;
-        let _colors_important = reader.read_u32::<LittleEndian>()?;
+        // Read the header struct, which may be any of several versions:
+        let header = BmpHeader::read(&mut reader)?;
+        let width = header.width;
+        let height = header.height;
+        let bits_per_pixel = header.bits_per_pixel;
+        let compression = header.compression;
+        let image_size = header.image_size;
 
         // Determine the size of the color table:
         let depth = match BmpDepth::from_bits_per_pixel(bits_per_pixel) {
@@ -303,20 +438,107 @@ impl IconImage {
                 );
             }
         };
-        let num_colors = depth.num_colors();
+        let compression = match BmpCompression::from_number(compression) {
+            Some(compression) => compression,
+            None => {
+                invalid_data!("Unsupported BMP compression ({})", compression);
+            }
+        };
 
-        // Read in the color table:
+        // Read in the color masks, if any.  For BI_BITFIELDS, the red, green,
+        // and blue masks are either part of the header (for V2 headers and
+        // later) or else immediately follow it (and likewise for
+        // BI_ALPHABITFIELDS, with an additional alpha mask).
+        let masks = match compression {
+            BmpCompression::Rgb => BmpColorMasks::for_depth(depth),
+            BmpCompression::Rle8 | BmpCompression::Rle4 => {
+                let expected_depth = if compression == BmpCompression::Rle8 {
+                    BmpDepth::Eight
+                } else {
+                    BmpDepth::Four
+                };
+                if depth != expected_depth {
+                    invalid_data!(
+                        "Invalid BMP bits-per-pixel for {:?} compression \
+                         (was {}, but must be {})",
+                        compression,
+                        bits_per_pixel,
+                        expected_depth.bits_per_pixel()
+                    );
+                }
+                BmpColorMasks::for_depth(depth)
+            }
+            BmpCompression::Bitfields | BmpCompression::AlphaBitfields => {
+                if depth != BmpDepth::Sixteen && depth != BmpDepth::ThirtyTwo {
+                    invalid_data!(
+                        "Invalid BMP bits-per-pixel for {:?} compression \
+                         (was {}, but must be 16 or 32)",
+                        compression,
+                        bits_per_pixel
+                    );
+                }
+                let (red, green, blue, alpha) = match header.masks {
+                    Some(masks) => masks,
+                    None => {
+                        let red = reader.read_u32::<LittleEndian>()?;
+                        let green = reader.read_u32::<LittleEndian>()?;
+                        let blue = reader.read_u32::<LittleEndian>()?;
+                        let alpha =
+                            if compression == BmpCompression::AlphaBitfields {
+                                reader.read_u32::<LittleEndian>()?
+                            } else {
+                                0
+                            };
+                        (red, green, blue, alpha)
+                    }
+                };
+                BmpColorMasks::new(red, green, blue, alpha)?
+            }
+        };
+
+        // Determine the size of the color table.  If biClrUsed is nonzero,
+        // it gives the actual number of entries, which may be fewer than the
+        // bit depth allows.  (At 16 bpp or more, the color table is optional
+        // and only used for optimizing display on palette-based devices, but
+        // we still need to skip past it.)
+        let max_colors = depth.num_colors();
+        let num_colors = if header.colors_used == 0 {
+            max_colors
+        } else if max_colors > 0 && header.colors_used as usize > max_colors {
+            invalid_data!(
+                "Invalid BMP colors-used (was {}, but max is {} for {} bpp)",
+                header.colors_used,
+                max_colors,
+                bits_per_pixel
+            );
+        } else if header.colors_used > 256 {
+            invalid_data!(
+                "Invalid BMP colors-used (was {}, but max is 256)",
+                header.colors_used
+            );
+        } else {
+            header.colors_used as usize
+        };
+
+        // Read in the color table.  With an OS/2 core header, each entry is
+        // an RGBTRIPLE (3 bytes) rather than an RGBQUAD (4 bytes).
         let mut color_table = Vec::<(u8, u8, u8)>::with_capacity(num_colors);
         for _ in 0..num_colors {
             let blue = reader.read_u8()?;
             let green = reader.read_u8()?;
             let red = reader.read_u8()?;
-            let _reserved = reader.read_u8()?;
+            if header.header_type != BmpHeaderType::Core {
+                let _reserved = reader.read_u8()?;
+            }
             color_table.push((red, green, blue));
         }
 
         // Read in the color data, which is stored row by row, starting from
-        // the *bottom* row:
+        // the *bottom* row (unless this is a top-down image):
+        let row_start = |row: u32| -> usize {
+            let y = if header.top_down { row } else { height - row - 1 };
+            (4 * y * width) as usize
+        };
 
         let num_pixels = match width.checked_mul(height) {
             Some(num) => num as usize,
@@ -334,12 +556,57 @@ impl IconImage {
             );
         }
 
+        // Run-length-encoded data is decoded up front.  If the header gives
+        // the size of the compressed data, we read exactly that much, so that
+        // we end up in the right place to read the alpha mask afterwards;
+        // otherwise we stop at the end-of-bitmap code.
+        let rle_indices = match compression {
+            BmpCompression::Rle8 | BmpCompression::Rle4 => {
+                let indices = if image_size > 0 {
+                    let mut rle_data = Vec::new();
+                    (&mut reader)
+                        .take(image_size as u64)
+                        .read_to_end(&mut rle_data)?;
+                    if rle_data.len() != image_size as usize {
+                        invalid_data!(
+                            "BMP compressed data is truncated \
+                             (was {} bytes, but should be {})",
+                            rle_data.len(),
+                            image_size
+                        );
+                    }
+                    decode_rle(
+                        rle_data.as_slice(),
+                        compression,
+                        width,
+                        height,
+                    )?
+                } else {
+                    decode_rle(&mut reader, compression, width, height)?
+                };
+                Some(indices)
+            }
+            _ => None,
+        };
+
         let mut rgba = vec![u8::MAX; num_pixels * 4];
         let row_data_size = (width * (bits_per_pixel as u32)).div_ceil(8);
         let row_padding_size = row_data_size.div_ceil(4) * 4 - row_data_size;
         let mut row_padding = vec![0; row_padding_size as usize];
         for row in 0..height {
-            let mut start = (4 * (height - row - 1) * width) as usize;
+            let mut start = row_start(row);
+            if let Some(ref indices) = rle_indices {
+                let row_start = (row * width) as usize;
+                for &index in &indices[row_start..][..(width as usize)] {
+                    let (red, green, blue) =
+                        lookup_color(&color_table, index)?;
+                    rgba[start] = red;
+                    rgba[start + 1] = green;
+                    rgba[start + 2] = blue;
+                    start += 4;
+                }
+                continue;
+            }
             match depth {
                 BmpDepth::One => {
                     let mut col = 0;
@@ -348,7 +615,7 @@ impl IconImage {
                         for bit in 0..8 {
                             let index = (byte >> (7 - bit)) & 0x1;
                             let (red, green, blue) =
-                                color_table[index as usize];
+                                lookup_color(&color_table, index)?;
                             rgba[start] = red;
                             rgba[start + 1] = green;
                             rgba[start + 2] = blue;
@@ -367,7 +634,7 @@ impl IconImage {
                         for nibble in 0..2 {
                             let index = (byte >> (4 * (1 - nibble))) & 0xf;
                             let (red, green, blue) =
-                                color_table[index as usize];
+                                lookup_color(&color_table, index)?;
                             rgba[start] = red;
                             rgba[start + 1] = green;
                             rgba[start + 2] = blue;
@@ -382,7 +649,8 @@ impl IconImage {
                 BmpDepth::Eight => {
                     for _ in 0..width {
                         let index = reader.read_u8()?;
-                        let (red, green, blue) = color_table[index as usize];
+                        let (red, green, blue) =
+                            lookup_color(&color_table, index)?;
                         rgba[start] = red;
                         rgba[start + 1] = green;
                         rgba[start + 2] = blue;
@@ -392,12 +660,8 @@ impl IconImage {
                 BmpDepth::Sixteen => {
                     for _ in 0..width {
                         let color = reader.read_u16::<LittleEndian>()?;
-                        let red = (color >> 10) & 0x1f;
-                        let green = (color >> 5) & 0x1f;
-                        let blue = color & 0x1f;
-                        rgba[start] = ((red * 255 + 15) / 31) as u8;
-                        rgba[start + 1] = ((green * 255 + 15) / 31) as u8;
-                        rgba[start + 2] = ((blue * 255 + 15) / 31) as u8;
+                        let pixel = masks.decode(color as u32);
+                        rgba[start..(start + 4)].copy_from_slice(&pixel);
                         start += 4;
                     }
                 }
@@ -414,14 +678,9 @@ impl IconImage {
                 }
                 BmpDepth::ThirtyTwo => {
                     for _ in 0..width {
-                        let blue = reader.read_u8()?;
-                        let green = reader.read_u8()?;
-                        let red = reader.read_u8()?;
-                        let alpha = reader.read_u8()?;
-                        rgba[start] = red;
-                        rgba[start + 1] = green;
-                        rgba[start + 2] = blue;
-                        rgba[start + 3] = alpha;
+                        let color = reader.read_u32::<LittleEndian>()?;
+                        let pixel = masks.decode(color);
+                        rgba[start..(start + 4)].copy_from_slice(&pixel);
                         start += 4;
                     }
                 }
@@ -429,16 +688,37 @@ impl IconImage {
             reader.read_exact(&mut row_padding)?;
         }
 
+        // If there's an alpha channel (normally only at 32 bpp), it takes the
+        // place of the alpha mask.  However, some older icons leave the alpha
+        // channel as all zeros and rely on the mask for transparency instead;
+        // in that case, Windows treats the image as opaque except where the
+        // mask says otherwise, so we do the same.
+        let has_alpha_channel = match depth {
+            BmpDepth::Sixteen | BmpDepth::ThirtyTwo => masks.has_alpha(),
+            _ => false,
+        };
+        let use_mask = if has_alpha_channel {
+            let alpha_unused = rgba.chunks_exact(4).all(|pixel| pixel[3] == 0);
+            if alpha_unused {
+                for pixel in rgba.chunks_exact_mut(4) {
+                    pixel[3] = u8::MAX;
+                }
+            }
+            alpha_unused
+        } else {
+            true
+        };
+
         // Read in the alpha mask (1 bit per pixel), which again is stored row
-        // by row, starting from the *bottom* row, with each row padded to a
-        // multiple of four bytes:
-        if depth != BmpDepth::ThirtyTwo {
+        // by row, starting from the *bottom* row (unless this is a top-down
+        // image), with each row padded to a multiple of four bytes:
+        if use_mask {
             let row_mask_size = width.div_ceil(8);
             let row_padding_size =
                 row_mask_size.div_ceil(4) * 4 - row_mask_size;
             let mut row_padding = vec![0; row_padding_size as usize];
             for row in 0..height {
-                let mut start = (4 * (height - row - 1) * width) as usize;
+                let mut start = row_start(row);
                 let mut col = 0;
                 for _ in 0..row_mask_size {
                     let byte = reader.read_u8()?;
@@ -465,12 +745,37 @@ impl IconImage {
     pub(crate) fn write_bmp_internal(
         &self,
         stats: &ImageStats,
+        options: &BmpEncodingOptions,
     ) -> io::Result<(u8, u16, Vec<u8>)> {
-        // Determine the most appropriate color depth for encoding this image:
+        let depth = match options.depth() {
+            Some(depth) => depth,
+            None => {
+                let (depth, colors) = self.choose_bmp_depth(stats);
+                return self.write_bmp_with_depth(depth, &colors, options);
+            }
+        };
+        if let Some(colors) = self.lossless_bmp_colors(depth, stats, options) {
+            self.write_bmp_with_depth(depth, &colors, options)
+        } else if options.allow_quantization() {
+            let (image, colors) = self.quantize_for_bmp(depth, options);
+            image.write_bmp_with_depth(depth, &colors, options)
+        } else {
+            invalid_input!(
+                "Image can't be encoded losslessly as a {}-bpp BMP",
+                depth.bits_per_pixel()
+            );
+        }
+    }
+
+    /// Determines the most appropriate color depth for encoding this image as
+    /// a BMP, and returns that depth along with the color table to use.
+    fn choose_bmp_depth(
+        &self,
+        stats: &ImageStats,
+    ) -> (BmpDepth, Vec<(u8, u8, u8)>) {
         let width = self.width();
         let height = self.height();
-        let rgba = self.rgba_data();
-        let (depth, colors) = if stats.has_nonbinary_alpha {
+        if stats.has_nonbinary_alpha {
             // Only 32 bpp can support alpha values between 0 and 255, even if
             // the image has a small number of colors, because the BMP color
             // table can't contain alpha values.
@@ -482,9 +787,12 @@ impl IconImage {
                 (BmpDepth::Four, colors.iter().cloned().collect())
             } else {
                 debug_assert!(colors.len() <= 256);
-                if width * height < 512 {
-                    // At fewer than 512 pixels, it's more efficient to encode
-                    // at 24 bpp, so we can omit the 256-entry color table.
+                let num_pixels = (width as u64) * (height as u64);
+                if num_pixels < 2 * (colors.len() as u64) {
+                    // At 8 bpp, each pixel takes one byte and each color
+                    // table entry takes four, whereas at 24 bpp each pixel
+                    // takes three bytes but there's no color table.  So for
+                    // small images with many colors, 24 bpp is more compact.
                     (BmpDepth::TwentyFour, Vec::new())
                 } else {
                     (BmpDepth::Eight, colors.iter().cloned().collect())
@@ -492,9 +800,139 @@ impl IconImage {
             }
         } else {
             (BmpDepth::TwentyFour, Vec::new())
+        }
+    }
+
+    /// Returns the color table to use for encoding this image as a BMP with
+    /// the given depth, or `None` if the image can't be represented exactly
+    /// at that depth.
+    fn lossless_bmp_colors(
+        &self,
+        depth: BmpDepth,
+        stats: &ImageStats,
+        options: &BmpEncodingOptions,
+    ) -> Option<Vec<(u8, u8, u8)>> {
+        if depth == BmpDepth::ThirtyTwo {
+            return Some(Vec::new());
+        }
+        if stats.has_nonbinary_alpha {
+            return None;
+        }
+        if depth == BmpDepth::Sixteen {
+            // Every color must survive being rounded to 5 (or 6) bits per
+            // channel.
+            let masks = bmp16_masks(options);
+            let exact = self.rgba_data.chunks_exact(4).all(|pixel| {
+                masks.decode(masks.encode(pixel))[..3] == pixel[..3]
+            });
+            return if exact { Some(Vec::new()) } else { None };
+        }
+        let max_colors = depth.num_colors();
+        if max_colors == 0 {
+            return Some(Vec::new());
+        }
+        match stats.colors {
+            Some(ref colors) if colors.len() <= max_colors => {
+                Some(colors.iter().cloned().collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a copy of this image altered so that it can be represented
+    /// exactly as a BMP with the given depth, along with the color table to
+    /// use.
+    fn quantize_for_bmp(
+        &self,
+        depth: BmpDepth,
+        options: &BmpEncodingOptions,
+    ) -> (IconImage, Vec<(u8, u8, u8)>) {
+        let max_colors = depth.num_colors();
+        if max_colors > 0 {
+            let indexed = IndexedImage::quantize(
+                self,
+                max_colors,
+                options.quantizer(),
+                options.dithering(),
+            );
+            return (indexed.to_rgba_image(), indexed.palette().to_vec());
+        }
+        let mut rgba = self.rgba_data.clone();
+        if depth != BmpDepth::ThirtyTwo {
+            // Only 32 bpp can store partial alpha; everything else has just a
+            // 1-bit mask.
+            for pixel in rgba.chunks_exact_mut(4) {
+                pixel[3] = if pixel[3] >= 0x80 { u8::MAX } else { 0 };
+            }
+        }
+        if depth == BmpDepth::Sixteen {
+            let masks = bmp16_masks(options);
+            for pixel in rgba.chunks_exact_mut(4) {
+                let rounded = masks.decode(masks.encode(pixel));
+                pixel[..3].copy_from_slice(&rounded[..3]);
+            }
+        }
+        let image = IconImage {
+            width: self.width,
+            height: self.height,
+            hotspot: self.hotspot,
+            rgba_data: rgba,
         };
+        (image, Vec::new())
+    }
+
+    /// Encodes the image as a BMP with the given depth and color table, which
+    /// must be able to represent the image exactly.
+    fn write_bmp_with_depth(
+        &self,
+        depth: BmpDepth,
+        colors: &[(u8, u8, u8)],
+        options: &BmpEncodingOptions,
+    ) -> io::Result<(u8, u16, Vec<u8>)> {
+        let mut indices = Vec::new();
+        if depth.num_colors() > 0 {
+            let mut color_map = HashMap::<(u8, u8, u8), u8>::new();
+            for (index, &color) in colors.iter().enumerate() {
+                color_map.insert(color, index as u8);
+            }
+            indices.extend(self.rgba_data.chunks_exact(4).map(|pixel| {
+                *color_map.get(&(pixel[0], pixel[1], pixel[2])).unwrap()
+            }));
+        }
+        self.write_bmp_with_indices(depth, colors, &indices, options)
+    }
+
+    /// Encodes the image as a BMP with the given depth and color table.  For
+    /// depths that use a color table, `indices` holds each pixel's index into
+    /// it, in row-major order from top to bottom, and the image's own colors
+    /// are ignored (though its alpha channel still determines the mask).
+    pub(crate) fn write_bmp_with_indices(
+        &self,
+        depth: BmpDepth,
+        colors: &[(u8, u8, u8)],
+        indices: &[u8],
+        options: &BmpEncodingOptions,
+    ) -> io::Result<(u8, u16, Vec<u8>)> {
+        let width = self.width();
+        let height = self.height();
+        let rgba = self.rgba_data();
         let bits_per_pixel = depth.bits_per_pixel();
         let num_colors = depth.num_colors();
+        // Only write as many color table entries as we actually need.
+        debug_assert!(colors.len() <= num_colors);
+        let colors_used =
+            if colors.len() < num_colors { colors.len() as u32 } else { 0 };
+        // 16-bpp pixels are 5-5-5 by default; anything else (i.e. 5-6-5)
+        // requires BI_BITFIELDS compression, with the masks stored right after
+        // the header.
+        let masks = bmp16_masks(options);
+        let compression = if depth == BmpDepth::Sixteen && options.rgb565() {
+            BmpCompression::Bitfields
+        } else {
+            BmpCompression::Rgb
+        };
+        let masks_size =
+            if compression == BmpCompression::Bitfields { 12 } else { 0 };
 
         // Determine the size of the encoded data:
         let rgb_row_data_size =
@@ -505,7 +943,8 @@ impl IconImage {
         let mask_row_size = mask_row_data_size.div_ceil(4) * 4;
         let mask_row_padding = vec![0u8; mask_row_size - mask_row_data_size];
         let data_size = BMP_HEADER_LEN as usize
-            + 4 * num_colors
+            + masks_size
+            + 4 * colors.len()
             + height as usize * (rgb_row_size + mask_row_size);
         let mut data = Vec::<u8>::with_capacity(data_size);
 
@@ -515,27 +954,29 @@ impl IconImage {
         data.write_i32::<LittleEndian>(2 * height as i32)?;
         data.write_u16::<LittleEndian>(1)?; // planes
         data.write_u16::<LittleEndian>(bits_per_pixel)?;
-        data.write_u32::<LittleEndian>(0)?; // compression
+        data.write_u32::<LittleEndian>(compression.number())?;
         data.write_u32::<LittleEndian>(0)?; // image size
         data.write_i32::<LittleEndian>(0)?; // horz ppm
         data.write_i32::<LittleEndian>(0)?; // vert ppm
-        data.write_u32::<LittleEndian>(0)?; // colors used
+        data.write_u32::<LittleEndian>(colors_used)?;
         data.write_u32::<LittleEndian>(0)?; // colors important
         debug_assert_eq!(data.len(), BMP_HEADER_LEN as usize);
 
+        // Write the color masks, if any:
+        if compression == BmpCompression::Bitfields {
+            let (red, green, blue) = masks.rgb_mask_values();
+            data.write_u32::<LittleEndian>(red)?;
+            data.write_u32::<LittleEndian>(green)?;
+            data.write_u32::<LittleEndian>(blue)?;
+        }
+
         // Write the color table:
-        let mut color_map = HashMap::<(u8, u8, u8), u8>::new();
-        for (index, &(red, green, blue)) in colors.iter().enumerate() {
-            color_map.insert((red, green, blue), index as u8);
+        for &(red, green, blue) in colors.iter() {
             data.write_u8(blue)?;
             data.write_u8(green)?;
             data.write_u8(red)?;
             data.write_u8(0)?;
         }
-        debug_assert!(color_map.len() <= num_colors);
-        for _ in 0..(num_colors - color_map.len()) {
-            data.write_u32::<LittleEndian>(0)?;
-        }
 
         // Write the color data:
         for row in 0..height {
@@ -546,11 +987,7 @@ impl IconImage {
                     for _ in 0..rgb_row_data_size {
                         let mut byte = 0;
                         for bit in 0..8 {
-                            let red = rgba[start];
-                            let green = rgba[start + 1];
-                            let blue = rgba[start + 2];
-                            let color = (red, green, blue);
-                            let index = *color_map.get(&color).unwrap();
+                            let index = indices[start / 4];
                             debug_assert!(index <= 0x1);
                             byte |= index << (7 - bit);
                             col += 1;
@@ -567,11 +1004,7 @@ impl IconImage {
                     for _ in 0..rgb_row_data_size {
                         let mut byte = 0;
                         for nibble in 0..2 {
-                            let red = rgba[start];
-                            let green = rgba[start + 1];
-                            let blue = rgba[start + 2];
-                            let color = (red, green, blue);
-                            let index = *color_map.get(&color).unwrap();
+                            let index = indices[start / 4];
                             debug_assert!(index <= 0xf);
                             byte |= index << (4 * (1 - nibble));
                             col += 1;
@@ -586,18 +1019,17 @@ impl IconImage {
                 BmpDepth::Eight => {
                     debug_assert_eq!(width as usize, rgb_row_data_size);
                     for _ in 0..width {
-                        let red = rgba[start];
-                        let green = rgba[start + 1];
-                        let blue = rgba[start + 2];
-                        let color = (red, green, blue);
-                        data.write_u8(*color_map.get(&color).unwrap())?;
+                        data.write_u8(indices[start / 4])?;
                         start += 4;
                     }
                 }
                 BmpDepth::Sixteen => {
-                    // We never choose BmpDepth::Sixteen above, so this should
-                    // be unreachable.
-                    invalid_input!("Encoding 16-bpp BMPs is not implemented");
+                    debug_assert_eq!(2 * width as usize, rgb_row_data_size);
+                    for _ in 0..width {
+                        let color = masks.encode(&rgba[start..(start + 4)]);
+                        data.write_u16::<LittleEndian>(color as u16)?;
+                        start += 4;
+                    }
                 }
                 BmpDepth::TwentyFour => {
                     debug_assert_eq!(3 * width as usize, rgb_row_data_size);
@@ -654,6 +1086,78 @@ impl IconImage {
         Ok((num_colors as u8, bits_per_pixel, data))
     }
 
+    /// Reduces the image to a palette of at most `max_colors` colors (e.g. so
+    /// that it can be encoded as a 1-, 4-, or 8-bpp BMP), using the given
+    /// quantizer to choose the palette.  Pixels with alpha values below 128
+    /// become fully transparent, and all others become fully opaque.  Panics
+    /// if `max_colors` is zero or greater than 256.
+    pub fn quantize(
+        &self,
+        max_colors: usize,
+        quantizer: Quantizer,
+    ) -> IndexedImage {
+        self.quantize_with_dithering(max_colors, quantizer, Dithering::None)
+    }
+
+    /// Like `quantize`, but uses the given dithering method when mapping
+    /// pixels to the palette.
+    pub fn quantize_with_dithering(
+        &self,
+        max_colors: usize,
+        quantizer: Quantizer,
+        dithering: Dithering,
+    ) -> IndexedImage {
+        IndexedImage::quantize(self, max_colors, quantizer, dithering)
+    }
+
+    /// Returns a copy of this image resized to the given dimensions, using
+    /// the given resampling filter.  Resampling is done in premultiplied
+    /// alpha, so that the colors of transparent pixels don't create dark
+    /// fringes.  The cursor hotspot, if any, is scaled along with the image.
+    /// Panics if either dimension is zero, or if the scaled hotspot wouldn't
+    /// fit in a cursor's 16-bit coordinates.
+    pub fn resize(
+        &self,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> IconImage {
+        if width < MIN_WIDTH {
+            panic!(
+                "Invalid width (was {}, but must be at least {})",
+                width, MIN_WIDTH
+            );
+        }
+        if height < MIN_HEIGHT {
+            panic!(
+                "Invalid height (was {}, but must be at least {})",
+                height, MIN_HEIGHT
+            );
+        }
+        resize::resize(self, width, height, filter)
+    }
+
+    /// Returns a copy of this image enlarged by an integer factor, with each
+    /// pixel becoming a `factor`x`factor` block, as is usual for pixel art.
+    /// The cursor hotspot, if any, is scaled along with the image.  Panics if
+    /// `factor` is zero, if the scaled width or height would overflow a
+    /// `u32`, or if the scaled hotspot wouldn't fit in a cursor's 16-bit
+    /// coordinates.
+    pub fn scale_up(&self, factor: u32) -> IconImage {
+        if factor == 0 {
+            panic!("Invalid scale factor (must be at least 1)");
+        }
+        if self.width.checked_mul(factor).is_none()
+            || self.height.checked_mul(factor).is_none()
+        {
+            panic!(
+                "Invalid scale factor (scaling {}x{} image by {} overflows)",
+                self.width, self.height, factor
+            );
+        }
+        resize::scale_up(self, factor)
+    }
+
     /// Returns the width of the image, in pixels.
     pub fn width(&self) -> u32 {
         self.width
@@ -688,6 +1192,121 @@ impl IconImage {
         &mut self.rgba_data
     }
 
+    /// Returns the RGBA value of the pixel at the given coordinates.  Panics
+    /// if the coordinates are out of bounds.
+    pub fn get_pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let start = self.pixel_start(x, y);
+        let mut pixel = [0u8; 4];
+        pixel.copy_from_slice(&self.rgba_data[start..(start + 4)]);
+        pixel
+    }
+
+    /// Sets the RGBA value of the pixel at the given coordinates.  Panics if
+    /// the coordinates are out of bounds.
+    pub fn put_pixel(&mut self, x: u32, y: u32, rgba: [u8; 4]) {
+        let start = self.pixel_start(x, y);
+        self.rgba_data[start..(start + 4)].copy_from_slice(&rgba);
+    }
+
+    fn pixel_start(&self, x: u32, y: u32) -> usize {
+        if x >= self.width || y >= self.height {
+            panic!(
+                "Pixel coordinates ({}, {}) are out of bounds for {}x{} image",
+                x, y, self.width, self.height
+            );
+        }
+        4 * ((y as usize) * (self.width as usize) + (x as usize))
+    }
+
+    /// Returns the RGBA data for one row of the image (with 4 bytes per
+    /// pixel).  Panics if `y` is out of bounds.
+    pub fn row(&self, y: u32) -> &[u8] {
+        let start = self.pixel_start(0, y);
+        &self.rgba_data[start..(start + 4 * self.width as usize)]
+    }
+
+    /// Returns the mutable RGBA data for one row of the image (with 4 bytes
+    /// per pixel).  Panics if `y` is out of bounds.
+    pub fn row_mut(&mut self, y: u32) -> &mut [u8] {
+        let start = self.pixel_start(0, y);
+        let end = start + 4 * self.width as usize;
+        &mut self.rgba_data[start..end]
+    }
+
+    /// Returns an iterator over the RGBA data for each row of the image, from
+    /// top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.rgba_data.chunks_exact(4 * self.width as usize)
+    }
+
+    /// Returns an iterator over the mutable RGBA data for each row of the
+    /// image, from top to bottom.
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        self.rgba_data.chunks_exact_mut(4 * self.width as usize)
+    }
+
+    /// Returns an iterator over the pixels of the image, in row-major order
+    /// from top to bottom, yielding the `(x, y)` coordinates and RGBA value
+    /// of each pixel.
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32, [u8; 4])> + '_ {
+        let width = self.width;
+        self.rgba_data.chunks_exact(4).enumerate().map(
+            move |(index, pixel)| {
+                let index = index as u32;
+                (
+                    index % width,
+                    index / width,
+                    [pixel[0], pixel[1], pixel[2], pixel[3]],
+                )
+            },
+        )
+    }
+
+    /// Returns an iterator over the pixels of the image, in row-major order
+    /// from top to bottom, yielding the `(x, y)` coordinates and a mutable
+    /// 4-byte RGBA slice for each pixel.
+    pub fn pixels_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (u32, u32, &mut [u8])> + '_ {
+        let width = self.width;
+        self.rgba_data.chunks_exact_mut(4).enumerate().map(
+            move |(index, pixel)| {
+                let index = index as u32;
+                (index % width, index / width, pixel)
+            },
+        )
+    }
+
+    /// Sets every pixel of the image to the given RGBA value.
+    pub fn fill(&mut self, rgba: [u8; 4]) {
+        for pixel in self.rgba_data.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&rgba);
+        }
+    }
+
+    /// Draws `source` onto this image with its top-left corner at `(x, y)`,
+    /// using "source-over" alpha compositing (so that transparent parts of
+    /// `source` let this image show through).  Any parts of `source` that
+    /// fall outside of this image are clipped.  This image's cursor hotspot
+    /// is unaffected.
+    pub fn blit(&mut self, source: &IconImage, x: i32, y: i32) {
+        let left = x.max(0) as i64;
+        let top = y.max(0) as i64;
+        let right = (x as i64 + source.width as i64).min(self.width as i64);
+        let bottom = (y as i64 + source.height as i64).min(self.height as i64);
+        for dst_y in top..bottom {
+            for dst_x in left..right {
+                let (src_x, src_y) = (dst_x - x as i64, dst_y - y as i64);
+                let src = source.get_pixel(src_x as u32, src_y as u32);
+                let start = self.pixel_start(dst_x as u32, dst_y as u32);
+                let dst = &mut self.rgba_data[start..(start + 4)];
+                let blended =
+                    composite_over(src, [dst[0], dst[1], dst[2], dst[3]]);
+                dst.copy_from_slice(&blended);
+            }
+        }
+    }
+
     /// Consumes this `IconImage` and returns the raw RGBA data in row-major
     /// order as an owned `Vec<u8>`
     pub fn into_rgba_data(self) -> Vec<u8> {
@@ -725,6 +1344,100 @@ impl IconImage {
 
 //===========================================================================//
 
+/// Returns the smallest PNG bit depth that can index the given number of
+/// palette colors.
+fn png_depth_for_colors(num_colors: usize) -> u8 {
+    if num_colors <= 2 {
+        1
+    } else if num_colors <= 4 {
+        2
+    } else if num_colors <= 16 {
+        4
+    } else {
+        8
+    }
+}
+
+/// Returns the smallest PNG bit depth that can exactly represent all of the
+/// given (gray) colors as grayscale samples.
+fn png_gray_depth(colors: &BTreeSet<(u8, u8, u8)>) -> u8 {
+    for depth in [1, 2, 4] {
+        let step = 255 / ((1u8 << depth) - 1);
+        if colors.iter().all(|&(gray, _, _)| gray % step == 0) {
+            return depth;
+        }
+    }
+    8
+}
+
+/// Packs one sample per pixel into PNG rows at the given bit depth, with
+/// each row starting on a byte boundary.
+fn pack_png_samples(samples: &[u8], width: usize, depth: u8) -> Vec<u8> {
+    if depth == 8 {
+        return samples.to_vec();
+    }
+    let samples_per_byte = 8 / depth as usize;
+    let mut packed = Vec::new();
+    for row in samples.chunks(width) {
+        for chunk in row.chunks(samples_per_byte) {
+            let mut byte = 0u8;
+            for (index, &sample) in chunk.iter().enumerate() {
+                byte |= sample << (8 - depth as usize * (index + 1));
+            }
+            packed.push(byte);
+        }
+    }
+    packed
+}
+
+/// Composites one (non-premultiplied) RGBA pixel over another, using the
+/// "source-over" operator.
+fn composite_over(src: [u8; 4], dst: [u8; 4]) -> [u8; 4] {
+    let src_alpha = src[3] as u32;
+    // All of these are scaled by 255 * 255:
+    let dst_weight = dst[3] as u32 * (255 - src_alpha);
+    let src_weight = src_alpha * 255;
+    let total = src_weight + dst_weight;
+    if total == 0 {
+        return [0, 0, 0, 0];
+    }
+    let channel = |index: usize| {
+        let sum =
+            src[index] as u32 * src_weight + dst[index] as u32 * dst_weight;
+        ((sum + total / 2) / total) as u8
+    };
+    [channel(0), channel(1), channel(2), ((total + 127) / 255) as u8]
+}
+
+/// Returns the color masks to use for encoding a 16-bpp BMP.
+fn bmp16_masks(options: &BmpEncodingOptions) -> BmpColorMasks {
+    if options.rgb565() {
+        BmpColorMasks::rgb565()
+    } else {
+        BmpColorMasks::for_depth(BmpDepth::Sixteen)
+    }
+}
+
+/// Looks up an entry in a BMP color table, returning an error if the index is
+/// out of range (which can happen if the table is shorter than the bit depth
+/// allows).
+fn lookup_color(
+    color_table: &[(u8, u8, u8)],
+    index: u8,
+) -> io::Result<(u8, u8, u8)> {
+    match color_table.get(index as usize) {
+        Some(&color) => Ok(color),
+        None => invalid_data!(
+            "Invalid BMP color index (was {}, but color table has only {} \
+             entries)",
+            index,
+            color_table.len()
+        ),
+    }
+}
+
+//===========================================================================//
+
 pub(crate) struct ImageStats {
     /// True if the image uses transparency.
     pub(crate) has_alpha: bool,
@@ -736,3 +1449,89 @@ pub(crate) struct ImageStats {
 }
 
 //===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::IconImage;
+
+    #[test]
+    fn get_and_put_pixels() {
+        let mut image = IconImage::new(3, 2);
+        image.put_pixel(2, 1, [1, 2, 3, 4]);
+        assert_eq!(image.get_pixel(2, 1), [1, 2, 3, 4]);
+        assert_eq!(image.get_pixel(1, 1), [0, 0, 0, 0]);
+        assert_eq!(&image.rgba_data()[20..24], &[1, 2, 3, 4]);
+        assert_eq!(image.row(1), &[0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4]);
+        image.row_mut(0)[0] = 9;
+        assert_eq!(image.rows().count(), 2);
+        assert_eq!(image.rows().next().unwrap()[0], 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows")]
+    fn scale_up_with_overflowing_factor() {
+        IconImage::new(1, 0x10000).scale_up(0x10000);
+    }
+
+    #[test]
+    #[should_panic(expected = "hotspot")]
+    fn scale_up_with_overflowing_hotspot() {
+        let mut image = IconImage::new(0x8001, 1);
+        image.set_cursor_hotspot(Some((0x8000, 0)));
+        image.scale_up(2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_pixel_out_of_bounds() {
+        IconImage::new(3, 2).get_pixel(3, 0);
+    }
+
+    #[test]
+    fn iterate_over_pixels() {
+        let mut image = IconImage::new(2, 2);
+        for (x, y, pixel) in image.pixels_mut() {
+            pixel.copy_from_slice(&[x as u8, y as u8, 0, 255]);
+        }
+        let pixels: Vec<(u32, u32, [u8; 4])> = image.pixels().collect();
+        assert_eq!(
+            pixels,
+            vec![
+                (0, 0, [0, 0, 0, 255]),
+                (1, 0, [1, 0, 0, 255]),
+                (0, 1, [0, 1, 0, 255]),
+                (1, 1, [1, 1, 0, 255]),
+            ]
+        );
+        image.fill([5, 6, 7, 8]);
+        assert_eq!(image.rgba_data(), [5, 6, 7, 8].repeat(4).as_slice());
+    }
+
+    #[test]
+    fn blit_with_source_over() {
+        let mut image = IconImage::new(3, 3);
+        image.fill([0, 0, 255, 255]);
+        image.put_pixel(0, 0, [0, 0, 0, 0]);
+        let mut source = IconImage::new(2, 2);
+        source.fill([255, 0, 0, 128]);
+        source.put_pixel(1, 1, [0, 255, 0, 255]);
+        source.put_pixel(0, 1, [0, 255, 0, 0]);
+        image.blit(&source, -1, -1);
+        // Only the source's bottom-right (opaque green) pixel lands inside
+        // the image.
+        assert_eq!(image.get_pixel(0, 0), [0, 255, 0, 255]);
+        assert_eq!(image.get_pixel(1, 0), [0, 0, 255, 255]);
+        image.blit(&source, 1, 1);
+        // Half-transparent red over opaque blue blends to purple.
+        assert_eq!(image.get_pixel(1, 1), [128, 0, 127, 255]);
+        assert_eq!(image.get_pixel(2, 2), [0, 255, 0, 255]);
+        assert_eq!(image.get_pixel(1, 2), [0, 0, 255, 255]);
+        image.blit(&source, 5, 5);
+        // Half-transparent red over transparent stays half-transparent red.
+        let mut clear = IconImage::new(1, 1);
+        clear.blit(&source, 0, 0);
+        assert_eq!(clear.get_pixel(0, 0), [255, 0, 0, 128]);
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,114 @@
+use crate::image::IconImage;
+use crate::quantize::{self, Dithering, Quantizer};
+
+//===========================================================================//
+
+/// An image whose pixels are indices into a color palette of at most 256
+/// colors, with each pixel either fully opaque or fully transparent.  This is
+/// the form in which images are stored in 1-, 4-, and 8-bpp BMPs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexedImage {
+    width: u32,
+    height: u32,
+    hotspot: Option<(u16, u16)>,
+    palette: Vec<(u8, u8, u8)>,
+    indices: Vec<u8>,
+    transparent: Vec<bool>,
+}
+
+impl IndexedImage {
+    /// Reduces an image to a palette of at most `max_colors` colors, using
+    /// the given quantizer to choose the palette and the given dithering
+    /// method to map pixels onto it.  Pixels with alpha values below 128
+    /// become fully transparent, and all others become fully opaque.  Panics
+    /// if `max_colors` is zero or greater than 256.
+    pub(crate) fn quantize(
+        image: &IconImage,
+        max_colors: usize,
+        quantizer: Quantizer,
+        dithering: Dithering,
+    ) -> IndexedImage {
+        if max_colors == 0 || max_colors > 256 {
+            panic!(
+                "Invalid max_colors (was {}, but must be from 1 to 256)",
+                max_colors
+            );
+        }
+        let mut rgba = image.rgba_data().to_vec();
+        let mut transparent = Vec::with_capacity(rgba.len() / 4);
+        for pixel in rgba.chunks_exact_mut(4) {
+            let is_transparent = pixel[3] < 0x80;
+            pixel[3] = if is_transparent { 0 } else { u8::MAX };
+            transparent.push(is_transparent);
+        }
+        let palette = quantizer.palette(&rgba, max_colors);
+        let indices = quantize::dither_to_palette(
+            &rgba,
+            image.width() as usize,
+            &palette,
+            dithering,
+        );
+        IndexedImage {
+            width: image.width(),
+            height: image.height(),
+            hotspot: image.cursor_hotspot(),
+            palette,
+            indices,
+            transparent,
+        }
+    }
+
+    /// Returns the width of the image, in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the image, in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the coordinates of the cursor hotspot (pixels right from the
+    /// left edge of the image, and pixels down from the top edge), or `None`
+    /// if this image is an icon rather than a cursor.
+    pub fn cursor_hotspot(&self) -> Option<(u16, u16)> {
+        self.hotspot
+    }
+
+    /// Returns the color palette.  This is never empty, and has at most 256
+    /// entries.
+    pub fn palette(&self) -> &[(u8, u8, u8)] {
+        &self.palette
+    }
+
+    /// Returns the palette index of each pixel, in row-major order from top
+    /// to bottom.
+    pub fn indices(&self) -> &[u8] {
+        &self.indices
+    }
+
+    /// Returns whether each pixel is fully transparent, in row-major order
+    /// from top to bottom.
+    pub fn transparency(&self) -> &[bool] {
+        &self.transparent
+    }
+
+    /// Converts this image back into RGBA form.  Transparent pixels keep the
+    /// color of their palette entry, but with an alpha of zero.
+    pub fn to_rgba_image(&self) -> IconImage {
+        let mut rgba = Vec::with_capacity(4 * self.indices.len());
+        for (&index, &transparent) in
+            self.indices.iter().zip(self.transparent.iter())
+        {
+            let (red, green, blue) = self.palette[index as usize];
+            let alpha = if transparent { 0 } else { u8::MAX };
+            rgba.extend_from_slice(&[red, green, blue, alpha]);
+        }
+        let mut image =
+            IconImage::from_rgba_data(self.width, self.height, rgba);
+        image.set_cursor_hotspot(self.hotspot);
+        image
+    }
+}
+
+//===========================================================================//
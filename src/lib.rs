@@ -59,13 +59,42 @@
 #[macro_use]
 mod macros;
 
+mod ani;
+mod bmpcompression;
 mod bmpdepth;
+mod bmpheader;
+mod compat;
+mod icns;
 mod icondir;
 mod image;
+mod indexed;
+mod options;
+mod pe;
+mod quantize;
+mod res;
+mod resize;
+mod resource;
 mod restype;
+mod transform;
 
+pub use crate::ani::{AniCursor, AniStep};
+pub use crate::bmpdepth::BmpDepth;
+pub use crate::bmpheader::BmpHeaderType;
+pub use crate::compat::{CompatibilityPreset, CompatibilityViolation};
+pub use crate::icns::{IcnsFamily, IcnsType};
 pub use crate::icondir::{IconDir, IconDirEntry};
 pub use crate::image::IconImage;
+pub use crate::indexed::IndexedImage;
+pub use crate::options::{
+    BmpEncodingOptions, EncodingOptions, FormatPolicy, PngCompression,
+    PngEncodingOptions, PngFilter,
+};
+pub use crate::pe::PeFile;
+pub use crate::quantize::{Dithering, Quantizer, WINDOWS_VGA_PALETTE};
+pub use crate::res::ResFile;
+pub use crate::resize::ResizeFilter;
+pub use crate::resource::{IconGroup, ResourceId, ResourceOptions};
 pub use crate::restype::ResourceType;
+pub use crate::transform::Anchor;
 
 //===========================================================================//
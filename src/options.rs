@@ -0,0 +1,296 @@
+use crate::bmpdepth::BmpDepth;
+use crate::quantize::{Dithering, Quantizer};
+
+//===========================================================================//
+
+/// Options for encoding an image as a BMP within an ICO/CUR file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BmpEncodingOptions {
+    depth: Option<BmpDepth>,
+    allow_quantization: bool,
+    quantizer: Quantizer,
+    dithering: Dithering,
+    rgb565: bool,
+}
+
+impl BmpEncodingOptions {
+    /// Creates a new set of options, which chooses the color depth
+    /// automatically based on the image.
+    pub fn new() -> BmpEncodingOptions {
+        BmpEncodingOptions::default()
+    }
+
+    /// Returns the color depth to encode at, or `None` if the depth should be
+    /// chosen automatically.
+    pub fn depth(&self) -> Option<BmpDepth> {
+        self.depth
+    }
+
+    /// Sets the color depth to encode at, or `None` to choose the depth
+    /// automatically based on the image.
+    pub fn set_depth(&mut self, depth: Option<BmpDepth>) {
+        self.depth = depth;
+    }
+
+    /// Returns true if 16-bpp images should be encoded with 5 bits of red, 6
+    /// bits of green, and 5 bits of blue (using BI_BITFIELDS compression),
+    /// rather than the default 5 bits each.
+    pub fn rgb565(&self) -> bool {
+        self.rgb565
+    }
+
+    /// Sets whether 16-bpp images should be encoded with 5-6-5 bits of red,
+    /// green, and blue, rather than the default 5-5-5.  Has no effect at
+    /// other color depths.
+    pub fn set_rgb565(&mut self, rgb565: bool) {
+        self.rgb565 = rgb565;
+    }
+
+    /// Returns true if the image may be altered to fit the chosen color depth.
+    pub fn allow_quantization(&self) -> bool {
+        self.allow_quantization
+    }
+
+    /// Sets whether the image may be altered to fit the chosen color depth.
+    /// If false (the default), encoding will fail if the image can't be
+    /// represented exactly at that depth (for example, if it has too many
+    /// colors for the color table, or has partially-transparent pixels and
+    /// the depth is less than 32 bpp).  If true, colors will instead be
+    /// reduced as needed, and alpha values will be rounded to fully opaque or
+    /// fully transparent.
+    pub fn set_allow_quantization(&mut self, allow: bool) {
+        self.allow_quantization = allow;
+    }
+
+    /// Returns the method used to choose a color table when quantization is
+    /// needed.
+    pub fn quantizer(&self) -> Quantizer {
+        self.quantizer
+    }
+
+    /// Sets the method used to choose a color table when quantization is
+    /// needed.  The default is `Quantizer::MedianCut`.
+    pub fn set_quantizer(&mut self, quantizer: Quantizer) {
+        self.quantizer = quantizer;
+    }
+
+    /// Returns the dithering method used when the image is quantized to a
+    /// color table.
+    pub fn dithering(&self) -> Dithering {
+        self.dithering
+    }
+
+    /// Sets the dithering method used when the image is quantized to a color
+    /// table (at 1, 4, or 8 bpp).  The default is `Dithering::None`.
+    pub fn set_dithering(&mut self, dithering: Dithering) {
+        self.dithering = dithering;
+    }
+}
+
+//===========================================================================//
+
+/// The compression level to use when encoding a PNG.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum PngCompression {
+    /// Fast compression, at the cost of larger output
+    Fast,
+    /// A balance between speed and output size
+    #[default]
+    Default,
+    /// The best compression, at the cost of slower encoding
+    Best,
+}
+
+impl PngCompression {
+    pub(crate) fn to_png(self) -> png::Compression {
+        match self {
+            PngCompression::Fast => png::Compression::Fast,
+            PngCompression::Default => png::Compression::Default,
+            PngCompression::Best => png::Compression::Best,
+        }
+    }
+}
+
+//===========================================================================//
+
+/// The row filtering strategy to use when encoding a PNG.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum PngFilter {
+    /// Don't filter rows at all
+    NoFilter,
+    /// Use the "Sub" filter for every row
+    #[default]
+    Sub,
+    /// Use the "Up" filter for every row
+    Up,
+    /// Use the "Average" filter for every row
+    Average,
+    /// Use the "Paeth" filter for every row
+    Paeth,
+    /// Choose a filter for each row individually, using a heuristic
+    Adaptive,
+    /// Encode the image once with each of the above strategies, and keep
+    /// whichever result is smallest (this is several times slower)
+    Smallest,
+}
+
+impl PngFilter {
+    /// The strategies that `PngFilter::Smallest` tries.
+    pub(crate) const ALL: [PngFilter; 6] = [
+        PngFilter::NoFilter,
+        PngFilter::Sub,
+        PngFilter::Up,
+        PngFilter::Average,
+        PngFilter::Paeth,
+        PngFilter::Adaptive,
+    ];
+
+    /// Returns the `png` crate settings for this strategy.  Panics if called
+    /// on `PngFilter::Smallest`.
+    pub(crate) fn to_png(self) -> (png::FilterType, png::AdaptiveFilterType) {
+        let filter = match self {
+            PngFilter::NoFilter => png::FilterType::NoFilter,
+            PngFilter::Sub | PngFilter::Adaptive => png::FilterType::Sub,
+            PngFilter::Up => png::FilterType::Up,
+            PngFilter::Average => png::FilterType::Avg,
+            PngFilter::Paeth => png::FilterType::Paeth,
+            PngFilter::Smallest => panic!("PngFilter::Smallest has no filter"),
+        };
+        let adaptive = if self == PngFilter::Adaptive {
+            png::AdaptiveFilterType::Adaptive
+        } else {
+            png::AdaptiveFilterType::NonAdaptive
+        };
+        (filter, adaptive)
+    }
+}
+
+//===========================================================================//
+
+/// Options for encoding an image as a PNG, either on its own or within an
+/// ICO/CUR file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PngEncodingOptions {
+    compression: PngCompression,
+    filter: PngFilter,
+}
+
+impl PngEncodingOptions {
+    /// Creates a new set of options, with default compression and filtering.
+    pub fn new() -> PngEncodingOptions {
+        PngEncodingOptions::default()
+    }
+
+    /// Returns the compression level to use.
+    pub fn compression(&self) -> PngCompression {
+        self.compression
+    }
+
+    /// Sets the compression level to use.
+    pub fn set_compression(&mut self, compression: PngCompression) {
+        self.compression = compression;
+    }
+
+    /// Returns the row filtering strategy to use.
+    pub fn filter(&self) -> PngFilter {
+        self.filter
+    }
+
+    /// Sets the row filtering strategy to use.
+    pub fn set_filter(&mut self, filter: PngFilter) {
+        self.filter = filter;
+    }
+}
+
+//===========================================================================//
+
+/// The policy that `IconDirEntry::encode_with_options` uses to decide whether
+/// to encode an image as a BMP or as a PNG.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum FormatPolicy {
+    /// Use PNG only for images with partially-transparent pixels or that are
+    /// larger than 64x64, and BMP otherwise.  This is fast, and usually (but
+    /// not always) picks the smaller format.
+    #[default]
+    Heuristic,
+    /// Always encode as BMP.
+    AlwaysBmp,
+    /// Always encode as PNG.
+    AlwaysPng,
+    /// Encode the image both ways, and keep whichever result is smaller
+    /// (preferring BMP in case of a tie).  PNG is only considered for images
+    /// whose width and height are both at least `min_png_size`; for example,
+    /// a `min_png_size` of 256 produces files that Windows XP, which doesn't
+    /// understand PNG entries, can still display at smaller sizes.  Where PNG
+    /// is considered, it is also used if the image can't be encoded as a BMP
+    /// with the given BMP options.
+    Smallest {
+        /// The minimum width and height for which PNG will be considered.
+        min_png_size: u32,
+    },
+}
+
+impl FormatPolicy {
+    /// Returns true if this policy permits encoding the given image as a PNG
+    /// at all.
+    pub(crate) fn allows_png(&self, width: u32, height: u32) -> bool {
+        match *self {
+            FormatPolicy::AlwaysBmp => false,
+            FormatPolicy::Smallest { min_png_size } => {
+                width >= min_png_size && height >= min_png_size
+            }
+            FormatPolicy::Heuristic | FormatPolicy::AlwaysPng => true,
+        }
+    }
+}
+
+//===========================================================================//
+
+/// Options for encoding an image within an ICO/CUR file, including how to
+/// choose between BMP and PNG.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EncodingOptions {
+    format_policy: FormatPolicy,
+    bmp_options: BmpEncodingOptions,
+    png_options: PngEncodingOptions,
+}
+
+impl EncodingOptions {
+    /// Creates a new set of options, which uses the heuristic format policy
+    /// and default BMP/PNG options.
+    pub fn new() -> EncodingOptions {
+        EncodingOptions::default()
+    }
+
+    /// Returns the policy for choosing between BMP and PNG.
+    pub fn format_policy(&self) -> FormatPolicy {
+        self.format_policy
+    }
+
+    /// Sets the policy for choosing between BMP and PNG.
+    pub fn set_format_policy(&mut self, policy: FormatPolicy) {
+        self.format_policy = policy;
+    }
+
+    /// Returns the options to use if the image is encoded as a BMP.
+    pub fn bmp_options(&self) -> &BmpEncodingOptions {
+        &self.bmp_options
+    }
+
+    /// Sets the options to use if the image is encoded as a BMP.
+    pub fn set_bmp_options(&mut self, options: BmpEncodingOptions) {
+        self.bmp_options = options;
+    }
+
+    /// Returns the options to use if the image is encoded as a PNG.
+    pub fn png_options(&self) -> &PngEncodingOptions {
+        &self.png_options
+    }
+
+    /// Sets the options to use if the image is encoded as a PNG.
+    pub fn set_png_options(&mut self, options: PngEncodingOptions) {
+        self.png_options = options;
+    }
+}
+
+//===========================================================================//
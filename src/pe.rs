@@ -0,0 +1,1305 @@
+use crate::icondir::IconDir;
+use crate::resource::{
+    self, IconGroup, Resource, ResourceId, ResourceOptions, RT_GROUP_ICON,
+};
+use crate::restype::ResourceType;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read, Write};
+
+//===========================================================================//
+
+// Optional header magic numbers.
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+// Indices of tables in the optional header's data directories.
+const RESOURCE_DIRECTORY_INDEX: usize = 2;
+const CERTIFICATE_DIRECTORY_INDEX: usize = 4;
+
+// Offsets of fields within the optional header (the same for PE32 and
+// PE32+).
+const SIZE_OF_INITIALIZED_DATA_OFFSET: usize = 8;
+const SECTION_ALIGNMENT_OFFSET: usize = 32;
+const FILE_ALIGNMENT_OFFSET: usize = 36;
+const SIZE_OF_IMAGE_OFFSET: usize = 56;
+const SIZE_OF_HEADERS_OFFSET: usize = 60;
+const CHECKSUM_OFFSET: usize = 64;
+
+// The size of a section header, in bytes.
+const SECTION_HEADER_LEN: usize = 40;
+
+// Section flags for a resource section: initialized, read-only data.
+const RESOURCE_SECTION_FLAGS: u32 = 0x4000_0040;
+
+// High bit of a resource directory entry's name or offset field.
+const RESOURCE_HIGH_BIT: u32 = 0x8000_0000;
+
+//===========================================================================//
+
+/// A section header from a PE file.
+#[derive(Clone, Debug)]
+struct Section {
+    virtual_size: u32,
+    virtual_address: u32,
+    raw_size: u32,
+    raw_offset: u32,
+}
+
+impl Section {
+    /// Returns the file offset corresponding to the given RVA, if the RVA
+    /// falls within the part of this section that is stored in the file.
+    fn file_offset(&self, rva: u32) -> Option<usize> {
+        if rva < self.virtual_address
+            || rva - self.virtual_address >= self.raw_size
+        {
+            return None;
+        }
+        Some(self.raw_offset as usize + (rva - self.virtual_address) as usize)
+    }
+
+    /// Returns the file offset just past the end of this section's data.
+    fn raw_end(&self) -> u64 {
+        self.raw_offset as u64 + self.raw_size as u64
+    }
+
+    /// Returns the RVA just past the end of this section, once loaded.
+    fn virtual_end(&self, section_alignment: u32) -> u64 {
+        let size = self.virtual_size.max(self.raw_size) as u64;
+        align_up(self.virtual_address as u64 + size, section_alignment)
+    }
+}
+
+//===========================================================================//
+
+/// A Windows Portable Executable file (such as an .exe or .dll), from which
+/// embedded icons and cursors can be extracted, and whose icons can be
+/// replaced.
+///
+/// Both 32-bit (PE32) and 64-bit (PE32+) executables are supported.
+#[derive(Clone, Debug)]
+pub struct PeFile {
+    data: Vec<u8>,
+    optional_offset: usize,
+    directories_offset: usize,
+    num_directories: u32,
+    sections_offset: usize,
+    sections: Vec<Section>,
+    resources: Vec<Resource>,
+}
+
+impl PeFile {
+    /// Reads a PE file into memory, parsing its headers and the contents of
+    /// its resource directory.  Returns an error if the file isn't a valid
+    /// PE file, or if its resource directory is malformed.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<PeFile> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if !data.starts_with(b"MZ") {
+            invalid_data!("Not a PE file (no MZ signature)");
+        }
+        let pe_offset = read_u32_at(&data, 0x3c)? as usize;
+        if slice_at(&data, pe_offset, 4)? != b"PE\0\0" {
+            invalid_data!("Not a PE file (no PE signature)");
+        }
+        let coff_offset = pe_offset + 4;
+        let num_sections = read_u16_at(&data, coff_offset + 2)? as usize;
+        let optional_size = read_u16_at(&data, coff_offset + 16)? as usize;
+        let optional_offset = coff_offset + 20;
+        let magic = read_u16_at(&data, optional_offset)?;
+        let directories_offset = match magic {
+            PE32_MAGIC => optional_offset + 96,
+            PE32_PLUS_MAGIC => optional_offset + 112,
+            _ => invalid_data!("Invalid optional header magic ({:#x})", magic),
+        };
+        let num_directories = read_u32_at(&data, directories_offset - 4)?;
+        let mut sections = Vec::with_capacity(num_sections);
+        let sections_offset = optional_offset + optional_size;
+        for index in 0..num_sections {
+            let offset = sections_offset + 40 * index;
+            sections.push(Section {
+                virtual_size: read_u32_at(&data, offset + 8)?,
+                virtual_address: read_u32_at(&data, offset + 12)?,
+                raw_size: read_u32_at(&data, offset + 16)?,
+                raw_offset: read_u32_at(&data, offset + 20)?,
+            });
+        }
+        let mut resources = Vec::new();
+        if num_directories as usize > RESOURCE_DIRECTORY_INDEX {
+            let offset = directories_offset + 8 * RESOURCE_DIRECTORY_INDEX;
+            let rva = read_u32_at(&data, offset)?;
+            if rva != 0 {
+                let (section, start) = find_section(&sections, rva)?;
+                let end =
+                    section.raw_offset as usize + section.raw_size as usize;
+                let rsrc = match data.get(start..end) {
+                    Some(rsrc) => rsrc,
+                    None => invalid_data!("Resource section is truncated"),
+                };
+                let mut path = Vec::new();
+                let mut visited = HashSet::new();
+                read_resource_directory(
+                    &data,
+                    &sections,
+                    rsrc,
+                    0,
+                    &mut path,
+                    &mut visited,
+                    &mut resources,
+                )?;
+            }
+        }
+        Ok(PeFile {
+            data,
+            optional_offset,
+            directories_offset,
+            num_directories,
+            sections_offset,
+            sections,
+            resources,
+        })
+    }
+
+    /// Returns every icon and cursor group in the file's resources, each
+    /// reconstructed as an `IconDir` from its RT_ICON or RT_CURSOR members.
+    /// Returns an error if any group is malformed or refers to a missing
+    /// member.
+    pub fn icon_groups(&self) -> io::Result<Vec<IconGroup>> {
+        IconGroup::read_all(&self.resources)
+    }
+
+    /// Adds a collection of icons (or cursors) to the file's resources, as an
+    /// RT_GROUP_ICON (or RT_GROUP_CURSOR) resource with one RT_ICON (or
+    /// RT_CURSOR) resource per entry, using the resource names and language
+    /// given by the options.  An existing group with the same name and
+    /// language is replaced, along with its members.  The change takes effect
+    /// when the file is written.  Returns an error if the member IDs would be
+    /// out of range, or if another group already uses any of them.
+    pub fn set_icon_dir(
+        &mut self,
+        icon_dir: &IconDir,
+        options: &ResourceOptions,
+    ) -> io::Result<()> {
+        resource::replace_icon_group(&mut self.resources, icon_dir, options)
+    }
+
+    /// Replaces the application icon (the first RT_GROUP_ICON resource, which
+    /// is the one Windows shows for the file) with the given icons, or adds
+    /// one if the file has no icons.  The new RT_ICON resources are numbered
+    /// so as not to collide with those of any other icon group.  Returns an
+    /// error if `icon_dir` contains cursors rather than icons, or if there
+    /// are no free resource IDs for its entries.
+    pub fn set_main_icon(&mut self, icon_dir: &IconDir) -> io::Result<()> {
+        if icon_dir.resource_type() != ResourceType::Icon {
+            invalid_input!("Can't use cursors as the application icon");
+        }
+        let mut options = ResourceOptions::new();
+        let main_group = self
+            .resources
+            .iter()
+            .filter(|resource| {
+                resource.restype == ResourceId::Id(RT_GROUP_ICON)
+            })
+            .min_by(|a, b| {
+                a.name.cmp(&b.name).then(a.language.cmp(&b.language))
+            });
+        if let Some(group) = main_group {
+            options.set_group_name(group.name.clone());
+            options.set_language(group.language);
+        }
+        let first_id = resource::unused_member_id(
+            &self.resources,
+            ResourceType::Icon,
+            options.group_name(),
+            options.language(),
+            icon_dir.entries().len(),
+        );
+        match first_id {
+            Some(first_id) => options.set_first_member_id(first_id),
+            None => invalid_input!(
+                "No free resource IDs for {} icons",
+                icon_dir.entries().len()
+            ),
+        }
+        self.set_icon_dir(icon_dir, &options)
+    }
+
+    /// Writes the PE file out to disk, with a resource section rebuilt from
+    /// the current resources.
+    ///
+    /// If the existing resource section is the last section in the file, it
+    /// is rewritten in place (growing or shrinking as needed); otherwise a new
+    /// `.rsrc` section is appended, and the old one is left unused.  The image
+    /// size, section table, and checksum are updated to match.  Any
+    /// Authenticode signature is removed, since it would no longer be valid.
+    /// Returns an error if the file has no room for the resource table or for
+    /// a new section header.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        if self.num_directories as usize <= RESOURCE_DIRECTORY_INDEX {
+            invalid_input!("PE file has no resource data directory");
+        }
+        let opt = self.optional_offset;
+        let section_alignment =
+            read_u32_at(&self.data, opt + SECTION_ALIGNMENT_OFFSET)?;
+        let file_alignment =
+            read_u32_at(&self.data, opt + FILE_ALIGNMENT_OFFSET)?;
+        if !section_alignment.is_power_of_two()
+            || !file_alignment.is_power_of_two()
+        {
+            invalid_data!(
+                "Invalid section/file alignment ({:#x}/{:#x})",
+                section_alignment,
+                file_alignment
+            );
+        }
+        let size_of_headers =
+            read_u32_at(&self.data, opt + SIZE_OF_HEADERS_OFFSET)? as u64;
+        let resource_entry =
+            self.directories_offset + 8 * RESOURCE_DIRECTORY_INDEX;
+        let resource_rva = read_u32_at(&self.data, resource_entry)?;
+        let raw_end = self
+            .sections
+            .iter()
+            .map(Section::raw_end)
+            .fold(size_of_headers, u64::max);
+        if raw_end > self.data.len() as u64 {
+            invalid_data!(
+                "PE file is truncated (sections end at {:#x}, but file \
+                 length is {:#x})",
+                raw_end,
+                self.data.len()
+            );
+        }
+        let virtual_end = self
+            .sections
+            .iter()
+            .map(|section| section.virtual_end(section_alignment))
+            .fold(align_up(size_of_headers, section_alignment), u64::max);
+
+        // Rewrite the existing resource section in place if nothing follows
+        // it, either in the file or in memory; otherwise, add a new section.
+        let in_place = self.sections.iter().position(|section| {
+            resource_rva != 0
+                && section.virtual_address == resource_rva
+                && section.raw_end() == raw_end
+                && section.virtual_end(section_alignment) == virtual_end
+        });
+        let (index, rva, raw_offset, old_raw_size) = match in_place {
+            Some(index) => {
+                let section = &self.sections[index];
+                let raw_offset = section.raw_offset as u64;
+                (
+                    index,
+                    section.virtual_address as u64,
+                    raw_offset,
+                    section.raw_size,
+                )
+            }
+            None => {
+                let index = self.sections.len();
+                let header_end =
+                    self.sections_offset + SECTION_HEADER_LEN * (index + 1);
+                let first_raw_offset = self
+                    .sections
+                    .iter()
+                    .filter(|section| section.raw_size > 0)
+                    .map(|section| section.raw_offset as u64)
+                    .fold(size_of_headers, u64::min);
+                let header_start = header_end - SECTION_HEADER_LEN;
+                if header_end as u64 > first_raw_offset
+                    || self.data[header_start..header_end]
+                        .iter()
+                        .any(|&byte| byte != 0)
+                {
+                    invalid_input!(
+                        "PE file has no room for a new section header"
+                    );
+                }
+                let raw_offset = align_up(raw_end, file_alignment);
+                (index, virtual_end, raw_offset, 0)
+            }
+        };
+        if rva > u32::MAX as u64 {
+            invalid_input!("PE image is too large for a new section");
+        }
+        let rsrc = write_resource_directory(&self.resources, rva as u32)?;
+        let raw_size = align_up(rsrc.len() as u64, file_alignment);
+        let image_size = align_up(rva + rsrc.len() as u64, section_alignment);
+        if raw_offset + raw_size > u32::MAX as u64
+            || image_size > u32::MAX as u64
+        {
+            invalid_input!("PE image is too large for the new resources");
+        }
+
+        // Keep any data appended after the last section (the "overlay"),
+        // except for a trailing Authenticode signature.
+        let mut overlay_end = self.data.len();
+        let mut remove_certificate = false;
+        if self.num_directories as usize > CERTIFICATE_DIRECTORY_INDEX {
+            let entry =
+                self.directories_offset + 8 * CERTIFICATE_DIRECTORY_INDEX;
+            let offset = read_u32_at(&self.data, entry)? as usize;
+            let size = read_u32_at(&self.data, entry + 4)? as usize;
+            if size > 0 {
+                remove_certificate = true;
+                if offset as u64 >= raw_end && offset + size == overlay_end {
+                    overlay_end = offset;
+                }
+            }
+        }
+        let overlay_start = (raw_end as usize).min(overlay_end);
+        let overlay = &self.data[overlay_start..overlay_end];
+
+        let mut output =
+            self.data[..(raw_end as usize).min(raw_offset as usize)].to_vec();
+        output.resize(raw_offset as usize, 0);
+        output.extend_from_slice(&rsrc);
+        output.resize((raw_offset + raw_size) as usize, 0);
+        output.extend_from_slice(overlay);
+
+        // Fix up the headers.
+        let header = self.sections_offset + SECTION_HEADER_LEN * index;
+        if in_place.is_none() {
+            output[header..(header + 8)].copy_from_slice(b".rsrc\0\0\0");
+            write_u32_at(&mut output, header + 36, RESOURCE_SECTION_FLAGS);
+            let num_sections = (index + 1) as u16;
+            let num_sections_offset = opt - 18;
+            output[num_sections_offset..(num_sections_offset + 2)]
+                .copy_from_slice(&num_sections.to_le_bytes());
+        }
+        write_u32_at(&mut output, header + 8, rsrc.len() as u32);
+        write_u32_at(&mut output, header + 12, rva as u32);
+        write_u32_at(&mut output, header + 16, raw_size as u32);
+        write_u32_at(&mut output, header + 20, raw_offset as u32);
+        write_u32_at(&mut output, resource_entry, rva as u32);
+        write_u32_at(&mut output, resource_entry + 4, rsrc.len() as u32);
+        if remove_certificate {
+            let entry =
+                self.directories_offset + 8 * CERTIFICATE_DIRECTORY_INDEX;
+            write_u32_at(&mut output, entry, 0);
+            write_u32_at(&mut output, entry + 4, 0);
+        }
+        let initialized_offset = opt + SIZE_OF_INITIALIZED_DATA_OFFSET;
+        let initialized_size = read_u32_at(&output, initialized_offset)?
+            .wrapping_sub(old_raw_size)
+            .wrapping_add(raw_size as u32);
+        write_u32_at(&mut output, initialized_offset, initialized_size);
+        write_u32_at(
+            &mut output,
+            opt + SIZE_OF_IMAGE_OFFSET,
+            image_size as u32,
+        );
+        let checksum = pe_checksum(&output, opt + CHECKSUM_OFFSET);
+        write_u32_at(&mut output, opt + CHECKSUM_OFFSET, checksum);
+        writer.write_all(&output)
+    }
+}
+
+//===========================================================================//
+
+/// Recursively reads the resource directory table at `offset` within the
+/// resource section, appending each resource found to `resources`.  The
+/// `path` holds the type and name IDs of the enclosing directories, and
+/// `visited` holds the offsets of the directory tables and data entries read
+/// so far; each may only be referenced once, so that a malicious file can't
+/// alias them to make the tree (and the data copied) arbitrarily large.
+fn read_resource_directory(
+    data: &[u8],
+    sections: &[Section],
+    rsrc: &[u8],
+    offset: usize,
+    path: &mut Vec<ResourceId>,
+    visited: &mut HashSet<usize>,
+    resources: &mut Vec<Resource>,
+) -> io::Result<()> {
+    if !visited.insert(offset) {
+        invalid_data!(
+            "Resource directory table at offset {:#x} is referenced more \
+             than once",
+            offset
+        );
+    }
+    let num_named = read_u16_at(rsrc, offset + 12)? as usize;
+    let num_ids = read_u16_at(rsrc, offset + 14)? as usize;
+    for index in 0..(num_named + num_ids) {
+        let entry_offset = offset + 16 + 8 * index;
+        let name_field = read_u32_at(rsrc, entry_offset)?;
+        let id = if (name_field & RESOURCE_HIGH_BIT) != 0 {
+            let name_offset = (name_field & !RESOURCE_HIGH_BIT) as usize;
+            let len = read_u16_at(rsrc, name_offset)? as usize;
+            let mut chars = slice_at(rsrc, name_offset + 2, 2 * len)?;
+            let mut units = Vec::with_capacity(len);
+            for _ in 0..len {
+                units.push(chars.read_u16::<LittleEndian>()?);
+            }
+            ResourceId::Name(String::from_utf16_lossy(&units))
+        } else {
+            ResourceId::Id(name_field as u16)
+        };
+        let data_field = read_u32_at(rsrc, entry_offset + 4)?;
+        let is_directory = (data_field & RESOURCE_HIGH_BIT) != 0;
+        let child_offset = (data_field & !RESOURCE_HIGH_BIT) as usize;
+        if path.len() < 2 {
+            if !is_directory {
+                invalid_data!(
+                    "Resource directory entry at level {} is not a \
+                     subdirectory",
+                    path.len()
+                );
+            }
+            path.push(id);
+            read_resource_directory(
+                data,
+                sections,
+                rsrc,
+                child_offset,
+                path,
+                visited,
+                resources,
+            )?;
+            path.pop();
+            continue;
+        }
+        let language = match id {
+            ResourceId::Id(language) if !is_directory => language,
+            _ => invalid_data!("Invalid resource language directory entry"),
+        };
+        if !visited.insert(child_offset) {
+            invalid_data!(
+                "Resource data entry at offset {:#x} is referenced more than \
+                 once",
+                child_offset
+            );
+        }
+        let data_rva = read_u32_at(rsrc, child_offset)?;
+        let size = read_u32_at(rsrc, child_offset + 4)? as usize;
+        let (_, start) = find_section(sections, data_rva)?;
+        resources.push(Resource {
+            restype: path[0].clone(),
+            name: path[1].clone(),
+            language,
+            data: slice_at(data, start, size)?.to_vec(),
+        });
+    }
+    Ok(())
+}
+
+/// Serializes a resource directory (with its name strings and resource
+/// data) for a resource section loaded at the given RVA.  Entries at each
+/// level are sorted with named entries first, as Windows requires.
+fn write_resource_directory(
+    resources: &[Resource],
+    base_rva: u32,
+) -> io::Result<Vec<u8>> {
+    let mut tree =
+        BTreeMap::<&ResourceId, BTreeMap<&ResourceId, BTreeMap<u16, _>>>::new(
+        );
+    for resource in resources.iter() {
+        tree.entry(&resource.restype)
+            .or_default()
+            .entry(&resource.name)
+            .or_default()
+            .entry(resource.language)
+            .or_insert(&resource.data);
+    }
+
+    // Lay out the directory tables breadth-first (the root, then the name
+    // directories, then the language directories), followed by the data
+    // entries, the name strings, and finally the resource data itself.
+    let table_len = |num_entries: usize| 16 + 8 * num_entries;
+    let mut tables_len = table_len(tree.len());
+    let mut num_data_entries = 0;
+    for names in tree.values() {
+        tables_len += table_len(names.len());
+        for languages in names.values() {
+            tables_len += table_len(languages.len());
+            num_data_entries += languages.len();
+        }
+    }
+    let data_entries_offset = tables_len;
+    let strings_offset = data_entries_offset + 16 * num_data_entries;
+    let mut strings = Vec::<u8>::new();
+    let mut string_offsets = BTreeMap::<&str, usize>::new();
+    for (&restype, names) in tree.iter() {
+        for name in std::iter::once(restype).chain(names.keys().cloned()) {
+            if let ResourceId::Name(string) = name {
+                if !string_offsets.contains_key(string.as_str()) {
+                    string_offsets.insert(
+                        string.as_str(),
+                        strings_offset + strings.len(),
+                    );
+                    let units: Vec<u16> = string.encode_utf16().collect();
+                    strings.write_u16::<LittleEndian>(units.len() as u16)?;
+                    for unit in units {
+                        strings.write_u16::<LittleEndian>(unit)?;
+                    }
+                }
+            }
+        }
+    }
+    let data_offset = align_up((strings_offset + strings.len()) as u64, 8);
+    let name_field = |id: &ResourceId| match id {
+        ResourceId::Id(id) => *id as u32,
+        ResourceId::Name(string) => {
+            RESOURCE_HIGH_BIT | string_offsets[string.as_str()] as u32
+        }
+    };
+
+    let mut output = Vec::with_capacity(data_offset as usize);
+    let mut next_table = table_len(tree.len());
+    write_table_header(&mut output, tree.keys().cloned())?;
+    for (&restype, names) in tree.iter() {
+        output.write_u32::<LittleEndian>(name_field(restype))?;
+        output.write_u32::<LittleEndian>(
+            RESOURCE_HIGH_BIT | next_table as u32,
+        )?;
+        next_table += table_len(names.len());
+    }
+    for names in tree.values() {
+        write_table_header(&mut output, names.keys().cloned())?;
+        for (&name, languages) in names.iter() {
+            output.write_u32::<LittleEndian>(name_field(name))?;
+            output.write_u32::<LittleEndian>(
+                RESOURCE_HIGH_BIT | next_table as u32,
+            )?;
+            next_table += table_len(languages.len());
+        }
+    }
+    let mut next_data_entry = data_entries_offset;
+    for names in tree.values() {
+        for languages in names.values() {
+            output.extend_from_slice(&[0; 12]);
+            output.write_u16::<LittleEndian>(0)?; // named entries
+            output.write_u16::<LittleEndian>(languages.len() as u16)?;
+            for &language in languages.keys() {
+                output.write_u32::<LittleEndian>(language as u32)?;
+                output.write_u32::<LittleEndian>(next_data_entry as u32)?;
+                next_data_entry += 16;
+            }
+        }
+    }
+    let mut blobs = Vec::new();
+    let mut next_data = data_offset;
+    for names in tree.values() {
+        for languages in names.values() {
+            for &data in languages.values() {
+                let rva = base_rva as u64 + next_data;
+                if rva + data.len() as u64 > u32::MAX as u64 {
+                    invalid_input!("Resource section is too large");
+                }
+                output.write_u32::<LittleEndian>(rva as u32)?;
+                output.write_u32::<LittleEndian>(data.len() as u32)?;
+                output.write_u32::<LittleEndian>(0)?; // code page
+                output.write_u32::<LittleEndian>(0)?; // reserved
+                next_data = align_up(next_data + data.len() as u64, 8);
+                blobs.push(data);
+            }
+        }
+    }
+    output.extend_from_slice(&strings);
+    for data in blobs {
+        output.resize(align_up(output.len() as u64, 8) as usize, 0);
+        output.extend_from_slice(data);
+    }
+    Ok(output)
+}
+
+/// Writes the fixed-size header of a resource directory table with the
+/// given entry names.
+fn write_table_header<'a, I>(output: &mut Vec<u8>, names: I) -> io::Result<()>
+where
+    I: Iterator<Item = &'a ResourceId>,
+{
+    let (mut num_named, mut num_ids) = (0u16, 0u16);
+    for name in names {
+        match name {
+            ResourceId::Name(_) => num_named += 1,
+            ResourceId::Id(_) => num_ids += 1,
+        }
+    }
+    output.extend_from_slice(&[0; 12]); // flags, timestamp, and version
+    output.write_u16::<LittleEndian>(num_named)?;
+    output.write_u16::<LittleEndian>(num_ids)?;
+    Ok(())
+}
+
+/// Computes the PE checksum of a file, skipping over the checksum field
+/// itself (at the given offset).
+fn pe_checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (index, chunk) in data.chunks(2).enumerate() {
+        let offset = 2 * index;
+        if offset == checksum_offset || offset == checksum_offset + 2 {
+            continue;
+        }
+        let word = chunk[0] as u64 | (*chunk.get(1).unwrap_or(&0) as u64) << 8;
+        sum += word;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum as u32).wrapping_add(data.len() as u32)
+}
+
+/// Rounds `value` up to a multiple of `alignment`, which must be a power of
+/// two.
+fn align_up(value: u64, alignment: u32) -> u64 {
+    let mask = alignment as u64 - 1;
+    (value + mask) & !mask
+}
+
+/// Returns the section containing the given RVA, along with the RVA's
+/// corresponding file offset.
+fn find_section(
+    sections: &[Section],
+    rva: u32,
+) -> io::Result<(&Section, usize)> {
+    for section in sections.iter() {
+        if let Some(offset) = section.file_offset(rva) {
+            return Ok((section, offset));
+        }
+    }
+    invalid_data!("RVA {:#x} is not within any section", rva);
+}
+
+fn slice_at(data: &[u8], offset: usize, len: usize) -> io::Result<&[u8]> {
+    match data.get(offset..offset.saturating_add(len)) {
+        Some(slice) => Ok(slice),
+        None => invalid_data!(
+            "PE data is truncated (needed {} bytes at offset {:#x})",
+            len,
+            offset
+        ),
+    }
+}
+
+fn read_u16_at(data: &[u8], offset: usize) -> io::Result<u16> {
+    slice_at(data, offset, 2)?.read_u16::<LittleEndian>()
+}
+
+fn read_u32_at(data: &[u8], offset: usize) -> io::Result<u32> {
+    slice_at(data, offset, 4)?.read_u32::<LittleEndian>()
+}
+
+fn write_u32_at(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..(offset + 4)].copy_from_slice(&value.to_le_bytes());
+}
+
+//===========================================================================//
+#[cfg(test)]
+mod tests {
+    use super::{pe_checksum, write_resource_directory, PeFile};
+    use crate::icondir::{IconDir, IconDirEntry};
+    use crate::image::IconImage;
+    use crate::resource::{Resource, ResourceId};
+    use crate::restype::ResourceType;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use std::collections::BTreeMap;
+    use std::io;
+
+    // A resource given as (type, name, language, data).
+    type RawResource = (ResourceId, ResourceId, u16, Vec<u8>);
+
+    // Each test section gets its own 64 KiB slot in memory.
+    fn section_rva(index: usize) -> u32 {
+        0x1000 + 0x10000 * index as u32
+    }
+
+    /// Builds a resource section (as loaded at `base_rva`) containing the
+    /// given resources.
+    fn build_resource_section(
+        resources: &[RawResource],
+        base_rva: u32,
+    ) -> Vec<u8> {
+        let mut tree = BTreeMap::<_, BTreeMap<_, Vec<_>>>::new();
+        for (restype, name, language, data) in resources.iter() {
+            tree.entry(restype.clone())
+                .or_default()
+                .entry(name.clone())
+                .or_default()
+                .push((*language, data.clone()));
+        }
+        fn dir_len(num_entries: usize) -> usize {
+            16 + 8 * num_entries
+        }
+        // Lay out the root directory, then the name directories, then the
+        // language directories, then the data entries, then the name
+        // strings, then the data itself.
+        let mut tables_len = dir_len(tree.len());
+        for names in tree.values() {
+            tables_len += dir_len(names.len());
+            for languages in names.values() {
+                tables_len += dir_len(languages.len()) + 16 * languages.len();
+            }
+        }
+        let mut strings = Vec::<u8>::new();
+        let mut string_offset = |id: &ResourceId| -> u32 {
+            match id {
+                ResourceId::Id(id) => *id as u32,
+                ResourceId::Name(name) => {
+                    let offset = tables_len + strings.len();
+                    let units: Vec<u16> = name.encode_utf16().collect();
+                    strings
+                        .write_u16::<LittleEndian>(units.len() as u16)
+                        .unwrap();
+                    for unit in units {
+                        strings.write_u16::<LittleEndian>(unit).unwrap();
+                    }
+                    0x8000_0000 | offset as u32
+                }
+            }
+        };
+        let mut tables = Vec::<u8>::new();
+        let mut blobs = Vec::<Vec<u8>>::new();
+        let write_dir = |out: &mut Vec<u8>, entries: &[(u32, u32)]| {
+            out.extend_from_slice(&[0; 12]);
+            let num_named =
+                entries.iter().filter(|e| e.0 & 0x8000_0000 != 0).count();
+            out.write_u16::<LittleEndian>(num_named as u16).unwrap();
+            out.write_u16::<LittleEndian>((entries.len() - num_named) as u16)
+                .unwrap();
+            for &(name, offset) in entries {
+                out.write_u32::<LittleEndian>(name).unwrap();
+                out.write_u32::<LittleEndian>(offset).unwrap();
+            }
+        };
+        // Compute offsets of each table, in layout order.
+        let mut next = dir_len(tree.len());
+        let mut type_entries = Vec::new();
+        let mut name_dirs = Vec::new();
+        for (restype, names) in tree.iter() {
+            type_entries
+                .push((string_offset(restype), 0x8000_0000 | next as u32));
+            next += dir_len(names.len());
+            name_dirs.push(names);
+        }
+        let mut subdir_tables = Vec::new();
+        let mut lang_tables = Vec::new();
+        for names in name_dirs.iter() {
+            let mut entries = Vec::new();
+            for (name, languages) in names.iter() {
+                entries.push((string_offset(name), 0x8000_0000 | next as u32));
+                next += dir_len(languages.len());
+                lang_tables.push(languages);
+            }
+            subdir_tables.push(entries);
+        }
+        for languages in lang_tables.iter() {
+            let mut entries = Vec::new();
+            for (language, data) in languages.iter() {
+                entries.push((*language as u32, next as u32));
+                next += 16;
+                blobs.push(data.clone());
+            }
+            subdir_tables.push(entries);
+        }
+        write_dir(&mut tables, &type_entries);
+        for entries in subdir_tables.iter() {
+            write_dir(&mut tables, entries);
+        }
+        let mut data_offset = tables_len + strings.len();
+        for blob in blobs.iter() {
+            tables
+                .write_u32::<LittleEndian>(base_rva + data_offset as u32)
+                .unwrap();
+            tables.write_u32::<LittleEndian>(blob.len() as u32).unwrap();
+            tables.extend_from_slice(&[0; 8]);
+            data_offset += blob.len();
+        }
+        assert_eq!(tables.len(), tables_len);
+        tables.extend_from_slice(&strings);
+        for blob in blobs {
+            tables.extend_from_slice(&blob);
+        }
+        tables
+    }
+
+    /// Builds a minimal PE32 file with the given sections, using the table in
+    /// section `resource_index` (if any) as the resource directory.
+    fn build_pe(
+        sections: &[(&[u8; 8], Vec<u8>)],
+        resource_index: Option<usize>,
+    ) -> Vec<u8> {
+        build_pe_with_magic(0x10b, sections, resource_index)
+    }
+
+    /// Like `build_pe`, but with the given optional header magic number
+    /// (0x10b for PE32, or 0x20b for PE32+).
+    fn build_pe_with_magic(
+        magic: u16,
+        sections: &[(&[u8; 8], Vec<u8>)],
+        resource_index: Option<usize>,
+    ) -> Vec<u8> {
+        // The data directories are 16 bytes further in for PE32+, since some
+        // of the preceding fields are 64 bits wide.
+        let directories = if magic == 0x20b { 112 } else { 96 };
+        let optional_size = directories + 16 * 8;
+        let mut pe = vec![0u8; 0x40];
+        pe[0..2].copy_from_slice(b"MZ");
+        pe[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        pe.extend_from_slice(b"PE\0\0");
+        let machine: u16 = if magic == 0x20b { 0x8664 } else { 0x14c };
+        pe.write_u16::<LittleEndian>(machine).unwrap();
+        pe.write_u16::<LittleEndian>(sections.len() as u16).unwrap();
+        pe.extend_from_slice(&[0; 12]);
+        pe.write_u16::<LittleEndian>(optional_size as u16).unwrap();
+        pe.write_u16::<LittleEndian>(0x102).unwrap(); // characteristics
+        let mut optional = vec![0u8; optional_size];
+        optional[0..2].copy_from_slice(&magic.to_le_bytes());
+        optional[32..36].copy_from_slice(&0x1000u32.to_le_bytes());
+        optional[36..40].copy_from_slice(&0x200u32.to_le_bytes());
+        let image_size = section_rva(sections.len());
+        optional[56..60].copy_from_slice(&image_size.to_le_bytes());
+        optional[60..64].copy_from_slice(&0x400u32.to_le_bytes());
+        optional[(directories - 4)..directories]
+            .copy_from_slice(&16u32.to_le_bytes());
+        if let Some(index) = resource_index {
+            let len = sections[index].1.len() as u32;
+            let entry = directories + 16;
+            optional[entry..(entry + 4)]
+                .copy_from_slice(&section_rva(index).to_le_bytes());
+            optional[(entry + 4)..(entry + 8)]
+                .copy_from_slice(&len.to_le_bytes());
+        }
+        pe.extend_from_slice(&optional);
+        let mut raw_offset = 0x400u32;
+        for (index, (name, data)) in sections.iter().enumerate() {
+            let raw_size = (data.len() as u32 + 0x1ff) & !0x1ff;
+            pe.extend_from_slice(&name[..]);
+            pe.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            pe.write_u32::<LittleEndian>(section_rva(index)).unwrap();
+            pe.write_u32::<LittleEndian>(raw_size).unwrap();
+            pe.write_u32::<LittleEndian>(raw_offset).unwrap();
+            pe.extend_from_slice(&[0; 12]);
+            pe.write_u32::<LittleEndian>(0x4000_0040).unwrap();
+            raw_offset += raw_size;
+        }
+        pe.resize(0x400, 0);
+        for (_, data) in sections.iter() {
+            pe.extend_from_slice(data);
+            pe.resize((pe.len() + 0x1ff) & !0x1ff, 0);
+        }
+        pe
+    }
+
+    /// Builds a resource section in slot `index` containing the given
+    /// resources.
+    fn resource_section(resources: &[RawResource], index: usize) -> Vec<u8> {
+        build_resource_section(resources, section_rva(index))
+    }
+
+    /// Encodes a collection as an RT_GROUP_ICON or RT_GROUP_CURSOR resource
+    /// and its members (in U.S. English), with member IDs starting from
+    /// `first_id`.
+    fn group_resources(
+        icon_dir: &IconDir,
+        group_name: ResourceId,
+        first_id: u16,
+    ) -> Vec<RawResource> {
+        let (group_type, member_type) = match icon_dir.resource_type() {
+            ResourceType::Icon => (14, 3),
+            ResourceType::Cursor => (12, 1),
+        };
+        let mut group = Vec::new();
+        group.write_u16::<LittleEndian>(0).unwrap();
+        group
+            .write_u16::<LittleEndian>(icon_dir.resource_type().number())
+            .unwrap();
+        group
+            .write_u16::<LittleEndian>(icon_dir.entries().len() as u16)
+            .unwrap();
+        let mut resources = Vec::new();
+        for (index, entry) in icon_dir.entries().iter().enumerate() {
+            let mut data = Vec::new();
+            match entry.cursor_hotspot() {
+                None => {
+                    group.push(entry.width() as u8);
+                    group.push(entry.height() as u8);
+                    group.extend_from_slice(&[0, 0, 1, 0]);
+                    group
+                        .write_u16::<LittleEndian>(entry.bits_per_pixel())
+                        .unwrap();
+                }
+                Some((x, y)) => {
+                    group
+                        .write_u16::<LittleEndian>(entry.width() as u16)
+                        .unwrap();
+                    group
+                        .write_u16::<LittleEndian>(2 * entry.height() as u16)
+                        .unwrap();
+                    group.extend_from_slice(&[1, 0, 32, 0]);
+                    data.write_u16::<LittleEndian>(x).unwrap();
+                    data.write_u16::<LittleEndian>(y).unwrap();
+                }
+            }
+            data.extend_from_slice(entry.data());
+            group.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            let id = first_id + index as u16;
+            group.write_u16::<LittleEndian>(id).unwrap();
+            resources.push((
+                ResourceId::Id(member_type),
+                ResourceId::Id(id),
+                0x409,
+                data,
+            ));
+        }
+        resources.push((ResourceId::Id(group_type), group_name, 0x409, group));
+        resources
+    }
+
+    fn sample_icon_dir(sizes: &[u32], hotspot: Option<(u16, u16)>) -> IconDir {
+        let restype = if hotspot.is_some() {
+            ResourceType::Cursor
+        } else {
+            ResourceType::Icon
+        };
+        let mut icon_dir = IconDir::new(restype);
+        for &size in sizes.iter() {
+            let mut image = IconImage::from_rgba_data(
+                size,
+                size,
+                vec![size as u8; (4 * size * size) as usize],
+            );
+            image.set_cursor_hotspot(hotspot);
+            icon_dir.add_entry(IconDirEntry::encode(&image).unwrap());
+        }
+        icon_dir
+    }
+
+    fn assert_same_entries(actual: &IconDir, expected: &IconDir) {
+        assert_eq!(actual.resource_type(), expected.resource_type());
+        assert_eq!(actual.entries().len(), expected.entries().len());
+        for (entry, expected) in
+            actual.entries().iter().zip(expected.entries())
+        {
+            assert_eq!(entry.width(), expected.width());
+            assert_eq!(entry.height(), expected.height());
+            assert_eq!(entry.bits_per_pixel(), expected.bits_per_pixel());
+            assert_eq!(entry.cursor_hotspot(), expected.cursor_hotspot());
+            assert_eq!(entry.data(), expected.data());
+        }
+    }
+
+    fn write_and_reread(pe: &PeFile) -> (Vec<u8>, PeFile) {
+        let mut output = Vec::<u8>::new();
+        pe.write(&mut output).unwrap();
+        let checksum_offset = pe.optional_offset + 64;
+        let checksum = u32::from_le_bytes([
+            output[checksum_offset],
+            output[checksum_offset + 1],
+            output[checksum_offset + 2],
+            output[checksum_offset + 3],
+        ]);
+        assert_eq!(checksum, pe_checksum(&output, checksum_offset));
+        let reread = PeFile::read(output.as_slice()).unwrap();
+        (output, reread)
+    }
+
+    #[test]
+    fn read_icon_and_cursor_groups() {
+        let icons = sample_icon_dir(&[16, 32], None);
+        let cursors = sample_icon_dir(&[16, 32], Some((3, 4)));
+        let mut resources =
+            group_resources(&icons, ResourceId::Name("MAINICON".into()), 1);
+        resources.extend(group_resources(&cursors, ResourceId::Id(7), 5));
+        let rsrc = resource_section(&resources, 0);
+        let pe = build_pe(&[(b".rsrc\0\0\0", rsrc)], Some(0));
+        let pe = PeFile::read(pe.as_slice()).unwrap();
+        let groups = pe.icon_groups().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].name(), &ResourceId::Id(7));
+        assert_eq!(groups[0].language(), 0x409);
+        assert_same_entries(groups[0].icon_dir(), &cursors);
+        assert_eq!(groups[1].name(), &ResourceId::Name("MAINICON".into()));
+        let icon_dir = groups[1].clone().into_icon_dir();
+        assert_same_entries(&icon_dir, &icons);
+        let image = icon_dir.entries()[1].decode().unwrap();
+        assert_eq!(image.rgba_data(), &[32; 4 * 32 * 32][..]);
+    }
+
+    #[test]
+    fn resource_directory_order() {
+        let resource = |restype, name| Resource {
+            restype,
+            name,
+            language: 0,
+            data: vec![1, 2, 3],
+        };
+        let resources = vec![
+            resource(ResourceId::Id(3), ResourceId::Id(2)),
+            resource(ResourceId::Name("B".into()), ResourceId::Id(1)),
+            resource(ResourceId::Id(3), ResourceId::Name("X".into())),
+            resource(ResourceId::Name("A".into()), ResourceId::Id(1)),
+            resource(ResourceId::Id(3), ResourceId::Id(1)),
+        ];
+        let rsrc = write_resource_directory(&resources, 0x1000).unwrap();
+        let u16_at = |offset: usize| {
+            u16::from_le_bytes([rsrc[offset], rsrc[offset + 1]])
+        };
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                rsrc[offset],
+                rsrc[offset + 1],
+                rsrc[offset + 2],
+                rsrc[offset + 3],
+            ])
+        };
+        // Returns the name (or ID) fields of a directory table's entries,
+        // with names resolved to strings, along with the offsets the entries
+        // point to.
+        let entries = |offset: usize| -> Vec<(ResourceId, u32)> {
+            assert_eq!(u32_at(offset + 4), 0); // time stamp
+            let count =
+                u16_at(offset + 12) as usize + u16_at(offset + 14) as usize;
+            (0..count)
+                .map(|index| {
+                    let entry = offset + 16 + 8 * index;
+                    let name = u32_at(entry);
+                    let id = if name & 0x8000_0000 == 0 {
+                        ResourceId::Id(name as u16)
+                    } else {
+                        let start = (name & 0x7fff_ffff) as usize;
+                        let len = u16_at(start) as usize;
+                        let units: Vec<u16> = (0..len)
+                            .map(|unit| u16_at(start + 2 + 2 * unit))
+                            .collect();
+                        ResourceId::Name(String::from_utf16(&units).unwrap())
+                    };
+                    (id, u32_at(entry + 4))
+                })
+                .collect()
+        };
+        // Named entries must come first (sorted by name), followed by ID
+        // entries (sorted by ID), at every level of the directory.
+        let root = entries(0);
+        assert_eq!(u16_at(12), 2);
+        assert_eq!(
+            root.iter().map(|entry| entry.0.clone()).collect::<Vec<_>>(),
+            vec![
+                ResourceId::Name("A".into()),
+                ResourceId::Name("B".into()),
+                ResourceId::Id(3),
+            ]
+        );
+        let names_offset = (root[2].1 & 0x7fff_ffff) as usize;
+        assert_eq!(u16_at(names_offset + 12), 1);
+        let names = entries(names_offset);
+        assert_eq!(
+            names.iter().map(|entry| entry.0.clone()).collect::<Vec<_>>(),
+            vec![
+                ResourceId::Name("X".into()),
+                ResourceId::Id(1),
+                ResourceId::Id(2),
+            ]
+        );
+        // Every leaf is a data entry pointing at the resource data.
+        for (_, offset) in names {
+            let languages = entries((offset & 0x7fff_ffff) as usize);
+            assert_eq!(languages.len(), 1);
+            assert_eq!(languages[0].0, ResourceId::Id(0));
+            let data_entry = languages[0].1 as usize;
+            let data_start = (u32_at(data_entry) - 0x1000) as usize;
+            assert_eq!(u32_at(data_entry + 4), 3);
+            assert_eq!(&rsrc[data_start..(data_start + 3)], &[1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn reject_aliased_resource_directories() {
+        // Builds a resource section from directory tables (each a list of
+        // name and offset fields) laid out one after another, followed by a
+        // single data entry and its data.
+        let build = |tables: &[&[(u32, u32)]]| {
+            let mut rsrc = Vec::new();
+            for entries in tables.iter() {
+                rsrc.extend_from_slice(&[0; 14]);
+                rsrc.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+                for &(name, offset) in entries.iter() {
+                    rsrc.write_u32::<LittleEndian>(name).unwrap();
+                    rsrc.write_u32::<LittleEndian>(offset).unwrap();
+                }
+            }
+            let data_rva = section_rva(0) + rsrc.len() as u32 + 16;
+            rsrc.write_u32::<LittleEndian>(data_rva).unwrap();
+            rsrc.write_u32::<LittleEndian>(4).unwrap();
+            rsrc.extend_from_slice(&[0; 8]);
+            rsrc.extend_from_slice(b"data");
+            let pe = build_pe(&[(b".rsrc\0\0\0", rsrc)], Some(0));
+            PeFile::read(pe.as_slice())
+        };
+        let is_aliased = |result: io::Result<PeFile>| match result {
+            Ok(_) => false,
+            Err(error) => error.to_string().contains("more than once"),
+        };
+        let dir = 0x8000_0000;
+        // Root (at 0), one name directory (at 24), one language directory (at
+        // 48), and the data entry (at 72).
+        let pe = build(&[&[(3, dir | 24)], &[(1, dir | 48)], &[(0x409, 72)]]);
+        let pe = pe.unwrap();
+        assert_eq!(pe.resources.len(), 1);
+        assert_eq!(pe.resources[0].data, b"data");
+        // Two types sharing one name directory (the root is now 8 bytes
+        // longer, so everything else moves down).
+        let tables: &[&[(u32, u32)]] = &[
+            &[(3, dir | 32), (14, dir | 32)],
+            &[(1, dir | 56)],
+            &[(0x409, 80)],
+        ];
+        assert!(is_aliased(build(tables)));
+        // A name directory that refers back to the root.
+        assert!(is_aliased(build(&[&[(3, dir | 24)], &[(1, dir)], &[]])));
+        // Two languages sharing one data entry.
+        let tables: &[&[(u32, u32)]] =
+            &[&[(3, dir | 24)], &[(1, dir | 48)], &[(0, 80), (0x409, 80)]];
+        assert!(is_aliased(build(tables)));
+    }
+
+    #[test]
+    fn missing_group_member() {
+        let icons = sample_icon_dir(&[16, 32], None);
+        let mut resources = group_resources(&icons, ResourceId::Id(1), 1);
+        resources.remove(0);
+        let rsrc = resource_section(&resources, 0);
+        let pe = build_pe(&[(b".rsrc\0\0\0", rsrc)], Some(0));
+        let pe = PeFile::read(pe.as_slice()).unwrap();
+        assert!(pe.icon_groups().is_err());
+    }
+
+    #[test]
+    fn reject_non_pe_files() {
+        assert!(PeFile::read(&b"not a PE file"[..]).is_err());
+        let mut pe = build_pe(&[(b".text\0\0\0", vec![0xc3])], None);
+        pe[0x40] = b'X';
+        assert!(PeFile::read(pe.as_slice()).is_err());
+        pe.truncate(0x100);
+        assert!(PeFile::read(pe.as_slice()).is_err());
+    }
+
+    #[test]
+    fn replace_main_icon_in_place() {
+        let old_icons = sample_icon_dir(&[16, 32], None);
+        let other_icons = sample_icon_dir(&[48], None);
+        let cursors = sample_icon_dir(&[32], Some((1, 2)));
+        let mut resources =
+            group_resources(&old_icons, ResourceId::Name("APP".into()), 1);
+        resources.extend(group_resources(&other_icons, ResourceId::Id(2), 3));
+        resources.extend(group_resources(&cursors, ResourceId::Id(1), 1));
+        let rsrc = resource_section(&resources, 1);
+        let text = vec![0xc3; 0x80];
+        let pe = build_pe(
+            &[(b".text\0\0\0", text.clone()), (b".rsrc\0\0\0", rsrc)],
+            Some(1),
+        );
+        let mut pe = PeFile::read(pe.as_slice()).unwrap();
+        let new_icons = sample_icon_dir(&[16, 24, 32, 64], None);
+        pe.set_main_icon(&new_icons).unwrap();
+        let (output, pe) = write_and_reread(&pe);
+        assert_eq!(pe.sections.len(), 2);
+        assert_eq!(&output[0x400..0x480], text.as_slice());
+        let groups = pe.icon_groups().unwrap();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].name(), &ResourceId::Id(1));
+        assert_same_entries(groups[0].icon_dir(), &cursors);
+        assert_eq!(groups[1].name(), &ResourceId::Name("APP".into()));
+        assert_eq!(groups[1].language(), 0x409);
+        assert_same_entries(groups[1].icon_dir(), &new_icons);
+        assert_eq!(groups[2].name(), &ResourceId::Id(2));
+        assert_same_entries(groups[2].icon_dir(), &other_icons);
+        // The old members were removed, and the new ones were numbered around
+        // the other group's member (ID 3).
+        let mut icon_ids: Vec<_> = pe
+            .resources
+            .iter()
+            .filter(|resource| resource.restype == ResourceId::Id(3))
+            .map(|resource| resource.name.clone())
+            .collect();
+        icon_ids.sort();
+        let expected: Vec<_> = (3..=7).map(ResourceId::Id).collect();
+        assert_eq!(icon_ids, expected);
+    }
+
+    #[test]
+    fn append_resource_section() {
+        let old_icons = sample_icon_dir(&[16], None);
+        let resources = group_resources(&old_icons, ResourceId::Id(1), 1);
+        let rsrc = resource_section(&resources, 0);
+        let reloc = vec![0x55; 0x20];
+        let mut pe = build_pe(
+            &[(b".rsrc\0\0\0", rsrc), (b".reloc\0\0", reloc)],
+            Some(0),
+        );
+        // Append an overlay, followed by a (fake) signature.
+        let overlay = b"overlay data".to_vec();
+        pe.extend_from_slice(&overlay);
+        let certificate_offset = pe.len() as u32;
+        pe.extend_from_slice(&[0xaa; 16]);
+        let certificate_entry = 0x40 + 24 + 128;
+        pe[certificate_entry..(certificate_entry + 4)]
+            .copy_from_slice(&certificate_offset.to_le_bytes());
+        pe[(certificate_entry + 4)..(certificate_entry + 8)]
+            .copy_from_slice(&16u32.to_le_bytes());
+        let mut pe = PeFile::read(pe.as_slice()).unwrap();
+        let new_icons = sample_icon_dir(&[32, 48], None);
+        pe.set_main_icon(&new_icons).unwrap();
+        let (output, pe) = write_and_reread(&pe);
+        assert_eq!(pe.sections.len(), 3);
+        assert_eq!(pe.sections[2].virtual_address, section_rva(1) + 0x1000);
+        assert!(output.ends_with(&overlay));
+        assert_eq!(
+            &output[certificate_entry..(certificate_entry + 8)],
+            &[0; 8]
+        );
+        let groups = pe.icon_groups().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_same_entries(groups[0].icon_dir(), &new_icons);
+    }
+
+    #[test]
+    fn inject_icon_into_file_without_resources() {
+        let pe = build_pe(&[(b".text\0\0\0", vec![0xc3; 0x10])], None);
+        let mut pe = PeFile::read(pe.as_slice()).unwrap();
+        assert!(pe.icon_groups().unwrap().is_empty());
+        let cursors = sample_icon_dir(&[32], Some((0, 0)));
+        assert!(pe.set_main_icon(&cursors).is_err());
+        let icons = sample_icon_dir(&[16, 32], None);
+        pe.set_main_icon(&icons).unwrap();
+        let (_, pe) = write_and_reread(&pe);
+        assert_eq!(pe.sections.len(), 2);
+        let groups = pe.icon_groups().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name(), &ResourceId::Id(1));
+        assert_eq!(groups[0].language(), 0);
+        assert_same_entries(groups[0].icon_dir(), &icons);
+    }
+
+    #[test]
+    fn read_and_write_pe32_plus() {
+        let old_icons = sample_icon_dir(&[16], None);
+        let resources = group_resources(&old_icons, ResourceId::Id(1), 1);
+        let rsrc = resource_section(&resources, 1);
+        let text = vec![0xc3; 0x40];
+        let pe = build_pe_with_magic(
+            0x20b,
+            &[(b".text\0\0\0", text.clone()), (b".rsrc\0\0\0", rsrc)],
+            Some(1),
+        );
+        let mut pe = PeFile::read(pe.as_slice()).unwrap();
+        assert_eq!(pe.directories_offset, pe.optional_offset + 112);
+        let groups = pe.icon_groups().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_same_entries(groups[0].icon_dir(), &old_icons);
+
+        let new_icons = sample_icon_dir(&[16, 32, 48], None);
+        pe.set_main_icon(&new_icons).unwrap();
+        let (output, pe) = write_and_reread(&pe);
+        let magic = pe.optional_offset;
+        assert_eq!(&output[magic..(magic + 2)], &[0x0b, 0x02]);
+        assert_eq!(&output[0x400..0x440], text.as_slice());
+        assert_eq!(pe.sections.len(), 2);
+        let groups = pe.icon_groups().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name(), &ResourceId::Id(1));
+        assert_same_entries(groups[0].icon_dir(), &new_icons);
+    }
+}
+
+//===========================================================================//
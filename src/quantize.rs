@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+
+//===========================================================================//
+
+/// The standard 16-color palette used by Windows for 4-bpp images, in its
+/// usual order (the CGA/VGA colors).
+pub const WINDOWS_VGA_PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0x80, 0x00, 0x00),
+    (0x00, 0x80, 0x00),
+    (0x80, 0x80, 0x00),
+    (0x00, 0x00, 0x80),
+    (0x80, 0x00, 0x80),
+    (0x00, 0x80, 0x80),
+    (0xc0, 0xc0, 0xc0),
+    (0x80, 0x80, 0x80),
+    (0xff, 0x00, 0x00),
+    (0x00, 0xff, 0x00),
+    (0xff, 0xff, 0x00),
+    (0x00, 0x00, 0xff),
+    (0xff, 0x00, 0xff),
+    (0x00, 0xff, 0xff),
+    (0xff, 0xff, 0xff),
+];
+
+//===========================================================================//
+
+/// A method for choosing a limited color palette for an image.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Quantizer {
+    /// Build an adaptive palette by repeatedly splitting the image's colors
+    /// at the median of their widest channel
+    #[default]
+    MedianCut,
+    /// Build an adaptive palette from the image's most frequently-used colors
+    /// (which is fast, but tends to lose rare colors entirely)
+    Popularity,
+    /// Use the fixed `WINDOWS_VGA_PALETTE` (or, if only two colors are
+    /// allowed, just black and white)
+    WindowsVga,
+}
+
+impl Quantizer {
+    /// Chooses a palette of at most `max_colors` colors (which must be
+    /// nonzero) for the opaque pixels in the given RGBA data.  The returned
+    /// palette is never empty.
+    pub(crate) fn palette(
+        &self,
+        rgba: &[u8],
+        max_colors: usize,
+    ) -> Vec<(u8, u8, u8)> {
+        match *self {
+            Quantizer::MedianCut => median_cut_palette(rgba, max_colors),
+            Quantizer::Popularity => popularity_palette(rgba, max_colors),
+            Quantizer::WindowsVga => {
+                if max_colors >= WINDOWS_VGA_PALETTE.len() {
+                    WINDOWS_VGA_PALETTE.to_vec()
+                } else if max_colors >= 2 {
+                    vec![WINDOWS_VGA_PALETTE[0], WINDOWS_VGA_PALETTE[15]]
+                } else {
+                    vec![WINDOWS_VGA_PALETTE[0]]
+                }
+            }
+        }
+    }
+}
+
+//===========================================================================//
+
+/// A method for hiding the banding that results from reducing an image to a
+/// limited color palette.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Dithering {
+    /// Map each pixel to the nearest palette color, with no dithering
+    #[default]
+    None,
+    /// Diffuse each pixel's quantization error into its neighbors, using
+    /// Floyd-Steinberg error diffusion
+    FloydSteinberg,
+    /// Offset each pixel by a threshold from a 4x4 Bayer matrix before
+    /// mapping it to the palette, producing a regular cross-hatch pattern
+    /// (which tends to compress better than error diffusion)
+    Bayer,
+}
+
+// The 4x4 Bayer threshold matrix, with values from 0 to 15.
+const BAYER_4X4: [[i32; 4]; 4] =
+    [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+//===========================================================================//
+
+/// Counts how many times each color appears among the opaque pixels in the
+/// given RGBA data, and returns the counts sorted by color.
+fn color_counts(rgba: &[u8]) -> Vec<((u8, u8, u8), usize)> {
+    let mut counts = HashMap::<(u8, u8, u8), usize>::new();
+    for pixel in rgba.chunks_exact(4) {
+        if pixel[3] != 0 {
+            *counts.entry((pixel[0], pixel[1], pixel[2])).or_insert(0) += 1;
+        }
+    }
+    let mut colors: Vec<((u8, u8, u8), usize)> = counts.into_iter().collect();
+    colors.sort();
+    colors
+}
+
+/// Returns the red (0), green (1), or blue (2) component of a color.
+fn channel_value(color: (u8, u8, u8), channel: usize) -> u8 {
+    match channel {
+        0 => color.0,
+        1 => color.1,
+        _ => color.2,
+    }
+}
+
+/// Returns the channel with the widest range of values among the given
+/// colors, along with the size of that range.
+fn widest_channel(colors: &[((u8, u8, u8), usize)]) -> (usize, u8) {
+    let mut widest = (0, 0);
+    for channel in 0..3 {
+        let values =
+            colors.iter().map(|&(color, _)| channel_value(color, channel));
+        let min = values.clone().min().unwrap_or(0);
+        let max = values.max().unwrap_or(0);
+        if max - min > widest.1 {
+            widest = (channel, max - min);
+        }
+    }
+    widest
+}
+
+/// Chooses a palette of at most `max_colors` colors for the opaque pixels in
+/// the given RGBA data, using the median cut algorithm.  If the image already
+/// has few enough colors, they are returned exactly.  The returned palette is
+/// never empty.
+pub(crate) fn median_cut_palette(
+    rgba: &[u8],
+    max_colors: usize,
+) -> Vec<(u8, u8, u8)> {
+    debug_assert!(max_colors > 0);
+    let colors = color_counts(rgba);
+    if colors.is_empty() {
+        return vec![(0, 0, 0)];
+    }
+    if colors.len() <= max_colors {
+        return colors.into_iter().map(|(color, _)| color).collect();
+    }
+    let mut boxes = vec![colors];
+    while boxes.len() < max_colors {
+        // Split the box with the widest range of values in any one channel.
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(index, colors)| (index, widest_channel(colors)))
+            .max_by_key(|&(index, (_, range))| (range, usize::MAX - index));
+        let (index, channel) = match widest {
+            Some((index, (channel, _))) => (index, channel),
+            None => break,
+        };
+        let mut lower = boxes.remove(index);
+        lower.sort_by_key(|&(color, _)| channel_value(color, channel));
+        // Split at the median pixel (rather than the median color), so that
+        // heavily-used colors get more of the palette.
+        let total: usize = lower.iter().map(|&(_, count)| count).sum();
+        let mut split = 1;
+        let mut sum = 0;
+        for (position, &(_, count)) in lower.iter().enumerate() {
+            sum += count;
+            if 2 * sum >= total {
+                split = position + 1;
+                break;
+            }
+        }
+        let split = split.clamp(1, lower.len() - 1);
+        let upper = lower.split_off(split);
+        boxes.insert(index, upper);
+        boxes.insert(index, lower);
+    }
+    boxes
+        .iter()
+        .map(|colors| {
+            let total: usize = colors.iter().map(|&(_, count)| count).sum();
+            let average = |channel: usize| {
+                let sum: usize = colors
+                    .iter()
+                    .map(|&(color, count)| {
+                        channel_value(color, channel) as usize * count
+                    })
+                    .sum();
+                ((sum + total / 2) / total) as u8
+            };
+            (average(0), average(1), average(2))
+        })
+        .collect()
+}
+
+/// Chooses a palette of at most `max_colors` colors for the opaque pixels in
+/// the given RGBA data, by picking the most frequently-used colors.  The
+/// returned palette is never empty.
+pub(crate) fn popularity_palette(
+    rgba: &[u8],
+    max_colors: usize,
+) -> Vec<(u8, u8, u8)> {
+    debug_assert!(max_colors > 0);
+    let mut colors = color_counts(rgba);
+    // Sort by descending frequency, breaking ties by color so that the result
+    // is deterministic.
+    colors.sort_by(|&(color1, count1), &(color2, count2)| {
+        count2.cmp(&count1).then(color1.cmp(&color2))
+    });
+    let mut palette: Vec<(u8, u8, u8)> =
+        colors.into_iter().take(max_colors).map(|(color, _)| color).collect();
+    if palette.is_empty() {
+        palette.push((0, 0, 0));
+    }
+    palette
+}
+
+/// Returns the index of the palette color closest to the given color.
+pub(crate) fn nearest_color(
+    palette: &[(u8, u8, u8)],
+    color: (u8, u8, u8),
+) -> usize {
+    let distance = |&(red, green, blue): &(u8, u8, u8)| {
+        let dr = (red as i32) - (color.0 as i32);
+        let dg = (green as i32) - (color.1 as i32);
+        let db = (blue as i32) - (color.2 as i32);
+        dr * dr + dg * dg + db * db
+    };
+    let mut best_index = 0;
+    let mut best_distance = i32::MAX;
+    for (index, entry) in palette.iter().enumerate() {
+        let dist = distance(entry);
+        if dist < best_distance {
+            best_index = index;
+            best_distance = dist;
+        }
+    }
+    best_index
+}
+
+/// Maps each pixel in the given RGBA data to the index of the nearest color
+/// in the palette.
+pub(crate) fn map_to_palette(
+    rgba: &[u8],
+    palette: &[(u8, u8, u8)],
+) -> Vec<u8> {
+    let mut cache = HashMap::<(u8, u8, u8), u8>::new();
+    rgba.chunks_exact(4)
+        .map(|pixel| {
+            let color = (pixel[0], pixel[1], pixel[2]);
+            *cache
+                .entry(color)
+                .or_insert_with(|| nearest_color(palette, color) as u8)
+        })
+        .collect()
+}
+
+/// Maps each pixel in the given RGBA data (for an image `width` pixels wide)
+/// to the index of a palette color, using the given dithering method.
+/// Fully transparent pixels are mapped to the nearest color, but never
+/// dithered; nor do they receive or pass on any diffused error, so that the
+/// hidden colors of transparent areas don't bleed into visible ones.
+pub(crate) fn dither_to_palette(
+    rgba: &[u8],
+    width: usize,
+    palette: &[(u8, u8, u8)],
+    dithering: Dithering,
+) -> Vec<u8> {
+    match dithering {
+        Dithering::None => map_to_palette(rgba, palette),
+        Dithering::FloydSteinberg => floyd_steinberg(rgba, width, palette),
+        Dithering::Bayer => bayer(rgba, width, palette),
+    }
+}
+
+fn floyd_steinberg(
+    rgba: &[u8],
+    width: usize,
+    palette: &[(u8, u8, u8)],
+) -> Vec<u8> {
+    let num_pixels = rgba.len() / 4;
+    let is_opaque = |index: usize| rgba[4 * index + 3] != 0;
+    // The accumulated error for each pixel and channel, in sixteenths:
+    let mut errors = vec![[0i32; 3]; num_pixels];
+    let mut indices = Vec::with_capacity(num_pixels);
+    for (index, pixel) in rgba.chunks_exact(4).enumerate() {
+        if !is_opaque(index) {
+            indices
+                .push(nearest_color(palette, (pixel[0], pixel[1], pixel[2]))
+                    as u8);
+            continue;
+        }
+        let mut color = [0i32; 3];
+        for channel in 0..3 {
+            let value = pixel[channel] as i32 + errors[index][channel] / 16;
+            color[channel] = value.clamp(0, 255);
+        }
+        let nearest = nearest_color(
+            palette,
+            (color[0] as u8, color[1] as u8, color[2] as u8),
+        );
+        indices.push(nearest as u8);
+        let (red, green, blue) = palette[nearest];
+        let error = [
+            color[0] - red as i32,
+            color[1] - green as i32,
+            color[2] - blue as i32,
+        ];
+        let (x, y) = (index % width, index / width);
+        let mut diffuse = |x: usize, y: usize, weight: i32| {
+            let neighbor = y * width + x;
+            if x < width && neighbor < num_pixels && is_opaque(neighbor) {
+                for channel in 0..3 {
+                    errors[neighbor][channel] += weight * error[channel];
+                }
+            }
+        };
+        diffuse(x + 1, y, 7);
+        if x > 0 {
+            diffuse(x - 1, y + 1, 3);
+        }
+        diffuse(x, y + 1, 5);
+        diffuse(x + 1, y + 1, 1);
+    }
+    indices
+}
+
+fn bayer(rgba: &[u8], width: usize, palette: &[(u8, u8, u8)]) -> Vec<u8> {
+    // Spread the thresholds over roughly the distance between neighboring
+    // palette colors, assuming they're evenly distributed.
+    let spread = (255.0 / (palette.len() as f64).cbrt()) as i32;
+    rgba.chunks_exact(4)
+        .enumerate()
+        .map(|(index, pixel)| {
+            let offset = if pixel[3] == 0 {
+                0
+            } else {
+                let (x, y) = (index % width, index / width);
+                (2 * BAYER_4X4[y % 4][x % 4] + 1 - 16) * spread / 32
+            };
+            let channel =
+                |value: u8| (value as i32 + offset).clamp(0, 255) as u8;
+            let color =
+                (channel(pixel[0]), channel(pixel[1]), channel(pixel[2]));
+            nearest_color(palette, color) as u8
+        })
+        .collect()
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dither_to_palette, map_to_palette, median_cut_palette, nearest_color,
+        popularity_palette, Dithering, Quantizer, WINDOWS_VGA_PALETTE,
+    };
+
+    #[test]
+    fn popularity_palette_prefers_common_colors() {
+        let rgba: &[u8] = &[
+            1, 1, 1, 255, 2, 2, 2, 255, 2, 2, 2, 255, 3, 3, 3, 255, 3, 3, 3,
+            255, 3, 3, 3, 255, 4, 4, 4, 0, 4, 4, 4, 0, 4, 4, 4, 0, 4, 4, 4, 0,
+        ];
+        let palette = popularity_palette(rgba, 2);
+        assert_eq!(palette, vec![(3, 3, 3), (2, 2, 2)]);
+        assert_eq!(nearest_color(&palette, (1, 1, 1)), 1);
+        assert_eq!(nearest_color(&palette, (9, 9, 9)), 0);
+    }
+
+    #[test]
+    fn median_cut_palette_keeps_exact_colors_when_possible() {
+        let rgba: &[u8] = &[9, 9, 9, 255, 1, 2, 3, 255, 9, 9, 9, 255];
+        assert_eq!(median_cut_palette(rgba, 2), vec![(1, 2, 3), (9, 9, 9)]);
+        assert_eq!(median_cut_palette(&[], 2), vec![(0, 0, 0)]);
+    }
+
+    #[test]
+    fn median_cut_palette_splits_clusters() {
+        // Two clusters of reds and blues; with two colors, each cluster
+        // should get its own (averaged) palette entry, however uneven their
+        // pixel counts are.
+        let rgba: &[u8] = &[
+            200, 0, 0, 255, 202, 0, 0, 255, 204, 0, 0, 255, 0, 0, 100, 255, 0,
+            0, 104, 255, 0, 0, 104, 255, 0, 0, 104, 255, 0, 0, 104, 255, 0, 0,
+            104, 255, 0, 0, 104, 255, 50, 50, 50, 0,
+        ];
+        let palette = median_cut_palette(rgba, 2);
+        assert_eq!(palette, vec![(0, 0, 103), (202, 0, 0)]);
+        assert_eq!(map_to_palette(&rgba[..8], &palette), vec![1, 1]);
+    }
+
+    #[test]
+    fn windows_vga_palette() {
+        let palette = Quantizer::WindowsVga.palette(&[], 256);
+        assert_eq!(palette, WINDOWS_VGA_PALETTE.to_vec());
+        let palette = Quantizer::WindowsVga.palette(&[], 2);
+        assert_eq!(palette, vec![(0, 0, 0), (255, 255, 255)]);
+    }
+
+    #[test]
+    fn floyd_steinberg_dithers_gray_to_black_and_white() {
+        let palette = [(0, 0, 0), (255, 255, 255)];
+        let rgba = [128, 128, 128, 255].repeat(4 * 4);
+        let indices =
+            dither_to_palette(&rgba, 4, &palette, Dithering::FloydSteinberg);
+        let num_white = indices.iter().filter(|&&index| index == 1).count();
+        assert_eq!(num_white, 8);
+        let indices = dither_to_palette(&rgba, 4, &palette, Dithering::None);
+        assert!(indices.iter().all(|&index| index == 1));
+    }
+
+    #[test]
+    fn floyd_steinberg_ignores_transparent_pixels() {
+        // The hidden colors of transparent pixels must not affect how the
+        // opaque pixels are dithered.
+        let palette = [(0, 0, 0), (255, 255, 255)];
+        let checkerboard = |hidden: u8| {
+            let mut rgba = Vec::new();
+            for index in 0..16 {
+                if (index + index / 4) % 2 == 0 {
+                    rgba.extend_from_slice(&[hidden, hidden, hidden, 0]);
+                } else {
+                    rgba.extend_from_slice(&[100, 100, 100, 255]);
+                }
+            }
+            dither_to_palette(&rgba, 4, &palette, Dithering::FloydSteinberg)
+        };
+        let dark = checkerboard(0);
+        let light = checkerboard(255);
+        for index in 0..16 {
+            if (index + index / 4) % 2 == 0 {
+                assert_eq!((dark[index], light[index]), (0, 1));
+            } else {
+                assert_eq!(dark[index], light[index]);
+            }
+        }
+    }
+
+    #[test]
+    fn bayer_dithers_gray_to_black_and_white() {
+        let palette = [(0, 0, 0), (255, 255, 255)];
+        let rgba = [128, 128, 128, 255].repeat(4 * 4);
+        let indices = dither_to_palette(&rgba, 4, &palette, Dithering::Bayer);
+        let expected = vec![0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0];
+        assert_eq!(indices, expected);
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,378 @@
+use crate::icondir::IconDir;
+use crate::resource::{
+    self, IconGroup, Resource, ResourceId, ResourceOptions, RT_CURSOR, RT_ICON,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+//===========================================================================//
+
+// Resource memory flags.  These are ignored by modern versions of Windows,
+// but resource compilers still set them.
+const MOVEABLE: u16 = 0x0010;
+const PURE: u16 = 0x0020;
+const DISCARDABLE: u16 = 0x1000;
+
+//===========================================================================//
+
+/// A collection of Windows resources; the contents of a 32-bit .res file, as
+/// produced by resource compilers and consumed by linkers.
+///
+/// Only icon and cursor resources can be created or decoded, but resources of
+/// other types are preserved when reading and writing.
+#[derive(Clone, Debug, Default)]
+pub struct ResFile {
+    resources: Vec<Resource>,
+}
+
+impl ResFile {
+    /// Creates a new, empty resource file.
+    pub fn new() -> ResFile {
+        ResFile::default()
+    }
+
+    /// Returns every icon and cursor group in the file, each reconstructed as
+    /// an `IconDir` from its RT_ICON or RT_CURSOR members.  Returns an error
+    /// if any group is malformed or refers to a missing member.
+    pub fn icon_groups(&self) -> io::Result<Vec<IconGroup>> {
+        IconGroup::read_all(&self.resources)
+    }
+
+    /// Adds a collection of icons (or cursors) to the file, as an
+    /// RT_GROUP_ICON (or RT_GROUP_CURSOR) resource with one RT_ICON (or
+    /// RT_CURSOR) resource per entry, using the resource names and language
+    /// given by the options.  An existing group with the same name and
+    /// language is replaced, along with its members.  Returns an error if the
+    /// member IDs would be out of range, or if another group already uses any
+    /// of them.
+    pub fn add_icon_dir(
+        &mut self,
+        icon_dir: &IconDir,
+        options: &ResourceOptions,
+    ) -> io::Result<()> {
+        resource::replace_icon_group(&mut self.resources, icon_dir, options)
+    }
+
+    /// Reads a .res file into memory.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<ResFile> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut remaining = data.as_slice();
+        let mut resources = Vec::new();
+        while !remaining.is_empty() {
+            let data_size = remaining.read_u32::<LittleEndian>()? as usize;
+            let header_size = remaining.read_u32::<LittleEndian>()? as usize;
+            if header_size < 24 || header_size - 8 > remaining.len() {
+                invalid_data!(
+                    "Invalid resource header size ({})",
+                    header_size
+                );
+            }
+            let mut header = &remaining[..(header_size - 8)];
+            remaining = &remaining[(header_size - 8)..];
+            let restype = read_name(&mut header)?;
+            let name = read_name(&mut header)?;
+            // Skip padding, and then the fixed-size fields that follow it.
+            if header.len() < 16 {
+                invalid_data!("Resource header is truncated");
+            }
+            header = &header[(header.len() - 16)..];
+            let _data_version = header.read_u32::<LittleEndian>()?;
+            let _memory_flags = header.read_u16::<LittleEndian>()?;
+            let language = header.read_u16::<LittleEndian>()?;
+            if data_size > remaining.len() {
+                invalid_data!(
+                    "Resource data is truncated (size is {}, but only {} \
+                     bytes remain)",
+                    data_size,
+                    remaining.len()
+                );
+            }
+            let resource_data = remaining[..data_size].to_vec();
+            let padded_size = (data_size + 3) & !3;
+            remaining = &remaining[padded_size.min(remaining.len())..];
+            // The file starts with an empty entry, which marks it as a 32-bit
+            // (rather than 16-bit) .res file.
+            if restype == ResourceId::Id(0) {
+                continue;
+            }
+            resources.push(Resource {
+                restype,
+                name,
+                language,
+                data: resource_data,
+            });
+        }
+        Ok(ResFile { resources })
+    }
+
+    /// Writes a .res file out to disk.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let empty = Resource {
+            restype: ResourceId::Id(0),
+            name: ResourceId::Id(0),
+            language: 0,
+            data: Vec::new(),
+        };
+        write_resource(&mut writer, &empty, 0)?;
+        for resource in self.resources.iter() {
+            let memory_flags = match resource.restype {
+                ResourceId::Id(RT_ICON) | ResourceId::Id(RT_CURSOR) => {
+                    MOVEABLE | DISCARDABLE
+                }
+                _ => MOVEABLE | PURE | DISCARDABLE,
+            };
+            write_resource(&mut writer, resource, memory_flags)?;
+        }
+        Ok(())
+    }
+}
+
+//===========================================================================//
+
+/// Reads a resource type or name, which is either 0xffff followed by a
+/// 16-bit ID, or a null-terminated UTF-16 string.
+fn read_name(reader: &mut &[u8]) -> io::Result<ResourceId> {
+    let first = reader.read_u16::<LittleEndian>()?;
+    if first == 0xffff {
+        return Ok(ResourceId::Id(reader.read_u16::<LittleEndian>()?));
+    }
+    let mut units = Vec::new();
+    let mut unit = first;
+    while unit != 0 {
+        units.push(unit);
+        unit = reader.read_u16::<LittleEndian>()?;
+    }
+    Ok(ResourceId::Name(String::from_utf16_lossy(&units)))
+}
+
+fn write_name(output: &mut Vec<u8>, id: &ResourceId) -> io::Result<()> {
+    match id {
+        ResourceId::Id(id) => {
+            output.write_u16::<LittleEndian>(0xffff)?;
+            output.write_u16::<LittleEndian>(*id)?;
+        }
+        ResourceId::Name(name) => {
+            for unit in name.encode_utf16() {
+                output.write_u16::<LittleEndian>(unit)?;
+            }
+            output.write_u16::<LittleEndian>(0)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes one resource header and its data, each padded to a multiple of
+/// four bytes.
+fn write_resource<W: Write>(
+    writer: &mut W,
+    resource: &Resource,
+    memory_flags: u16,
+) -> io::Result<()> {
+    let mut names = Vec::new();
+    write_name(&mut names, &resource.restype)?;
+    write_name(&mut names, &resource.name)?;
+    while names.len() % 4 != 0 {
+        names.push(0);
+    }
+    if resource.data.len() > u32::MAX as usize {
+        invalid_input!(
+            "Resource is too large ({} bytes)",
+            resource.data.len()
+        );
+    }
+    writer.write_u32::<LittleEndian>(resource.data.len() as u32)?;
+    writer.write_u32::<LittleEndian>(8 + names.len() as u32 + 16)?;
+    writer.write_all(&names)?;
+    writer.write_u32::<LittleEndian>(0)?; // data version
+    writer.write_u16::<LittleEndian>(memory_flags)?;
+    writer.write_u16::<LittleEndian>(resource.language)?;
+    writer.write_u32::<LittleEndian>(0)?; // version
+    writer.write_u32::<LittleEndian>(0)?; // characteristics
+    writer.write_all(&resource.data)?;
+    let padding = (4 - resource.data.len() % 4) % 4;
+    writer.write_all(&[0u8; 3][..padding])?;
+    Ok(())
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::ResFile;
+    use crate::icondir::{IconDir, IconDirEntry};
+    use crate::image::IconImage;
+    use crate::resource::{ResourceId, ResourceOptions};
+    use crate::restype::ResourceType;
+
+    fn sample_icon_dir(hotspot: Option<(u16, u16)>) -> IconDir {
+        let restype = if hotspot.is_some() {
+            ResourceType::Cursor
+        } else {
+            ResourceType::Icon
+        };
+        let mut icon_dir = IconDir::new(restype);
+        for &size in [16, 48, 256].iter() {
+            let mut image = IconImage::from_rgba_data(
+                size,
+                size,
+                vec![size as u8; (4 * size * size) as usize],
+            );
+            image.set_cursor_hotspot(hotspot);
+            icon_dir.add_entry(IconDirEntry::encode(&image).unwrap());
+        }
+        icon_dir
+    }
+
+    #[test]
+    fn write_icon_group() {
+        let mut icon_dir = IconDir::new(ResourceType::Icon);
+        let image = IconImage::from_rgba_data(1, 1, vec![0, 0, 0, 255]);
+        icon_dir.add_entry(IconDirEntry::encode(&image).unwrap());
+        let mut options = ResourceOptions::new();
+        options.set_first_member_id(5);
+        options.set_language(0x0409);
+        let mut res_file = ResFile::new();
+        res_file.add_icon_dir(&icon_dir, &options).unwrap();
+        let mut output = Vec::<u8>::new();
+        res_file.write(&mut output).unwrap();
+        let data_len = icon_dir.entries()[0].data().len();
+        let padded_len = (data_len + 3) & !3;
+        let expected_header: &[u8] = &[
+            0, 0, 0, 0, 32, 0, 0, 0, 0xff, 0xff, 0, 0, 0xff, 0xff, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(&output[..32], expected_header);
+        let icon = &output[32..];
+        assert_eq!(&icon[..4], &(data_len as u32).to_le_bytes());
+        assert_eq!(
+            &icon[4..16],
+            &[32, 0, 0, 0, 0xff, 0xff, 3, 0, 0xff, 0xff, 5, 0]
+        );
+        assert_eq!(&icon[20..24], &[0x10, 0x10, 0x09, 0x04]);
+        let group = &icon[(32 + padded_len)..];
+        let expected_group: &[u8] = &[
+            20, 0, 0, 0, 32, 0, 0, 0, 0xff, 0xff, 14, 0, 0xff, 0xff, 1, 0, 0,
+            0, 0, 0, 0x30, 0x10, 0x09, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+            0, 1, 0, 1, 1, 2, 0, 1, 0, 1, 0,
+        ];
+        assert_eq!(&group[..46], expected_group);
+        assert_eq!(&group[46..50], &(data_len as u32).to_le_bytes());
+        assert_eq!(&group[50..], &[5, 0]);
+    }
+
+    #[test]
+    fn res_file_round_trip() {
+        let icons = sample_icon_dir(None);
+        let cursors = sample_icon_dir(Some((7, 9)));
+        let mut res_file = ResFile::new();
+        let mut options = ResourceOptions::new();
+        options.set_group_name(ResourceId::Name("APPICON".to_string()));
+        res_file.add_icon_dir(&icons, &options).unwrap();
+        options.set_group_name(ResourceId::Id(2));
+        options.set_first_member_id(10);
+        options.set_language(0x0407);
+        res_file.add_icon_dir(&cursors, &options).unwrap();
+        let mut output = Vec::<u8>::new();
+        res_file.write(&mut output).unwrap();
+
+        let res_file = ResFile::read(output.as_slice()).unwrap();
+        let groups = res_file.icon_groups().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].name(), &ResourceId::Name("APPICON".to_string()));
+        assert_eq!(groups[0].language(), 0);
+        assert_eq!(groups[1].name(), &ResourceId::Id(2));
+        assert_eq!(groups[1].language(), 0x0407);
+        for (group, expected) in groups.iter().zip([&icons, &cursors]) {
+            let icon_dir = group.icon_dir();
+            assert_eq!(icon_dir.resource_type(), expected.resource_type());
+            assert_eq!(icon_dir.entries().len(), expected.entries().len());
+            for (entry, expected) in
+                icon_dir.entries().iter().zip(expected.entries())
+            {
+                assert_eq!(entry.width(), expected.width());
+                assert_eq!(entry.height(), expected.height());
+                assert_eq!(entry.bits_per_pixel(), expected.bits_per_pixel());
+                assert_eq!(entry.cursor_hotspot(), expected.cursor_hotspot());
+                assert_eq!(entry.data(), expected.data());
+            }
+        }
+
+        let mut rewritten = Vec::<u8>::new();
+        res_file.write(&mut rewritten).unwrap();
+        assert_eq!(rewritten, output);
+    }
+
+    #[test]
+    fn replace_icon_group() {
+        let mut res_file = ResFile::new();
+        let options = ResourceOptions::new();
+        res_file.add_icon_dir(&sample_icon_dir(None), &options).unwrap();
+        let mut icon_dir = IconDir::new(ResourceType::Icon);
+        let image = IconImage::from_rgba_data(8, 8, vec![0; 4 * 8 * 8]);
+        icon_dir.add_entry(IconDirEntry::encode(&image).unwrap());
+        res_file.add_icon_dir(&icon_dir, &options).unwrap();
+        let groups = res_file.icon_groups().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].icon_dir().entries().len(), 1);
+        assert_eq!(groups[0].icon_dir().entries()[0].width(), 8);
+        assert_eq!(res_file.resources.len(), 2);
+    }
+
+    #[test]
+    fn reject_colliding_member_ids() {
+        let mut res_file = ResFile::new();
+        let mut options = ResourceOptions::new();
+        res_file.add_icon_dir(&sample_icon_dir(None), &options).unwrap();
+        // The first group's members have IDs 1 through 3, so a second group
+        // starting at ID 3 would clobber one of them.
+        options.set_group_name(ResourceId::Id(2));
+        options.set_first_member_id(3);
+        assert!(res_file
+            .add_icon_dir(&sample_icon_dir(None), &options)
+            .is_err());
+        assert_eq!(res_file.resources.len(), 4);
+        // Members in another language don't collide.
+        options.set_language(0x0409);
+        res_file.add_icon_dir(&sample_icon_dir(None), &options).unwrap();
+        options.set_first_member_id(4);
+        options.set_language(0);
+        res_file.add_icon_dir(&sample_icon_dir(None), &options).unwrap();
+        assert_eq!(res_file.icon_groups().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn replace_icon_group_keeps_shared_members() {
+        let mut res_file = ResFile::new();
+        let mut options = ResourceOptions::new();
+        res_file.add_icon_dir(&sample_icon_dir(None), &options).unwrap();
+        // Add a second group that uses the same members as the first.
+        let mut shared = res_file.resources[3].clone();
+        shared.name = ResourceId::Id(2);
+        res_file.resources.push(shared);
+        let mut icon_dir = IconDir::new(ResourceType::Icon);
+        let image = IconImage::from_rgba_data(8, 8, vec![0; 4 * 8 * 8]);
+        icon_dir.add_entry(IconDirEntry::encode(&image).unwrap());
+        options.set_first_member_id(10);
+        res_file.add_icon_dir(&icon_dir, &options).unwrap();
+        let groups = res_file.icon_groups().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].name(), &ResourceId::Id(2));
+        assert_eq!(groups[0].icon_dir().entries().len(), 3);
+        assert_eq!(groups[1].name(), &ResourceId::Id(1));
+        assert_eq!(groups[1].icon_dir().entries().len(), 1);
+    }
+
+    #[test]
+    fn reject_truncated_res_file() {
+        let mut res_file = ResFile::new();
+        let options = ResourceOptions::new();
+        res_file.add_icon_dir(&sample_icon_dir(None), &options).unwrap();
+        let mut output = Vec::<u8>::new();
+        res_file.write(&mut output).unwrap();
+        output.truncate(output.len() - 100);
+        assert!(ResFile::read(output.as_slice()).is_err());
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,448 @@
+use crate::image::IconImage;
+use std::f64::consts::PI;
+
+//===========================================================================//
+
+/// A resampling filter for resizing images.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ResizeFilter {
+    /// Use the nearest source pixel (fast and blocky; no new colors)
+    Nearest,
+    /// Average the source pixels covered by each destination pixel (good
+    /// for shrinking by large factors)
+    Box,
+    /// Linearly interpolate between neighboring source pixels (the
+    /// "triangle" filter)
+    Bilinear,
+    /// Cubic interpolation using the Catmull-Rom spline (sharper than
+    /// bilinear)
+    Bicubic,
+    /// Windowed sinc interpolation with three lobes (the sharpest of these
+    /// filters, and a good default for downscaling icons)
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    /// Returns the filter's kernel function and the radius (in source pixels,
+    /// at 1:1 scale) beyond which the kernel is zero.
+    fn kernel(self) -> (fn(f64) -> f64, f64) {
+        match self {
+            ResizeFilter::Nearest | ResizeFilter::Box => (box_kernel, 0.5),
+            ResizeFilter::Bilinear => (triangle, 1.0),
+            ResizeFilter::Bicubic => (catmull_rom, 2.0),
+            ResizeFilter::Lanczos3 => (lanczos3, 3.0),
+        }
+    }
+}
+
+fn box_kernel(x: f64) -> f64 {
+    if x.abs() <= 0.5 {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn triangle(x: f64) -> f64 {
+    (1.0 - x.abs()).max(0.0)
+}
+
+/// The Catmull-Rom cubic (the Keys cubic with a = -0.5).
+fn catmull_rom(x: f64) -> f64 {
+    let x = x.abs();
+    if x < 1.0 {
+        (1.5 * x - 2.5) * x * x + 1.0
+    } else if x < 2.0 {
+        ((-0.5 * x + 2.5) * x - 4.0) * x + 2.0
+    } else {
+        0.0
+    }
+}
+
+/// The Lanczos kernel with a = 3.
+fn lanczos3(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else if x.abs() < 3.0 {
+        let px = PI * x;
+        3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+    } else {
+        0.0
+    }
+}
+
+//===========================================================================//
+
+/// The source pixels (and their weights) that contribute to one destination
+/// pixel along one axis.
+struct Contribution {
+    start: usize,
+    weights: Vec<f64>,
+}
+
+/// Computes the contributions of source pixels to each destination pixel
+/// when resampling `src_len` pixels into `dst_len` pixels.
+fn contributions(
+    src_len: usize,
+    dst_len: usize,
+    kernel: fn(f64) -> f64,
+    support: f64,
+) -> Vec<Contribution> {
+    let scale = src_len as f64 / dst_len as f64;
+    // When shrinking, stretch the kernel to cover every source pixel, so
+    // that it acts as a low-pass filter.
+    let kernel_scale = scale.max(1.0);
+    let radius = support * kernel_scale;
+    (0..dst_len)
+        .map(|dst| {
+            let center = (dst as f64 + 0.5) * scale;
+            let start = (center - radius).floor().max(0.0) as usize;
+            let end = ((center + radius).ceil() as usize).min(src_len);
+            let mut weights: Vec<f64> = (start..end)
+                .map(|src| kernel((src as f64 + 0.5 - center) / kernel_scale))
+                .collect();
+            let total: f64 = weights.iter().sum();
+            if total != 0.0 {
+                for weight in weights.iter_mut() {
+                    *weight /= total;
+                }
+            }
+            Contribution { start, weights }
+        })
+        .collect()
+}
+
+/// The layout of the pixels for one resampling pass: the input has `lines`
+/// lines of `src_len` pixels each, where consecutive pixels along a line are
+/// `step` apart and consecutive lines are `line_step` apart (and likewise
+/// for the output, which uses `dst_step` and `dst_line_step`).
+struct Axis {
+    src_len: usize,
+    dst_len: usize,
+    lines: usize,
+    step: usize,
+    line_step: usize,
+    dst_step: usize,
+    dst_line_step: usize,
+}
+
+/// Resamples premultiplied RGBA pixels along one axis.
+fn resample_axis(
+    pixels: &[[f64; 4]],
+    axis: &Axis,
+    kernel: fn(f64) -> f64,
+    support: f64,
+) -> Vec<[f64; 4]> {
+    let contributions =
+        contributions(axis.src_len, axis.dst_len, kernel, support);
+    let mut output = vec![[0.0; 4]; axis.dst_len * axis.lines];
+    for line in 0..axis.lines {
+        for (dst, contribution) in contributions.iter().enumerate() {
+            let mut sum = [0.0; 4];
+            for (offset, &weight) in contribution.weights.iter().enumerate() {
+                let src = line * axis.line_step
+                    + (contribution.start + offset) * axis.step;
+                for channel in 0..4 {
+                    sum[channel] += weight * pixels[src][channel];
+                }
+            }
+            output[line * axis.dst_line_step + dst * axis.dst_step] = sum;
+        }
+    }
+    output
+}
+
+/// Resizes an image to the given (nonzero) dimensions using the given
+/// filter.  The resampling is done in premultiplied alpha, so that the colors
+/// of fully transparent pixels don't bleed into their neighbors.  The cursor
+/// hotspot (if any) is scaled along with the image.
+pub(crate) fn resize(
+    image: &IconImage,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+) -> IconImage {
+    if filter == ResizeFilter::Nearest {
+        return resize_nearest(image, width, height);
+    }
+    let (kernel, support) = filter.kernel();
+    let (src_width, src_height) =
+        (image.width() as usize, image.height() as usize);
+    let (dst_width, dst_height) = (width as usize, height as usize);
+    let premultiplied: Vec<[f64; 4]> = image
+        .rgba_data()
+        .chunks_exact(4)
+        .map(|pixel| {
+            let alpha = pixel[3] as f64 / 255.0;
+            [
+                pixel[0] as f64 * alpha,
+                pixel[1] as f64 * alpha,
+                pixel[2] as f64 * alpha,
+                pixel[3] as f64,
+            ]
+        })
+        .collect();
+    let horizontal = Axis {
+        src_len: src_width,
+        dst_len: dst_width,
+        lines: src_height,
+        step: 1,
+        line_step: src_width,
+        dst_step: 1,
+        dst_line_step: dst_width,
+    };
+    let pixels = resample_axis(&premultiplied, &horizontal, kernel, support);
+    let vertical = Axis {
+        src_len: src_height,
+        dst_len: dst_height,
+        lines: dst_width,
+        step: dst_width,
+        line_step: 1,
+        dst_step: dst_width,
+        dst_line_step: 1,
+    };
+    let pixels = resample_axis(&pixels, &vertical, kernel, support);
+    let mut rgba = Vec::with_capacity(4 * pixels.len());
+    for pixel in pixels {
+        let alpha = pixel[3].round().clamp(0.0, 255.0);
+        if alpha == 0.0 {
+            rgba.extend_from_slice(&[0, 0, 0, 0]);
+            continue;
+        }
+        let unpremultiply = |value: f64| {
+            (value * 255.0 / pixel[3].max(1.0)).round().clamp(0.0, 255.0) as u8
+        };
+        rgba.push(unpremultiply(pixel[0]));
+        rgba.push(unpremultiply(pixel[1]));
+        rgba.push(unpremultiply(pixel[2]));
+        rgba.push(alpha as u8);
+    }
+    let mut resized = IconImage::from_rgba_data(width, height, rgba);
+    resized.set_cursor_hotspot(image.cursor_hotspot().map(|(x, y)| {
+        scale_hotspot((x, y), (image.width(), image.height()), (width, height))
+    }));
+    resized
+}
+
+/// Resizes an image by copying the source pixel nearest to the center of
+/// each destination pixel.
+fn resize_nearest(image: &IconImage, width: u32, height: u32) -> IconImage {
+    let (src_width, src_height) =
+        (image.width() as u64, image.height() as u64);
+    let source = |dst: u32, src_len: u64, dst_len: u32| {
+        ((2 * dst as u64 + 1) * src_len / (2 * dst_len as u64)) as usize
+    };
+    let src_rgba = image.rgba_data();
+    let mut rgba =
+        Vec::with_capacity(4 * (width as usize) * (height as usize));
+    for y in 0..height {
+        let src_y = source(y, src_height, height);
+        for x in 0..width {
+            let src_x = source(x, src_width, width);
+            let start = 4 * (src_y * src_width as usize + src_x);
+            rgba.extend_from_slice(&src_rgba[start..(start + 4)]);
+        }
+    }
+    let mut resized = IconImage::from_rgba_data(width, height, rgba);
+    resized.set_cursor_hotspot(image.cursor_hotspot().map(|(x, y)| {
+        scale_hotspot((x, y), (image.width(), image.height()), (width, height))
+    }));
+    resized
+}
+
+/// Enlarges an image by an integer factor, turning each pixel into a
+/// `factor`x`factor` block (which keeps pixel art crisp).  The cursor hotspot
+/// (if any) moves to the top-left corner of its pixel's block.  The caller
+/// must check that `factor` is nonzero and that the scaled dimensions fit in
+/// a `u32`.
+pub(crate) fn scale_up(image: &IconImage, factor: u32) -> IconImage {
+    let (width, height) = (image.width() * factor, image.height() * factor);
+    let factor = factor as usize;
+    let mut rgba =
+        Vec::with_capacity(4 * (width as usize) * (height as usize));
+    for src_row in image.rgba_data().chunks_exact(4 * image.width() as usize) {
+        let mut row = Vec::with_capacity(4 * width as usize);
+        for pixel in src_row.chunks_exact(4) {
+            for _ in 0..factor {
+                row.extend_from_slice(pixel);
+            }
+        }
+        for _ in 0..factor {
+            rgba.extend_from_slice(&row);
+        }
+    }
+    let mut scaled = IconImage::from_rgba_data(width, height, rgba);
+    scaled.set_cursor_hotspot(image.cursor_hotspot().map(|(x, y)| {
+        scale_hotspot((x, y), (image.width(), image.height()), (width, height))
+    }));
+    scaled
+}
+
+/// Scales hotspot coordinates from one image size to another, keeping them
+/// within the bounds of the new image.  Panics if the scaled coordinates
+/// don't fit in a cursor's 16-bit hotspot.
+fn scale_hotspot(
+    (x, y): (u16, u16),
+    (src_width, src_height): (u32, u32),
+    (dst_width, dst_height): (u32, u32),
+) -> (u16, u16) {
+    let scale = |value: u16, src: u32, dst: u32| {
+        let scaled = (value as u64 * dst as u64) / src as u64;
+        scaled.min(dst as u64 - 1)
+    };
+    let (x, y) =
+        (scale(x, src_width, dst_width), scale(y, src_height, dst_height));
+    if x > u16::MAX as u64 || y > u16::MAX as u64 {
+        panic!(
+            "Invalid size (scaled cursor hotspot ({}, {}) overflows)",
+            x, y
+        );
+    }
+    (x as u16, y as u16)
+}
+
+/// Resizes an image to fit within a `size`x`size` square, preserving its
+/// aspect ratio, and centers it on a transparent background.
+pub(crate) fn resize_to_square(
+    image: &IconImage,
+    size: u32,
+    filter: ResizeFilter,
+) -> IconImage {
+    let (width, height) = (image.width() as u64, image.height() as u64);
+    let (fit_width, fit_height) = if width >= height {
+        let fit_height = (height * size as u64 + width / 2) / width;
+        (size, (fit_height as u32).max(1))
+    } else {
+        let fit_width = (width * size as u64 + height / 2) / height;
+        ((fit_width as u32).max(1), size)
+    };
+    let resized = resize(image, fit_width, fit_height, filter);
+    if fit_width == size && fit_height == size {
+        return resized;
+    }
+    let left = ((size - fit_width) / 2) as usize;
+    let top = ((size - fit_height) / 2) as usize;
+    let mut square = IconImage::new(size, size);
+    let row_len = 4 * fit_width as usize;
+    for (row, src) in resized.rgba_data().chunks_exact(row_len).enumerate() {
+        let start = 4 * ((top + row) * size as usize + left);
+        square.rgba_data_mut()[start..(start + row_len)].copy_from_slice(src);
+    }
+    square.set_cursor_hotspot(
+        resized
+            .cursor_hotspot()
+            .map(|(x, y)| (x + left as u16, y + top as u16)),
+    );
+    square
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        resize, resize_to_square, scale_hotspot, scale_up, ResizeFilter,
+    };
+    use crate::image::IconImage;
+
+    const FILTERS: [ResizeFilter; 5] = [
+        ResizeFilter::Nearest,
+        ResizeFilter::Box,
+        ResizeFilter::Bilinear,
+        ResizeFilter::Bicubic,
+        ResizeFilter::Lanczos3,
+    ];
+
+    #[test]
+    fn resize_solid_color() {
+        let image =
+            IconImage::from_rgba_data(7, 5, [10, 200, 30, 255].repeat(7 * 5));
+        for &filter in FILTERS.iter() {
+            let resized = resize(&image, 3, 4, filter);
+            assert_eq!(resized.rgba_data(), [10, 200, 30, 255].repeat(3 * 4));
+            let resized = resize(&image, 16, 9, filter);
+            assert_eq!(resized.rgba_data(), [10, 200, 30, 255].repeat(16 * 9));
+        }
+    }
+
+    #[test]
+    fn resize_with_nearest_and_box() {
+        let image = IconImage::from_rgba_data(
+            4,
+            1,
+            vec![0, 0, 0, 255, 100, 0, 0, 255, 0, 50, 0, 255, 0, 0, 200, 255],
+        );
+        let resized = resize(&image, 2, 1, ResizeFilter::Nearest);
+        assert_eq!(resized.rgba_data(), &[100, 0, 0, 255, 0, 0, 200, 255]);
+        let resized = resize(&image, 2, 1, ResizeFilter::Box);
+        assert_eq!(resized.rgba_data(), &[50, 0, 0, 255, 0, 25, 100, 255]);
+        let resized = resize(&image, 8, 1, ResizeFilter::Nearest);
+        assert_eq!(&resized.rgba_data()[8..16], &[100, 0, 0, 255].repeat(2));
+    }
+
+    #[test]
+    fn resize_with_bilinear() {
+        let image = IconImage::from_rgba_data(
+            2,
+            1,
+            vec![0, 0, 0, 255, 200, 200, 200, 255],
+        );
+        let resized = resize(&image, 4, 1, ResizeFilter::Bilinear);
+        let reds: Vec<u8> =
+            resized.rgba_data().chunks(4).map(|pixel| pixel[0]).collect();
+        assert_eq!(reds, vec![0, 50, 150, 200]);
+    }
+
+    #[test]
+    fn scale_up_pixel_art() {
+        let mut image =
+            IconImage::from_rgba_data(2, 1, vec![1, 2, 3, 255, 4, 5, 6, 0]);
+        image.set_cursor_hotspot(Some((1, 0)));
+        let scaled = scale_up(&image, 3);
+        assert_eq!((scaled.width(), scaled.height()), (6, 3));
+        let row = [[1, 2, 3, 255].repeat(3), [4, 5, 6, 0].repeat(3)].concat();
+        assert_eq!(scaled.rgba_data(), row.repeat(3).as_slice());
+        assert_eq!(scaled.cursor_hotspot(), Some((3, 0)));
+    }
+
+    #[test]
+    fn resize_does_not_bleed_transparent_colors() {
+        // The left half is opaque red, and the right half is transparent
+        // green; the green must not show up in the resized image.
+        let mut rgba = Vec::new();
+        for _ in 0..8 {
+            rgba.extend_from_slice(&[255, 0, 0, 255].repeat(4));
+            rgba.extend_from_slice(&[0, 255, 0, 0].repeat(4));
+        }
+        let image = IconImage::from_rgba_data(8, 8, rgba);
+        for &filter in FILTERS.iter() {
+            let resized = resize(&image, 3, 3, filter);
+            for pixel in resized.rgba_data().chunks(4) {
+                if pixel[3] > 0 {
+                    assert_eq!(&pixel[..3], &[255, 0, 0]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resize_non_square_image_to_square() {
+        let image =
+            IconImage::from_rgba_data(8, 4, [0, 0, 255, 255].repeat(8 * 4));
+        let square = resize_to_square(&image, 4, ResizeFilter::Lanczos3);
+        let rgba = square.rgba_data();
+        assert_eq!(&rgba[0..16], [0; 16]);
+        assert_eq!(&rgba[16..48], [0, 0, 255, 255].repeat(8).as_slice());
+        assert_eq!(&rgba[48..64], [0; 16]);
+    }
+
+    #[test]
+    fn scale_hotspot_within_bounds() {
+        assert_eq!(scale_hotspot((10, 20), (32, 32), (16, 16)), (5, 10));
+        assert_eq!(scale_hotspot((31, 31), (32, 32), (64, 64)), (62, 62));
+        assert_eq!(scale_hotspot((1, 1), (2, 2), (1, 1)), (0, 0));
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,357 @@
+use crate::icondir::IconDir;
+use crate::restype::ResourceType;
+use std::cmp::Ordering;
+use std::io;
+
+//===========================================================================//
+
+// Standard resource type IDs.
+pub(crate) const RT_CURSOR: u16 = 1;
+pub(crate) const RT_ICON: u16 = 3;
+pub(crate) const RT_GROUP_CURSOR: u16 = 12;
+pub(crate) const RT_GROUP_ICON: u16 = 14;
+
+//===========================================================================//
+
+/// The name of a Windows resource (or resource type), which is either a
+/// 16-bit integer ID or a string.
+///
+/// Resource names are ordered as in a PE file's resource directory: strings
+/// first (compared by UTF-16 code units), followed by integer IDs.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ResourceId {
+    /// A resource identified by an integer
+    Id(u16),
+    /// A resource identified by a string
+    Name(String),
+}
+
+impl Ord for ResourceId {
+    fn cmp(&self, other: &ResourceId) -> Ordering {
+        match (self, other) {
+            (ResourceId::Name(name), ResourceId::Name(other)) => {
+                name.encode_utf16().cmp(other.encode_utf16())
+            }
+            (ResourceId::Name(_), ResourceId::Id(_)) => Ordering::Less,
+            (ResourceId::Id(_), ResourceId::Name(_)) => Ordering::Greater,
+            (ResourceId::Id(id), ResourceId::Id(other)) => id.cmp(other),
+        }
+    }
+}
+
+impl PartialOrd for ResourceId {
+    fn partial_cmp(&self, other: &ResourceId) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+//===========================================================================//
+
+/// Options for storing an `IconDir` as Windows resources.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResourceOptions {
+    group_name: ResourceId,
+    first_member_id: u16,
+    language: u16,
+}
+
+impl Default for ResourceOptions {
+    fn default() -> ResourceOptions {
+        ResourceOptions {
+            group_name: ResourceId::Id(1),
+            first_member_id: 1,
+            language: 0,
+        }
+    }
+}
+
+impl ResourceOptions {
+    /// Creates a new set of options, with default values.
+    pub fn new() -> ResourceOptions {
+        ResourceOptions::default()
+    }
+
+    /// Returns the name of the RT_GROUP_ICON or RT_GROUP_CURSOR resource.
+    /// The default is ID 1.
+    pub fn group_name(&self) -> &ResourceId {
+        &self.group_name
+    }
+
+    /// Sets the name of the RT_GROUP_ICON or RT_GROUP_CURSOR resource.
+    pub fn set_group_name(&mut self, name: ResourceId) {
+        self.group_name = name;
+    }
+
+    /// Returns the ID of the first RT_ICON or RT_CURSOR resource; the rest
+    /// are numbered consecutively from there.  The default is 1.
+    pub fn first_member_id(&self) -> u16 {
+        self.first_member_id
+    }
+
+    /// Sets the ID of the first RT_ICON or RT_CURSOR resource.  Panics if
+    /// `id` is zero.
+    pub fn set_first_member_id(&mut self, id: u16) {
+        if id == 0 {
+            panic!("Invalid first member ID (must be nonzero)");
+        }
+        self.first_member_id = id;
+    }
+
+    /// Returns the language ID of the resources.  The default is 0
+    /// (language-neutral).
+    pub fn language(&self) -> u16 {
+        self.language
+    }
+
+    /// Sets the language ID of the resources (e.g. 0x0409 for U.S. English).
+    pub fn set_language(&mut self, language: u16) {
+        self.language = language;
+    }
+}
+
+//===========================================================================//
+
+/// A single resource, as stored in a PE or .res file.
+#[derive(Clone, Debug)]
+pub(crate) struct Resource {
+    pub(crate) restype: ResourceId,
+    pub(crate) name: ResourceId,
+    pub(crate) language: u16,
+    pub(crate) data: Vec<u8>,
+}
+
+//===========================================================================//
+
+/// An icon or cursor group stored as Windows resources: a collection of
+/// images, along with the resource name and language of the group.
+#[derive(Clone, Debug)]
+pub struct IconGroup {
+    name: ResourceId,
+    language: u16,
+    icon_dir: IconDir,
+}
+
+impl IconGroup {
+    /// Returns the resource name of the RT_GROUP_ICON or RT_GROUP_CURSOR
+    /// resource for this group.
+    pub fn name(&self) -> &ResourceId {
+        &self.name
+    }
+
+    /// Returns the language ID of the group resource (e.g. 0x0409 for U.S.
+    /// English, or 0 for language-neutral).
+    pub fn language(&self) -> u16 {
+        self.language
+    }
+
+    /// Returns the images in this group.
+    pub fn icon_dir(&self) -> &IconDir {
+        &self.icon_dir
+    }
+
+    /// Consumes the group, returning its images.
+    pub fn into_icon_dir(self) -> IconDir {
+        self.icon_dir
+    }
+
+    /// Finds every RT_GROUP_ICON and RT_GROUP_CURSOR resource in the list and
+    /// reconstructs it from its member resources.  Returns an error if any
+    /// group is malformed or refers to a missing member.
+    pub(crate) fn read_all(
+        resources: &[Resource],
+    ) -> io::Result<Vec<IconGroup>> {
+        let mut groups = Vec::new();
+        for group in resources.iter() {
+            let member_type = match group.restype {
+                ResourceId::Id(RT_GROUP_ICON) => RT_ICON,
+                ResourceId::Id(RT_GROUP_CURSOR) => RT_CURSOR,
+                _ => continue,
+            };
+            let icon_dir = IconDir::read_resource_group(&group.data, |id| {
+                find_member(resources, member_type, id, group.language)
+                    .map(|member| member.data.as_slice())
+            })?;
+            groups.push(IconGroup {
+                name: group.name.clone(),
+                language: group.language,
+                icon_dir,
+            });
+        }
+        Ok(groups)
+    }
+}
+
+/// Adds a collection to a list of resources, as a group resource and its
+/// member resources described by the options.  If the list already has a
+/// group with the same type, name, and language, that group and its members
+/// are removed first (except for members that other groups still use), as are
+/// any other resources that would conflict with the new ones.  Returns an
+/// error if the member IDs would be out of range, or if any of them is
+/// already used by a member of another group.
+pub(crate) fn replace_icon_group(
+    resources: &mut Vec<Resource>,
+    icon_dir: &IconDir,
+    options: &ResourceOptions,
+) -> io::Result<()> {
+    let (group_type, member_type) = resource_types(icon_dir.resource_type());
+    let first_id = options.first_member_id();
+    let language = options.language();
+    let (group, members) = icon_dir.write_resource_group(first_id)?;
+    let group = Resource {
+        restype: ResourceId::Id(group_type),
+        name: options.group_name().clone(),
+        language,
+        data: group,
+    };
+    let is_new_member_id =
+        |id: u16| id >= first_id && ((id - first_id) as usize) < members.len();
+    let mut old_member_ids = Vec::new();
+    let mut shared_member_ids = Vec::new();
+    for resource in resources.iter() {
+        if resource.restype != group.restype {
+            continue;
+        }
+        if resource.name == group.name && resource.language == language {
+            old_member_ids.extend(member_ids(&resource.data));
+            continue;
+        }
+        for id in member_ids(&resource.data) {
+            // Members in other languages won't be touched, so only those in
+            // this language (or missing ones, which the new members would
+            // stand in for) can conflict.
+            if find_member(resources, member_type, id, resource.language)
+                .is_some_and(|member| member.language != language)
+            {
+                continue;
+            }
+            if is_new_member_id(id) {
+                invalid_input!(
+                    "Member ID {} is already used by group {:?}",
+                    id,
+                    resource.name
+                );
+            }
+            shared_member_ids.push(id);
+        }
+    }
+    resources.retain(|resource| {
+        if resource.language != language {
+            return true;
+        }
+        if resource.restype == group.restype {
+            return resource.name != group.name;
+        }
+        match resource.name {
+            ResourceId::Id(id)
+                if resource.restype == ResourceId::Id(member_type) =>
+            {
+                !is_new_member_id(id)
+                    && (!old_member_ids.contains(&id)
+                        || shared_member_ids.contains(&id))
+            }
+            _ => true,
+        }
+    });
+    for (index, data) in members.into_iter().enumerate() {
+        resources.push(Resource {
+            restype: ResourceId::Id(member_type),
+            name: ResourceId::Id(first_id + index as u16),
+            language,
+            data,
+        });
+    }
+    resources.push(group);
+    Ok(())
+}
+
+/// Returns the lowest ID from which `count` consecutive icon or cursor
+/// resources can be numbered, without colliding with the members of any
+/// group other than the one with the given name and language.  Returns
+/// `None` if there is no such range of IDs.
+pub(crate) fn unused_member_id(
+    resources: &[Resource],
+    restype: ResourceType,
+    group_name: &ResourceId,
+    language: u16,
+    count: usize,
+) -> Option<u16> {
+    let (group_type, member_type) = resource_types(restype);
+    let replaced_ids: Vec<u16> = resources
+        .iter()
+        .filter(|resource| {
+            resource.restype == ResourceId::Id(group_type)
+                && &resource.name == group_name
+                && resource.language == language
+        })
+        .flat_map(|resource| member_ids(&resource.data))
+        .collect();
+    let mut used_ids: Vec<u16> = resources
+        .iter()
+        .filter_map(|resource| match resource.name {
+            ResourceId::Id(id)
+                if resource.restype == ResourceId::Id(member_type)
+                    && !replaced_ids.contains(&id) =>
+            {
+                Some(id)
+            }
+            _ => None,
+        })
+        .collect();
+    used_ids.sort_unstable();
+    let mut first_id = 1usize;
+    for id in used_ids {
+        let id = id as usize;
+        if id >= first_id + count {
+            break;
+        }
+        first_id = first_id.max(id + 1);
+    }
+    if first_id + count > u16::MAX as usize + 1 {
+        return None;
+    }
+    Some(first_id as u16)
+}
+
+/// Returns the resource type IDs for groups and members of the given type.
+fn resource_types(restype: ResourceType) -> (u16, u16) {
+    match restype {
+        ResourceType::Icon => (RT_GROUP_ICON, RT_ICON),
+        ResourceType::Cursor => (RT_GROUP_CURSOR, RT_CURSOR),
+    }
+}
+
+/// Returns the member resource IDs listed in a group resource's data,
+/// ignoring any that are truncated.
+fn member_ids(group: &[u8]) -> Vec<u16> {
+    let mut ids = Vec::new();
+    let mut offset = 6 + 12;
+    while offset + 2 <= group.len() {
+        ids.push(u16::from_le_bytes([group[offset], group[offset + 1]]));
+        offset += 14;
+    }
+    ids
+}
+
+/// Returns the member resource with the given type and ID, preferring one in
+/// the same language as its group.
+fn find_member(
+    resources: &[Resource],
+    restype: u16,
+    id: u16,
+    language: u16,
+) -> Option<&Resource> {
+    let mut candidates = resources.iter().filter(|resource| {
+        resource.restype == ResourceId::Id(restype)
+            && resource.name == ResourceId::Id(id)
+    });
+    let first = candidates.next()?;
+    if first.language == language {
+        return Some(first);
+    }
+    let resource = candidates
+        .find(|resource| resource.language == language)
+        .unwrap_or(first);
+    Some(resource)
+}
+
+//===========================================================================//
@@ -0,0 +1,399 @@
+use crate::image::IconImage;
+use std::io;
+
+//===========================================================================//
+
+/// Where to place an image within a larger area when padding it.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Anchor {
+    /// Against the top and left edges
+    TopLeft,
+    /// Against the top edge, centered horizontally
+    Top,
+    /// Against the top and right edges
+    TopRight,
+    /// Against the left edge, centered vertically
+    Left,
+    /// Centered both horizontally and vertically
+    #[default]
+    Center,
+    /// Against the right edge, centered vertically
+    Right,
+    /// Against the bottom and left edges
+    BottomLeft,
+    /// Against the bottom edge, centered horizontally
+    Bottom,
+    /// Against the bottom and right edges
+    BottomRight,
+}
+
+impl Anchor {
+    /// Returns the offset at which to place something of size `inner` within
+    /// a space of size `outer`, horizontally and vertically.
+    fn offset(self, inner: (u32, u32), outer: (u32, u32)) -> (u32, u32) {
+        let (extra_x, extra_y) = (outer.0 - inner.0, outer.1 - inner.1);
+        let x = match self {
+            Anchor::TopLeft | Anchor::Left | Anchor::BottomLeft => 0,
+            Anchor::Top | Anchor::Center | Anchor::Bottom => extra_x / 2,
+            Anchor::TopRight | Anchor::Right | Anchor::BottomRight => extra_x,
+        };
+        let y = match self {
+            Anchor::TopLeft | Anchor::Top | Anchor::TopRight => 0,
+            Anchor::Left | Anchor::Center | Anchor::Right => extra_y / 2,
+            Anchor::BottomLeft | Anchor::Bottom | Anchor::BottomRight => {
+                extra_y
+            }
+        };
+        (x, y)
+    }
+}
+
+//===========================================================================//
+
+impl IconImage {
+    /// Returns the given rectangle of this image as a new image.  If the
+    /// image has a cursor hotspot, it is moved along with the image content;
+    /// if that would put it outside the cropped image, it is clamped to the
+    /// nearest edge.  Returns an error if the rectangle is empty or extends
+    /// outside the image.
+    pub fn crop(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> io::Result<IconImage> {
+        if width == 0 || height == 0 {
+            invalid_input!(
+                "Invalid crop size (was {}x{}, but must be nonzero)",
+                width,
+                height
+            );
+        }
+        if (x as u64) + (width as u64) > (self.width() as u64)
+            || (y as u64) + (height as u64) > (self.height() as u64)
+        {
+            invalid_input!(
+                "Crop rectangle ({}x{} at ({}, {})) extends outside of {}x{} \
+                 image",
+                width,
+                height,
+                x,
+                y,
+                self.width(),
+                self.height()
+            );
+        }
+        let src_row_len = 4 * self.width() as usize;
+        let row_len = 4 * width as usize;
+        let mut rgba = Vec::with_capacity(row_len * height as usize);
+        let rows = self
+            .rgba_data()
+            .chunks_exact(src_row_len)
+            .skip(y as usize)
+            .take(height as usize);
+        for row in rows {
+            let start = 4 * x as usize;
+            rgba.extend_from_slice(&row[start..(start + row_len)]);
+        }
+        let mut image = IconImage::from_rgba_data(width, height, rgba);
+        image.set_cursor_hotspot(self.cursor_hotspot().map(|(hx, hy)| {
+            let clamp = |value: u16, start: u32, len: u32| {
+                (value as u32).saturating_sub(start).min(len - 1) as u16
+            };
+            (clamp(hx, x, width), clamp(hy, y, height))
+        }));
+        Ok(image)
+    }
+
+    /// Returns a copy of this image placed within a larger transparent area
+    /// of the given size, at the position given by `anchor`.  The cursor
+    /// hotspot, if any, moves along with the image content.  Returns an error
+    /// if the new size is smaller than the image in either dimension, or if
+    /// the moved hotspot wouldn't fit in a cursor's 16-bit coordinates.
+    pub fn pad(
+        &self,
+        width: u32,
+        height: u32,
+        anchor: Anchor,
+    ) -> io::Result<IconImage> {
+        if width < self.width() || height < self.height() {
+            invalid_input!(
+                "Can't pad {}x{} image to smaller size {}x{}",
+                self.width(),
+                self.height(),
+                width,
+                height
+            );
+        }
+        let (left, top) =
+            anchor.offset((self.width(), self.height()), (width, height));
+        let hotspot = match self.cursor_hotspot() {
+            Some((x, y)) => {
+                let (x, y) = (x as u32 + left, y as u32 + top);
+                if x > u16::MAX as u32 || y > u16::MAX as u32 {
+                    invalid_input!(
+                        "Padded cursor hotspot ({}, {}) is out of range",
+                        x,
+                        y
+                    );
+                }
+                Some((x as u16, y as u16))
+            }
+            None => None,
+        };
+        let mut image = IconImage::new(width, height);
+        let row_len = 4 * self.width() as usize;
+        let dst_row_len = 4 * width as usize;
+        for (row, src) in self.rgba_data().chunks_exact(row_len).enumerate() {
+            let start = (top as usize + row) * dst_row_len + 4 * left as usize;
+            image.rgba_data_mut()[start..(start + row_len)]
+                .copy_from_slice(src);
+        }
+        image.set_cursor_hotspot(hotspot);
+        Ok(image)
+    }
+
+    /// Returns a copy of this image padded with transparent pixels to make it
+    /// square, with the original centered.  See `pad` for how the cursor
+    /// hotspot is handled.
+    pub fn pad_to_square(&self) -> io::Result<IconImage> {
+        let size = self.width().max(self.height());
+        self.pad(size, size, Anchor::Center)
+    }
+
+    /// Returns the smallest rectangle, as `(x, y, width, height)`, that
+    /// contains all of the image's pixels that aren't fully transparent, or
+    /// `None` if the image is entirely transparent.
+    pub fn content_bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let width = self.width() as usize;
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (index, pixel) in self.rgba_data().chunks_exact(4).enumerate() {
+            if pixel[3] == 0 {
+                continue;
+            }
+            let (x, y) = (index % width, index / width);
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((left, top, right, bottom)) => {
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                }
+            });
+        }
+        bounds.map(|(left, top, right, bottom)| {
+            (
+                left as u32,
+                top as u32,
+                (right - left + 1) as u32,
+                (bottom - top + 1) as u32,
+            )
+        })
+    }
+
+    /// Returns a copy of this image with any fully transparent rows and
+    /// columns around the edges removed, or `None` if the image is entirely
+    /// transparent.  See `crop` for how the cursor hotspot is handled.
+    pub fn trim(&self) -> Option<IconImage> {
+        let (x, y, width, height) = self.content_bounds()?;
+        Some(self.crop(x, y, width, height).unwrap())
+    }
+
+    /// Returns a copy of this image mirrored left-to-right, along with its
+    /// cursor hotspot (if any).  Returns an error if the moved hotspot
+    /// wouldn't fit in a cursor's 16-bit coordinates.
+    pub fn flip_horizontal(&self) -> io::Result<IconImage> {
+        self.remap(self.width(), self.height(), |x, y| {
+            (self.width() - 1 - x, y)
+        })
+    }
+
+    /// Returns a copy of this image mirrored top-to-bottom, along with its
+    /// cursor hotspot (if any).  Returns an error if the moved hotspot
+    /// wouldn't fit in a cursor's 16-bit coordinates.
+    pub fn flip_vertical(&self) -> io::Result<IconImage> {
+        self.remap(self.width(), self.height(), |x, y| {
+            (x, self.height() - 1 - y)
+        })
+    }
+
+    /// Returns a copy of this image rotated 90 degrees clockwise, along with
+    /// its cursor hotspot (if any).  Returns an error if the moved hotspot
+    /// wouldn't fit in a cursor's 16-bit coordinates.
+    pub fn rotate_90(&self) -> io::Result<IconImage> {
+        self.remap(self.height(), self.width(), |x, y| {
+            (self.height() - 1 - y, x)
+        })
+    }
+
+    /// Returns a copy of this image rotated 180 degrees, along with its
+    /// cursor hotspot (if any).  Returns an error if the moved hotspot
+    /// wouldn't fit in a cursor's 16-bit coordinates.
+    pub fn rotate_180(&self) -> io::Result<IconImage> {
+        self.remap(self.width(), self.height(), |x, y| {
+            (self.width() - 1 - x, self.height() - 1 - y)
+        })
+    }
+
+    /// Returns a copy of this image rotated 90 degrees counterclockwise
+    /// (i.e. 270 degrees clockwise), along with its cursor hotspot (if any).
+    /// Returns an error if the moved hotspot wouldn't fit in a cursor's
+    /// 16-bit coordinates.
+    pub fn rotate_270(&self) -> io::Result<IconImage> {
+        self.remap(self.height(), self.width(), |x, y| {
+            (y, self.width() - 1 - x)
+        })
+    }
+
+    /// Creates a new image of the given size by moving each pixel (and the
+    /// hotspot) of this image from `(x, y)` to `position(x, y)`.  Returns an
+    /// error if the moved hotspot wouldn't fit in a cursor's 16-bit
+    /// coordinates.
+    fn remap<F>(
+        &self,
+        width: u32,
+        height: u32,
+        position: F,
+    ) -> io::Result<IconImage>
+    where
+        F: Fn(u32, u32) -> (u32, u32),
+    {
+        let src_width = self.width();
+        let hotspot = match self.cursor_hotspot() {
+            Some((x, y)) => {
+                // Hotspots outside the image (which can't normally happen)
+                // are clamped first, so that they can't wrap around.
+                let x = (x as u32).min(src_width - 1);
+                let y = (y as u32).min(self.height() - 1);
+                let (x, y) = position(x, y);
+                if x > u16::MAX as u32 || y > u16::MAX as u32 {
+                    invalid_input!(
+                        "Moved cursor hotspot ({}, {}) is out of range",
+                        x,
+                        y
+                    );
+                }
+                Some((x as u16, y as u16))
+            }
+            None => None,
+        };
+        let mut image = IconImage::new(width, height);
+        for (index, pixel) in self.rgba_data().chunks_exact(4).enumerate() {
+            let index = index as u32;
+            let (x, y) = position(index % src_width, index / src_width);
+            let start = 4 * (y as usize * width as usize + x as usize);
+            image.rgba_data_mut()[start..(start + 4)].copy_from_slice(pixel);
+        }
+        image.set_cursor_hotspot(hotspot);
+        Ok(image)
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::Anchor;
+    use crate::image::IconImage;
+
+    // A 3x2 image whose pixels are numbered 0 through 5 in their red
+    // channels.
+    fn numbered_image() -> IconImage {
+        let mut rgba = Vec::new();
+        for index in 0..6 {
+            rgba.extend_from_slice(&[index, 0, 0, 255]);
+        }
+        let mut image = IconImage::from_rgba_data(3, 2, rgba);
+        image.set_cursor_hotspot(Some((2, 0)));
+        image
+    }
+
+    fn numbers(image: &IconImage) -> Vec<u8> {
+        image.rgba_data().chunks(4).map(|pixel| pixel[0]).collect()
+    }
+
+    #[test]
+    fn crop_image() {
+        let image = numbered_image();
+        let cropped = image.crop(1, 0, 2, 2).unwrap();
+        assert_eq!(numbers(&cropped), vec![1, 2, 4, 5]);
+        assert_eq!(cropped.cursor_hotspot(), Some((1, 0)));
+        let cropped = image.crop(0, 1, 1, 1).unwrap();
+        assert_eq!(numbers(&cropped), vec![3]);
+        assert_eq!(cropped.cursor_hotspot(), Some((0, 0)));
+        assert!(image.crop(2, 0, 2, 1).is_err());
+        assert!(image.crop(0, 0, 0, 1).is_err());
+    }
+
+    #[test]
+    fn pad_image() {
+        let image = numbered_image();
+        let padded = image.pad(5, 3, Anchor::BottomRight).unwrap();
+        assert_eq!((padded.width(), padded.height()), (5, 3));
+        assert_eq!(padded.cursor_hotspot(), Some((4, 1)));
+        assert_eq!(padded.rgba_data()[3], 0);
+        assert_eq!(&padded.rgba_data()[(4 * 7)..(4 * 8)], &[0, 0, 0, 255]);
+        let padded = image.pad(5, 4, Anchor::Center).unwrap();
+        assert_eq!(padded.cursor_hotspot(), Some((3, 1)));
+        let square = image.pad_to_square().unwrap();
+        assert_eq!((square.width(), square.height()), (3, 3));
+        assert!(image.pad(2, 2, Anchor::TopLeft).is_err());
+    }
+
+    #[test]
+    fn trim_transparent_borders() {
+        let mut image = IconImage::new(5, 4);
+        image.rgba_data_mut()[(4 * 6)..(4 * 7)].copy_from_slice(&[1; 4]);
+        image.rgba_data_mut()[(4 * 13)..(4 * 14)].copy_from_slice(&[2; 4]);
+        image.set_cursor_hotspot(Some((4, 3)));
+        assert_eq!(image.content_bounds(), Some((1, 1, 3, 2)));
+        let trimmed = image.trim().unwrap();
+        assert_eq!((trimmed.width(), trimmed.height()), (3, 2));
+        assert_eq!(trimmed.cursor_hotspot(), Some((2, 1)));
+        assert!(IconImage::new(2, 2).trim().is_none());
+    }
+
+    #[test]
+    fn flip_and_rotate() {
+        let image = numbered_image();
+        let flipped = image.flip_horizontal().unwrap();
+        assert_eq!(numbers(&flipped), vec![2, 1, 0, 5, 4, 3]);
+        assert_eq!(flipped.cursor_hotspot(), Some((0, 0)));
+        let flipped = image.flip_vertical().unwrap();
+        assert_eq!(numbers(&flipped), vec![3, 4, 5, 0, 1, 2]);
+        assert_eq!(flipped.cursor_hotspot(), Some((2, 1)));
+        let rotated = image.rotate_90().unwrap();
+        assert_eq!((rotated.width(), rotated.height()), (2, 3));
+        assert_eq!(numbers(&rotated), vec![3, 0, 4, 1, 5, 2]);
+        assert_eq!(rotated.cursor_hotspot(), Some((1, 2)));
+        let rotated = image.rotate_180().unwrap();
+        assert_eq!(numbers(&rotated), vec![5, 4, 3, 2, 1, 0]);
+        assert_eq!(rotated.cursor_hotspot(), Some((0, 1)));
+        let rotated = image.rotate_270().unwrap();
+        assert_eq!(numbers(&rotated), vec![2, 5, 1, 4, 0, 3]);
+        assert_eq!(rotated.cursor_hotspot(), Some((0, 0)));
+        assert_eq!(
+            image.rotate_90().unwrap().rotate_270().unwrap().rgba_data(),
+            image.rgba_data()
+        );
+    }
+
+    #[test]
+    fn flip_and_rotate_large_cursor() {
+        // Moving the hotspot to the far edge of an image this wide would put
+        // it out of range of a cursor's 16-bit coordinates.
+        let mut image = IconImage::new(65537, 1);
+        image.set_cursor_hotspot(Some((0, 0)));
+        assert!(image.flip_horizontal().is_err());
+        assert!(image.rotate_270().is_err());
+        let flipped = image.flip_vertical().unwrap();
+        assert_eq!(flipped.cursor_hotspot(), Some((0, 0)));
+        let rotated = image.rotate_90().unwrap();
+        assert!(rotated.rotate_90().is_err());
+        assert_eq!(rotated.cursor_hotspot(), Some((0, 0)));
+        image.set_cursor_hotspot(Some((65535, 0)));
+        let flipped = image.flip_horizontal().unwrap();
+        assert_eq!(flipped.cursor_hotspot(), Some((1, 0)));
+    }
+}
+
+//===========================================================================//
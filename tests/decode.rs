@@ -1,6 +1,7 @@
 extern crate ico;
 
 use std::fs::File;
+use std::io::Cursor;
 use std::path::PathBuf;
 
 //===========================================================================//
@@ -30,6 +31,134 @@ fn decode_litexl_icons() {
 
 //===========================================================================//
 
+#[test]
+fn decode_indexed_png_with_trns() {
+    // A 2x2 image with 2 bits per pixel, using a three-entry palette whose
+    // first entry is fully transparent and whose second is half transparent.
+    let palette: &[u8] = b"\xff\x00\x00\x00\xff\x00\x00\x00\xff";
+    let trns: &[u8] = b"\x00\x80";
+    let png_data = encode_png(
+        2,
+        2,
+        png::ColorType::Indexed,
+        png::BitDepth::Two,
+        Some(palette),
+        Some(trns),
+        b"\x10\x80",
+    );
+    let rgba: &[u8] = b"\xff\x00\x00\x00\x00\xff\x00\x80\
+                        \x00\x00\xff\xff\xff\x00\x00\x00";
+    let image = ico::IconImage::read_png(png_data.as_slice()).unwrap();
+    assert_eq!(image.rgba_data(), rgba);
+    let entry = ico_entry_for_png(png_data);
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba);
+}
+
+#[test]
+fn decode_sixteen_bit_rgba_png() {
+    // A 2x1 image with 16 bits per sample; only the high byte of each sample
+    // should survive.
+    let image_data: &[u8] = b"\
+        \xff\xff\x00\x00\x80\x01\x00\x00\
+        \x12\x34\x56\x78\x9a\xbc\xde\xf0";
+    let png_data = encode_png(
+        2,
+        1,
+        png::ColorType::Rgba,
+        png::BitDepth::Sixteen,
+        None,
+        None,
+        image_data,
+    );
+    let rgba: &[u8] = b"\xff\x00\x80\x00\x12\x56\x9a\xde";
+    assert_eq!(
+        ico_entry_for_png(png_data).decode().unwrap().rgba_data(),
+        rgba
+    );
+}
+
+#[test]
+fn decode_low_bit_depth_grayscale_png() {
+    // A 3x2 image with 1 bit per pixel:
+    let png_data = encode_png(
+        3,
+        2,
+        png::ColorType::Grayscale,
+        png::BitDepth::One,
+        None,
+        None,
+        b"\xa0\x40",
+    );
+    let rgba: &[u8] = b"\
+        \xff\xff\xff\xff\x00\x00\x00\xff\xff\xff\xff\xff\
+        \x00\x00\x00\xff\xff\xff\xff\xff\x00\x00\x00\xff";
+    assert_eq!(
+        ico_entry_for_png(png_data).decode().unwrap().rgba_data(),
+        rgba
+    );
+    // A 2x2 image with 4 bits per pixel and a tRNS color key of 0x5:
+    let png_data = encode_png(
+        2,
+        2,
+        png::ColorType::Grayscale,
+        png::BitDepth::Four,
+        None,
+        Some(b"\x00\x05"),
+        b"\x05\xf5",
+    );
+    let rgba: &[u8] = b"\
+        \x00\x00\x00\xff\x55\x55\x55\x00\
+        \xff\xff\xff\xff\x55\x55\x55\x00";
+    assert_eq!(
+        ico_entry_for_png(png_data).decode().unwrap().rgba_data(),
+        rgba
+    );
+}
+
+//===========================================================================//
+
+fn encode_png(
+    width: u32,
+    height: u32,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    palette: Option<&[u8]>,
+    trns: Option<&[u8]>,
+    image_data: &[u8],
+) -> Vec<u8> {
+    let mut png_data = Vec::<u8>::new();
+    let mut encoder = png::Encoder::new(&mut png_data, width, height);
+    encoder.set_color(color_type);
+    encoder.set_depth(bit_depth);
+    if let Some(palette) = palette {
+        encoder.set_palette(palette);
+    }
+    if let Some(trns) = trns {
+        encoder.set_trns(trns);
+    }
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(image_data).unwrap();
+    writer.finish().unwrap();
+    png_data
+}
+
+fn ico_entry_for_png(png_data: Vec<u8>) -> ico::IconDirEntry {
+    let image = ico::IconImage::read_png(png_data.as_slice()).unwrap();
+    let (width, height) = (image.width(), image.height());
+    let mut ico_data = Vec::<u8>::new();
+    ico_data.extend_from_slice(b"\x00\x00\x01\x00\x01\x00");
+    ico_data.push(width as u8);
+    ico_data.push(height as u8);
+    ico_data.extend_from_slice(b"\x00\x00\x00\x00\x20\x00");
+    ico_data.extend_from_slice(&(png_data.len() as u32).to_le_bytes());
+    ico_data.extend_from_slice(&22u32.to_le_bytes());
+    ico_data.extend_from_slice(&png_data);
+    let icon_dir = ico::IconDir::read(Cursor::new(ico_data)).unwrap();
+    let entry = icon_dir.entries()[0].clone();
+    assert!(entry.is_png());
+    entry
+}
+
 fn compare_ico_and_png(ico_path: &str, ico_index: usize, png_path: &str) {
     let ico_path = PathBuf::from("tests/images").join(ico_path);
     let png_path = PathBuf::from("tests/images").join(png_path);
@@ -45,11 +45,28 @@ fn encode_bmp_with_fifty_colors() {
     assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
 }
 
+#[test]
+fn encode_bmp_with_minimal_color_table() {
+    // This image has 50 colors, so when encoded at 8 bpp, the color table
+    // should have only 50 entries rather than 256.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(31 * 29) {
+        rgba.extend_from_slice(&[(index % 50) as u8, 0, 0, 0xff]);
+    }
+    let image = ico::IconImage::from_rgba_data(31, 29, rgba.to_vec());
+    let entry = ico::IconDirEntry::encode_as_bmp(&image).unwrap();
+    assert_eq!(entry.bits_per_pixel(), 8);
+    // 40-byte header, 50 4-byte colors, and 29 rows of 32 bytes of color data
+    // plus 4 bytes of mask data:
+    assert_eq!(entry.data().len(), 40 + 4 * 50 + 29 * (32 + 4));
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
+}
+
 #[test]
 fn encode_small_bmp_with_fifty_colors() {
     // This image has 50 colors, like the above test, but only 50 pixels.  So
     // although it could be encoded at 8 bpp, it's actually more efficient to
-    // encode it at 24 bpp (so that we can omit the 256-entry color table).
+    // encode it at 24 bpp (so that we can omit the color table).
     let mut rgba = Vec::<u8>::new();
     for index in 0..(10 * 5) {
         rgba.extend_from_slice(&[(index % 50) as u8, 0, 0, 0xff]);
@@ -90,6 +107,123 @@ fn encode_bmp_with_nonbinary_alpha() {
     assert_eq!(entry.decode().unwrap().rgba_data(), rgba);
 }
 
+#[test]
+fn encode_bmp_with_forced_depth() {
+    // This image has only two colors, but we can still ask for it to be
+    // encoded at a higher color depth.
+    let rgba: &[u8] = b"\xff\x00\x00\xff\x00\xff\x00\xff\
+                        \xff\x00\x00\xff\xff\x00\x00\x00";
+    let image = ico::IconImage::from_rgba_data(2, 2, rgba.to_vec());
+    let depths = &[
+        ico::BmpDepth::One,
+        ico::BmpDepth::Four,
+        ico::BmpDepth::Eight,
+        ico::BmpDepth::TwentyFour,
+        ico::BmpDepth::ThirtyTwo,
+    ];
+    for &depth in depths.iter() {
+        let mut options = ico::BmpEncodingOptions::new();
+        options.set_depth(Some(depth));
+        let entry =
+            ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+                .unwrap();
+        assert!(!entry.is_png());
+        assert_eq!(entry.decode().unwrap().rgba_data(), rgba);
+    }
+}
+
+#[test]
+fn encode_bmp_with_forced_depth_requires_quantization() {
+    // This image has 10 colors and partial alpha, so it can't be encoded
+    // losslessly at 1 bpp.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(13 * 7) {
+        let alpha = if index % 3 == 0 { 0x40 } else { 0xc0 };
+        rgba.extend_from_slice(&[(index % 10) as u8 * 20, 0, 0, alpha]);
+    }
+    let image = ico::IconImage::from_rgba_data(13, 7, rgba.to_vec());
+    let mut options = ico::BmpEncodingOptions::new();
+    options.set_depth(Some(ico::BmpDepth::One));
+    assert!(ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+        .is_err());
+    options.set_allow_quantization(true);
+    let entry =
+        ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+            .unwrap();
+    assert_eq!(entry.bits_per_pixel(), 1);
+    let decoded = entry.decode().unwrap();
+    let mut colors = std::collections::BTreeSet::new();
+    for (pixel, original) in decoded.rgba_data().chunks(4).zip(rgba.chunks(4))
+    {
+        assert_eq!(pixel[3], if original[3] < 0x80 { 0 } else { 0xff });
+        colors.insert((pixel[0], pixel[1], pixel[2]));
+    }
+    assert!(colors.len() <= 2);
+}
+
+#[test]
+fn encode_bmp_at_sixteen_bpp() {
+    // Every 5-bit channel value, expanded to 8 bits, should survive a round
+    // trip through a 16-bpp (5-5-5) BMP.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..32u32 {
+        let value = ((index * 255 + 15) / 31) as u8;
+        let alpha = if index % 4 == 0 { 0 } else { 0xff };
+        rgba.extend_from_slice(&[value, 255 - value, value / 2, alpha]);
+    }
+    let image = ico::IconImage::from_rgba_data(8, 4, rgba.clone());
+    let mut options = ico::BmpEncodingOptions::new();
+    options.set_depth(Some(ico::BmpDepth::Sixteen));
+    // Not every channel value in this image is exactly representable in 5
+    // bits (because of the `value / 2` above):
+    assert!(ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+        .is_err());
+    for pixel in rgba.chunks_mut(4) {
+        pixel[2] = pixel[0];
+    }
+    let image = ico::IconImage::from_rgba_data(8, 4, rgba.clone());
+    let entry =
+        ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+            .unwrap();
+    assert!(!entry.is_png());
+    assert_eq!(entry.bits_per_pixel(), 16);
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
+}
+
+#[test]
+fn encode_bmp_at_sixteen_bpp_with_rgb565() {
+    // With 5-6-5 masks, every 6-bit green value should survive a round trip.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..64u32 {
+        let green = ((index * 255 + 31) / 63) as u8;
+        let red = if index % 2 == 0 { 0 } else { 0xff };
+        rgba.extend_from_slice(&[red, green, 0xff - red, 0xff]);
+    }
+    let image = ico::IconImage::from_rgba_data(8, 8, rgba.clone());
+    let mut options = ico::BmpEncodingOptions::new();
+    options.set_depth(Some(ico::BmpDepth::Sixteen));
+    options.set_rgb565(true);
+    let entry =
+        ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+            .unwrap();
+    assert_eq!(entry.bits_per_pixel(), 16);
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
+    // Without 5-6-5 masks, the green channel would lose precision.
+    options.set_rgb565(false);
+    assert!(ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+        .is_err());
+    options.set_allow_quantization(true);
+    let entry =
+        ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+            .unwrap();
+    let decoded = entry.decode().unwrap();
+    for (pixel, original) in decoded.rgba_data().chunks(4).zip(rgba.chunks(4))
+    {
+        assert_eq!(pixel[0], original[0]);
+        assert!((pixel[1] as i32 - original[1] as i32).abs() <= 4);
+    }
+}
+
 //===========================================================================//
 
 #[test]
@@ -124,4 +258,402 @@ fn encode_png_without_alpha_channel() {
     assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
 }
 
+#[test]
+fn encode_png_with_black_and_white() {
+    // This image is opaque and uses only black and white, so it should be
+    // encoded as 1-bit grayscale.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(24 * 24) {
+        let value = if index % 3 == 0 { 0 } else { 0xff };
+        rgba.extend_from_slice(&[value, value, value, 0xff]);
+    }
+    let image = ico::IconImage::from_rgba_data(24, 24, rgba.to_vec());
+    let entry = ico::IconDirEntry::encode_as_png(&image).unwrap();
+    assert!(entry.is_png());
+    assert_eq!(entry.bits_per_pixel(), 1);
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
+}
+
+#[test]
+fn encode_png_with_grayscale() {
+    // This image is opaque and uses many shades of gray, so it should be
+    // encoded as 8-bit grayscale.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(24 * 24) {
+        let value = (index % 200) as u8;
+        rgba.extend_from_slice(&[value, value, value, 0xff]);
+    }
+    let image = ico::IconImage::from_rgba_data(24, 24, rgba.to_vec());
+    let entry = ico::IconDirEntry::encode_as_png(&image).unwrap();
+    assert_eq!(entry.bits_per_pixel(), 8);
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
+}
+
+#[test]
+fn encode_png_with_grayscale_and_alpha() {
+    // This image is gray, but has more than 256 distinct gray/alpha
+    // combinations, so it should be encoded as 8-bit grayscale+alpha.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(24 * 24) {
+        let value = (index % 100) as u8;
+        rgba.extend_from_slice(&[value, value, value, (index / 100) as u8]);
+    }
+    let image = ico::IconImage::from_rgba_data(24, 24, rgba.to_vec());
+    let entry = ico::IconDirEntry::encode_as_png(&image).unwrap();
+    assert_eq!(entry.bits_per_pixel(), 16);
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
+}
+
+#[test]
+fn encode_png_with_palette() {
+    // This image has 10 distinct colors (some partially transparent), so it
+    // should be encoded as a 4-bit indexed PNG with a tRNS chunk.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(13 * 7) {
+        let alpha = if index % 10 < 3 { 0x40 } else { 0xff };
+        rgba.extend_from_slice(&[(index % 10) as u8 * 20, 0, 0x80, alpha]);
+    }
+    let image = ico::IconImage::from_rgba_data(13, 7, rgba.to_vec());
+    let entry = ico::IconDirEntry::encode_as_png(&image).unwrap();
+    assert!(entry.is_png());
+    assert_eq!(entry.bits_per_pixel(), 4);
+    assert_eq!(entry.decode().unwrap().rgba_data(), rgba.as_slice());
+}
+
+#[test]
+fn encode_png_with_options() {
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(32 * 32) {
+        let value = (index % 251) as u8;
+        rgba.extend_from_slice(&[value, value / 2, 0xff - value, 0xff]);
+    }
+    let image = ico::IconImage::from_rgba_data(32, 32, rgba.to_vec());
+    let mut fast = ico::PngEncodingOptions::new();
+    fast.set_compression(ico::PngCompression::Fast);
+    fast.set_filter(ico::PngFilter::NoFilter);
+    let fast_entry =
+        ico::IconDirEntry::encode_as_png_with_options(&image, &fast).unwrap();
+    let mut best = ico::PngEncodingOptions::new();
+    best.set_compression(ico::PngCompression::Best);
+    best.set_filter(ico::PngFilter::Smallest);
+    let best_entry =
+        ico::IconDirEntry::encode_as_png_with_options(&image, &best).unwrap();
+    assert!(best_entry.data().len() <= fast_entry.data().len());
+    assert_eq!(fast_entry.decode().unwrap().rgba_data(), rgba.as_slice());
+    assert_eq!(best_entry.decode().unwrap().rgba_data(), rgba.as_slice());
+
+    let mut png_data = Vec::<u8>::new();
+    image.write_png_with_options(&mut png_data, &best).unwrap();
+    assert_eq!(png_data.as_slice(), best_entry.data());
+}
+
+#[test]
+fn encode_with_smallest_format_policy() {
+    // A large image with a single color compresses far better as a PNG than
+    // as a BMP.
+    let image = ico::IconImage::from_rgba_data(
+        128,
+        128,
+        [0x40, 0x80, 0xc0, 0xff].repeat(128 * 128),
+    );
+    let mut options = ico::EncodingOptions::new();
+    options.set_format_policy(ico::FormatPolicy::Smallest { min_png_size: 0 });
+    let entry =
+        ico::IconDirEntry::encode_with_options(&image, &options).unwrap();
+    assert!(entry.is_png());
+    let bmp_entry = ico::IconDirEntry::encode_as_bmp(&image).unwrap();
+    assert!(entry.data().len() < bmp_entry.data().len());
+
+    // With a minimum PNG size above the image size, BMP must be used.
+    options
+        .set_format_policy(ico::FormatPolicy::Smallest { min_png_size: 256 });
+    let entry =
+        ico::IconDirEntry::encode_with_options(&image, &options).unwrap();
+    assert!(!entry.is_png());
+    assert_eq!(entry.decode().unwrap().rgba_data(), image.rgba_data());
+
+    // If the BMP options rule out the image, PNG is used where allowed.
+    let mut bmp_options = ico::BmpEncodingOptions::new();
+    bmp_options.set_depth(Some(ico::BmpDepth::Eight));
+    options.set_bmp_options(bmp_options);
+    let image = ico::IconImage::from_rgba_data(
+        16,
+        16,
+        [0x40, 0x80, 0xc0, 0x80].repeat(16 * 16),
+    );
+    assert!(ico::IconDirEntry::encode_with_options(&image, &options).is_err());
+    options.set_format_policy(ico::FormatPolicy::Smallest { min_png_size: 0 });
+    let entry =
+        ico::IconDirEntry::encode_with_options(&image, &options).unwrap();
+    assert!(entry.is_png());
+    assert_eq!(entry.decode().unwrap().rgba_data(), image.rgba_data());
+
+    options.set_format_policy(ico::FormatPolicy::AlwaysPng);
+    let image = ico::IconImage::from_rgba_data(2, 2, vec![0xff; 16]);
+    let entry =
+        ico::IconDirEntry::encode_with_options(&image, &options).unwrap();
+    assert!(entry.is_png());
+    options.set_format_policy(ico::FormatPolicy::AlwaysBmp);
+    let entry =
+        ico::IconDirEntry::encode_with_options(&image, &options).unwrap();
+    assert!(!entry.is_png());
+}
+
+#[test]
+fn encode_and_validate_compatibility_presets() {
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(32 * 32) {
+        let value = (index % 256) as u8;
+        rgba.extend_from_slice(&[value, 0xff - value, value / 2, 0xff]);
+    }
+    let small = ico::IconImage::from_rgba_data(32, 32, rgba);
+    let large = ico::IconImage::from_rgba_data(256, 256, vec![0x80; 262144]);
+
+    let preset = ico::CompatibilityPreset::WindowsXp;
+    let mut icon_dir = ico::IconDir::new(ico::ResourceType::Icon);
+    for image in [&small, &large] {
+        for entry in preset.encode(image).unwrap() {
+            icon_dir.add_entry(entry);
+        }
+    }
+    assert_eq!(icon_dir.entries().len(), 6);
+    assert!(icon_dir.entries().iter().all(|entry| !entry.is_png()));
+    let depths: Vec<u16> = icon_dir
+        .entries()
+        .iter()
+        .map(|entry| entry.bits_per_pixel())
+        .collect();
+    assert_eq!(depths, vec![32, 8, 4, 32, 8, 4]);
+    assert_eq!(preset.validate(&icon_dir), vec![]);
+
+    // The same file violates the Vista preset, since the 256x256 entries
+    // aren't PNGs.
+    let violations =
+        ico::CompatibilityPreset::WindowsVista.validate(&icon_dir);
+    assert_eq!(violations.len(), 3);
+    assert_eq!(
+        violations[0],
+        ico::CompatibilityViolation::PngRequired {
+            index: 3,
+            width: 256,
+            height: 256,
+        }
+    );
+
+    let preset = ico::CompatibilityPreset::WindowsVista;
+    let mut icon_dir = ico::IconDir::new(ico::ResourceType::Icon);
+    for image in [&small, &large] {
+        for entry in preset.encode(image).unwrap() {
+            icon_dir.add_entry(entry);
+        }
+    }
+    assert_eq!(icon_dir.entries().len(), 2);
+    assert!(!icon_dir.entries()[0].is_png());
+    assert!(icon_dir.entries()[1].is_png());
+    assert_eq!(preset.validate(&icon_dir), vec![]);
+    assert_eq!(
+        ico::CompatibilityPreset::WindowsXp.validate(&icon_dir),
+        vec![
+            ico::CompatibilityViolation::PngNotAllowed {
+                index: 1,
+                width: 256,
+                height: 256,
+            },
+            ico::CompatibilityViolation::MissingDepth {
+                width: 32,
+                height: 32,
+                depth: ico::BmpDepth::Eight,
+            },
+            ico::CompatibilityViolation::MissingDepth {
+                width: 32,
+                height: 32,
+                depth: ico::BmpDepth::Four,
+            },
+            ico::CompatibilityViolation::MissingDepth {
+                width: 256,
+                height: 256,
+                depth: ico::BmpDepth::Eight,
+            },
+            ico::CompatibilityViolation::MissingDepth {
+                width: 256,
+                height: 256,
+                depth: ico::BmpDepth::Four,
+            },
+        ]
+    );
+    assert_eq!(ico::CompatibilityPreset::Modern.validate(&icon_dir), vec![]);
+    assert_eq!(
+        ico::CompatibilityPreset::Modern
+            .validate(&ico::IconDir::new(ico::ResourceType::Icon)),
+        vec![ico::CompatibilityViolation::NoEntries]
+    );
+}
+
+#[test]
+fn quantize_to_indexed_image() {
+    // A smooth gradient with far more than 16 colors, plus a transparent
+    // corner.
+    let mut rgba = Vec::<u8>::new();
+    for y in 0..32 {
+        for x in 0..32 {
+            let alpha = if x + y < 4 { 0 } else { 0xff };
+            rgba.extend_from_slice(&[8 * x, 8 * y, 0x80, alpha]);
+        }
+    }
+    let image = ico::IconImage::from_rgba_data(32, 32, rgba);
+    let indexed = image.quantize(16, ico::Quantizer::MedianCut);
+    assert_eq!(indexed.palette().len(), 16);
+    assert_eq!(indexed.indices().len(), 32 * 32);
+    assert!(indexed.transparency()[0]);
+    assert!(!indexed.transparency()[32 * 32 - 1]);
+    let entry = ico::IconDirEntry::encode_indexed_as_bmp(&indexed).unwrap();
+    assert_eq!(entry.bits_per_pixel(), 4);
+    assert_eq!(
+        entry.decode().unwrap().rgba_data(),
+        indexed.to_rgba_image().rgba_data()
+    );
+}
+
+#[test]
+fn encode_indexed_bmp_keeps_palette_and_indices() {
+    // The VGA palette isn't in sorted order, and most of its colors go
+    // unused here, so this only round-trips if the encoder uses the indexed
+    // image's own palette and indices as-is.
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..16 {
+        let (red, green, blue) = ico::WINDOWS_VGA_PALETTE[(index * 5) % 16];
+        rgba.extend_from_slice(&[red, green, blue, 0xff]);
+    }
+    let image = ico::IconImage::from_rgba_data(8, 2, rgba);
+    let indexed = image.quantize(16, ico::Quantizer::WindowsVga);
+    assert_eq!(indexed.palette(), &ico::WINDOWS_VGA_PALETTE);
+    let entry = ico::IconDirEntry::encode_indexed_as_bmp(&indexed).unwrap();
+    assert_eq!(entry.bits_per_pixel(), 4);
+    let mut expected = Vec::<u8>::new();
+    for &(red, green, blue) in indexed.palette() {
+        expected.extend_from_slice(&[blue, green, red, 0]);
+    }
+    for row in indexed.indices().chunks(8).rev() {
+        for pair in row.chunks(2) {
+            expected.push((pair[0] << 4) | pair[1]);
+        }
+    }
+    assert_eq!(&entry.data()[40..(40 + expected.len())], &expected[..]);
+    assert_eq!(entry.decode().unwrap().rgba_data(), image.rgba_data());
+}
+
+#[test]
+fn encode_bmp_with_vga_palette() {
+    let mut rgba = Vec::<u8>::new();
+    for index in 0..(16 * 16) {
+        rgba.extend_from_slice(&[index as u8, 0x20, 0xe0, 0xff]);
+    }
+    let image = ico::IconImage::from_rgba_data(16, 16, rgba);
+    let mut options = ico::BmpEncodingOptions::new();
+    options.set_depth(Some(ico::BmpDepth::Four));
+    options.set_allow_quantization(true);
+    options.set_quantizer(ico::Quantizer::WindowsVga);
+    let entry =
+        ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+            .unwrap();
+    let decoded = entry.decode().unwrap();
+    for pixel in decoded.rgba_data().chunks(4) {
+        let color = (pixel[0], pixel[1], pixel[2]);
+        assert!(ico::WINDOWS_VGA_PALETTE.contains(&color));
+    }
+    // Pixels with little red should become blue, and those with lots of red
+    // should become fuchsia.
+    assert_eq!(&decoded.rgba_data()[..4], &[0x00, 0x00, 0xff, 0xff]);
+    assert_eq!(&decoded.rgba_data()[1020..], &[0xff, 0x00, 0xff, 0xff]);
+}
+
+#[test]
+fn encode_bmp_with_dithering() {
+    // A horizontal gray gradient reduced to black and white.
+    let mut rgba = Vec::<u8>::new();
+    for _ in 0..16 {
+        for x in 0..16 {
+            let value = (17 * x) as u8;
+            rgba.extend_from_slice(&[value, value, value, 0xff]);
+        }
+    }
+    let image = ico::IconImage::from_rgba_data(16, 16, rgba);
+    let count_white = |dithering: ico::Dithering| {
+        let mut options = ico::BmpEncodingOptions::new();
+        options.set_depth(Some(ico::BmpDepth::One));
+        options.set_allow_quantization(true);
+        options.set_quantizer(ico::Quantizer::WindowsVga);
+        options.set_dithering(dithering);
+        let entry =
+            ico::IconDirEntry::encode_as_bmp_with_options(&image, &options)
+                .unwrap();
+        let decoded = entry.decode().unwrap();
+        let column = |x: usize| {
+            (0..16)
+                .filter(|&y| decoded.rgba_data()[4 * (16 * y + x)] == 0xff)
+                .count()
+        };
+        (column(2), column(8), column(13))
+    };
+    // Without dithering, each column is entirely black or entirely white.
+    assert_eq!(count_white(ico::Dithering::None), (0, 16, 16));
+    // With dithering, the middle columns become a mix.
+    let (dark, middle, light) = count_white(ico::Dithering::FloydSteinberg);
+    assert!(dark < middle && middle < light);
+    assert!(middle > 4 && middle < 12);
+    let (dark, middle, light) = count_white(ico::Dithering::Bayer);
+    assert!(dark < middle && middle < light);
+    assert!(middle > 4 && middle < 12);
+}
+
+#[test]
+fn build_icon_dir_from_one_image() {
+    let mut rgba = Vec::<u8>::new();
+    for y in 0..300i32 {
+        for x in 0..300 {
+            // An opaque circle on a transparent background.
+            let (dx, dy) = (x - 150, y - 150);
+            let alpha = if dx * dx + dy * dy < 100 * 100 { 0xff } else { 0 };
+            rgba.extend_from_slice(&[0x20, 0x80, 0xe0, alpha]);
+        }
+    }
+    let image = ico::IconImage::from_rgba_data(300, 300, rgba);
+    let icon_dir = ico::IconDir::from_image(&image).unwrap();
+    assert_eq!(icon_dir.resource_type(), ico::ResourceType::Icon);
+    let sizes: Vec<u32> =
+        icon_dir.entries().iter().map(|entry| entry.width()).collect();
+    assert_eq!(sizes, ico::IconDir::DEFAULT_SIZES.to_vec());
+    for entry in icon_dir.entries() {
+        assert_eq!(entry.width(), entry.height());
+        let decoded = entry.decode().unwrap();
+        let size = decoded.width() as usize;
+        // The corners are transparent and the center is opaque.
+        assert_eq!(decoded.rgba_data()[3], 0);
+        let center = 4 * (size * (size / 2) + size / 2);
+        assert_eq!(
+            &decoded.rgba_data()[center..(center + 4)],
+            &[0x20, 0x80, 0xe0, 0xff]
+        );
+    }
+
+    // A non-square cursor image gets scaled to fit, with its hotspot moved
+    // accordingly.
+    let mut image =
+        ico::IconImage::from_rgba_data(64, 32, vec![0xff; 4 * 64 * 32]);
+    image.set_cursor_hotspot(Some((32, 16)));
+    let options = ico::EncodingOptions::new();
+    let icon_dir =
+        ico::IconDir::from_image_with_sizes(&image, &[16], &options).unwrap();
+    assert_eq!(icon_dir.resource_type(), ico::ResourceType::Cursor);
+    let entry = &icon_dir.entries()[0];
+    assert_eq!(entry.cursor_hotspot(), Some((8, 8)));
+    let decoded = entry.decode().unwrap();
+    assert_eq!(decoded.rgba_data()[3], 0);
+    assert_eq!(decoded.rgba_data()[4 * 16 * 8 + 3], 0xff);
+
+    assert!(
+        ico::IconDir::from_image_with_sizes(&image, &[300], &options).is_err()
+    );
+}
+
 //===========================================================================//